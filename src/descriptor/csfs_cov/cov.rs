@@ -57,6 +57,7 @@ use crate::miniscript::limits::{
     MAX_OPS_PER_SCRIPT, MAX_SCRIPT_SIZE, MAX_STANDARD_P2WSH_SCRIPT_SIZE,
 };
 use crate::miniscript::{decode, types};
+use crate::policy::{semantic, Liftable};
 use crate::util::varint_len;
 use crate::{
     Error, ExtTranslator, Extension, ForEachKey, Miniscript, MiniscriptKey, Satisfier,
@@ -456,6 +457,18 @@ where
     }
 }
 
+impl<Pk: MiniscriptKey, Ext: Extension> Liftable<Pk> for LegacyCSFSCov<Pk, Ext> {
+    fn lift(&self) -> Result<semantic::Policy<Pk>, Error> {
+        // The covenant code requires a signature by `pk` on top of the
+        // satisfaction of the underlying miniscript
+        let ret = semantic::Policy::Threshold(
+            2,
+            vec![semantic::Policy::Key(self.pk.clone()), self.ms.lift()?],
+        );
+        Ok(ret.normalized())
+    }
+}
+
 impl<Pk: MiniscriptKey, Ext: Extension> ForEachKey<Pk> for LegacyCSFSCov<Pk, Ext> {
     fn for_each_key<'a, F: FnMut(&'a Pk) -> bool>(&'a self, mut pred: F) -> bool
     where
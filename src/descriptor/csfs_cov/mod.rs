@@ -98,6 +98,24 @@ mod tests {
         string_rtt("elcovwsh(A,outputs_pref(01020304))");
     }
 
+    #[test]
+    fn lift_cov() {
+        use crate::policy::{Liftable, Semantic};
+
+        let desc =
+            Descriptor::<String>::from_str("elcovwsh(A,thresh(2,l:ver_eq(1),s:pk(C),s:pk(B)))")
+                .unwrap();
+        let policy = desc.lift().unwrap();
+        assert_eq!(
+            policy,
+            Semantic::from_str("and(pk(A),thresh(2,ext(ver_eq(1)),pk(C),pk(B)))")
+                .unwrap()
+                .normalized()
+        );
+        assert_eq!(policy.n_keys(), 3);
+        assert_eq!(policy.minimum_n_keys(), Some(2));
+    }
+
     fn script_rtt(desc_str: &str) {
         let desc = Descriptor::<bitcoin::PublicKey>::from_str(desc_str).unwrap();
         assert_eq!(desc.desc_type(), DescriptorType::Cov);
@@ -0,0 +1,269 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! # NUMS Internal Keys
+//!
+//! Provably unspendable Taproot internal keys, for descriptors which should
+//! only be spent through their script paths. As suggested by BIP 341, the
+//! internal key is `H + rG`, where `H` is a point whose discrete logarithm is
+//! unknown and `r` is a scalar revealed to auditors. Anybody knowing `r` can
+//! check that nobody can spend through the key path, while the key looks like
+//! any other key to everybody else.
+//!
+
+use bitcoin::bip32;
+use bitcoin::hashes::{sha256t_hash_newtype, Hash};
+use elements::secp256k1_zkp::{
+    PublicKey, Scalar, Secp256k1, SecretKey, Verification, XOnlyPublicKey,
+};
+
+use crate::descriptor::{Descriptor, DescriptorPublicKey, DescriptorXKey, SinglePubKey, Wildcard};
+use crate::{Extension, ForEachKey, MiniscriptKey};
+
+/// The SHA-256 initial midstate value for the [`NumsHash`].
+const MIDSTATE_NUMS_HASH: [u8; 32] = [
+    0xa6, 0x8d, 0xd1, 0x38, 0x75, 0x61, 0x1e, 0x4c, 0x6e, 0x34, 0x9b, 0x61, 0x40, 0xad, 0x38, 0x2d,
+    0x45, 0x1d, 0x95, 0x2f, 0x5c, 0xfc, 0xcf, 0xb0, 0xe9, 0x71, 0xbb, 0xb3, 0x16, 0x75, 0x96, 0xe7,
+];
+
+sha256t_hash_newtype!(
+    NumsHash,
+    NumsTag,
+    MIDSTATE_NUMS_HASH,
+    64,
+    doc = "BIP-340 Tagged hash deriving NUMS internal keys",
+    forward
+);
+
+/// The x coordinate of the BIP 341 NUMS point `H`, the SHA256 of the
+/// uncompressed encoding of the secp256k1 generator
+pub const NUMS_H_X: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
+/// The BIP 341 NUMS point `H`, with an even y coordinate
+pub fn nums_point() -> PublicKey {
+    let mut bytes = [0x02; 33];
+    bytes[1..].copy_from_slice(&NUMS_H_X);
+    PublicKey::from_slice(&bytes).expect("H is a valid point")
+}
+
+/// A provably unspendable internal key `H + rG`, along with its `r`
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct NumsKey {
+    r: SecretKey,
+    key: PublicKey,
+}
+
+impl NumsKey {
+    /// Computes the NUMS key `H + rG`. The key is only private if `r` is
+    /// chosen uniformly at random, or derived as in [`NumsKey::from_keys`].
+    pub fn new<C: Verification>(secp: &Secp256k1<C>, r: SecretKey) -> NumsKey {
+        let key = nums_point()
+            .add_exp_tweak(secp, &Scalar::from(r))
+            .expect("r is not the discrete logarithm of -H");
+        NumsKey { r, key }
+    }
+
+    /// Derives the NUMS key of a descriptor or policy from its keys.
+    ///
+    /// `r` is the tagged hash, with the tag `NUMS-Internal-Key/1.0`, of the
+    /// sorted and deduplicated string representations of the keys, separated
+    /// by commas. The key is private against parties who do not know all the
+    /// keys, and the same keys always give the same NUMS key.
+    pub fn from_keys<C, Pk, T>(secp: &Secp256k1<C>, keys: &T) -> NumsKey
+    where
+        C: Verification,
+        Pk: MiniscriptKey,
+        T: ForEachKey<Pk>,
+    {
+        let mut strings = vec![];
+        keys.for_each_key(|pk| {
+            strings.push(pk.to_string());
+            true
+        });
+        strings.sort();
+        strings.dedup();
+        let hash = NumsHash::hash(strings.join(",").as_bytes());
+        let r = SecretKey::from_slice(&hash.to_byte_array())
+            .expect("hash is a valid secret key with overwhelming probability");
+        NumsKey::new(secp, r)
+    }
+
+    /// The `r` of the NUMS key, to be revealed to auditors
+    pub fn r(&self) -> SecretKey {
+        self.r
+    }
+
+    /// The NUMS key
+    pub fn public_key(&self) -> bitcoin::PublicKey {
+        bitcoin::PublicKey::new(self.key)
+    }
+
+    /// The NUMS key as an x-only key
+    pub fn x_only_public_key(&self) -> XOnlyPublicKey {
+        self.key.x_only_public_key().0
+    }
+
+    /// The NUMS key as a master extended public key, whose chain code is the
+    /// tagged hash of the key.
+    ///
+    /// Keys derived from it are unspendable too, as they only differ from the
+    /// NUMS key by known tweaks, so that unspendable internal keys can be
+    /// derived alongside the keys of wildcard descriptors.
+    pub fn to_xpub(&self, network: bitcoin::Network) -> bip32::ExtendedPubKey {
+        let chain_code = NumsHash::hash(&self.key.serialize()).to_byte_array();
+        bip32::ExtendedPubKey {
+            network,
+            depth: 0,
+            parent_fingerprint: Default::default(),
+            child_number: bip32::ChildNumber::Normal { index: 0 },
+            public_key: self.key,
+            chain_code: bip32::ChainCode::from(&chain_code[..]),
+        }
+    }
+
+    /// The NUMS key as a [`DescriptorPublicKey`] deriving keys from
+    /// [`NumsKey::to_xpub`] when `wildcard` is not [`Wildcard::None`]
+    pub fn to_descriptor_public_key(
+        &self,
+        network: bitcoin::Network,
+        wildcard: Wildcard,
+    ) -> DescriptorPublicKey {
+        DescriptorPublicKey::XPub(DescriptorXKey {
+            origin: None,
+            xkey: self.to_xpub(network),
+            derivation_path: bip32::DerivationPath::master(),
+            wildcard,
+        })
+    }
+
+    /// Checks that `key` is the NUMS key `H + rG`
+    pub fn verify<C: Verification>(
+        secp: &Secp256k1<C>,
+        key: &XOnlyPublicKey,
+        r: SecretKey,
+    ) -> bool {
+        NumsKey::new(secp, r).x_only_public_key() == *key
+    }
+
+    /// Checks that a descriptor key is the NUMS key `H + rG`, or is an
+    /// extended key deriving keys from it
+    pub fn verify_descriptor_key<C: Verification>(
+        secp: &Secp256k1<C>,
+        key: &DescriptorPublicKey,
+        r: SecretKey,
+    ) -> bool {
+        let key = match *key {
+            DescriptorPublicKey::Single(ref single) => match single.key {
+                SinglePubKey::FullKey(pk) => pk.inner.x_only_public_key().0,
+                SinglePubKey::XOnly(x_only) => x_only,
+            },
+            DescriptorPublicKey::XPub(ref xpub) => xpub.xkey.public_key.x_only_public_key().0,
+            DescriptorPublicKey::MultiXPub(ref xpub) => xpub.xkey.public_key.x_only_public_key().0,
+        };
+        NumsKey::verify(secp, &key, r)
+    }
+
+    /// Checks that the internal key of a Taproot descriptor is a NUMS key,
+    /// see [`NumsKey::verify_descriptor_key`]. Returns `false` for other
+    /// descriptors.
+    pub fn verify_descriptor<C: Verification, T: Extension>(
+        secp: &Secp256k1<C>,
+        desc: &Descriptor<DescriptorPublicKey, T>,
+        r: SecretKey,
+    ) -> bool {
+        match *desc {
+            Descriptor::Tr(ref tr) => NumsKey::verify_descriptor_key(secp, tr.internal_key(), r),
+            Descriptor::TrExt(ref tr) => NumsKey::verify_descriptor_key(secp, tr.internal_key(), r),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::hashes::sha256t::Tag;
+    use bitcoin::hashes::{sha256, HashEngine};
+    use elements::secp256k1_zkp::constants::{GENERATOR_X, GENERATOR_Y};
+
+    use super::*;
+    use crate::policy::Concrete;
+
+    #[test]
+    fn tagged_hash() {
+        // Check that cached midstate is computed correctly
+        let mut engine = sha256::Hash::engine();
+        let tag_hash = sha256::Hash::hash(b"NUMS-Internal-Key/1.0");
+        engine.input(&tag_hash[..]);
+        engine.input(&tag_hash[..]);
+        assert_eq!(MIDSTATE_NUMS_HASH, engine.midstate().to_byte_array());
+
+        assert_eq!(
+            NumsHash::from_engine(NumsTag::engine()).to_string(),
+            "c6a470640a93af05a970b7444a97d6fceb6b13fb944303fd8f7d990a33f4cc90",
+        );
+    }
+
+    #[test]
+    fn nums_point() {
+        // H is the hash of the uncompressed generator
+        let mut engine = sha256::Hash::engine();
+        engine.input(&[0x04]);
+        engine.input(&GENERATOR_X);
+        engine.input(&GENERATOR_Y);
+        assert_eq!(sha256::Hash::from_engine(engine).to_byte_array(), NUMS_H_X);
+        assert_eq!(super::nums_point().serialize()[1..], NUMS_H_X);
+    }
+
+    #[test]
+    fn nums_key() {
+        let secp = Secp256k1::verification_only();
+
+        let mut one = [0; 32];
+        one[31] = 1;
+        let nums = NumsKey::new(&secp, SecretKey::from_slice(&one).unwrap());
+        assert_eq!(
+            nums.public_key().to_string(),
+            "03337b7285fc31a330c3e05d10c1cbbc009bf37c9c5dcf192adfd221bc8450d79a"
+        );
+
+        let policy = Concrete::<String>::from_str("or(pk(B),and(pk(A),pk(B)))").unwrap();
+        let nums = NumsKey::from_keys(&secp, &policy);
+        assert_eq!(
+            nums.public_key().to_string(),
+            "03babfbe1bb73e4fdf6ab1862a4c98889a52e8ee5b94c25ecaf7bd29fbad8e5fe9"
+        );
+        assert!(NumsKey::verify(&secp, &nums.x_only_public_key(), nums.r()));
+        let other = NumsKey::new(&secp, SecretKey::from_slice(&one).unwrap());
+        assert!(!NumsKey::verify(
+            &secp,
+            &nums.x_only_public_key(),
+            other.r()
+        ));
+    }
+
+    #[test]
+    fn nums_descriptor() {
+        let secp = Secp256k1::verification_only();
+        let nums = NumsKey::from_keys(&secp, &Concrete::<String>::from_str("pk(A)").unwrap());
+
+        let key = nums.to_descriptor_public_key(bitcoin::Network::Testnet, Wildcard::Unhardened);
+        let desc = Descriptor::<DescriptorPublicKey>::from_str(&format!(
+            "eltr({},pk(03f28773c2d975288bc7d1d205c3748651b075fbc6610e58cddeeddf8f19405aa8))",
+            key
+        ))
+        .unwrap();
+        assert!(NumsKey::verify_descriptor(&secp, &desc, nums.r()));
+        let other = NumsKey::from_keys(&secp, &Concrete::<String>::from_str("pk(B)").unwrap());
+        assert!(!NumsKey::verify_descriptor(&secp, &desc, other.r()));
+
+        // The xpub and the single key forms carry the same NUMS key
+        let xpub = nums.to_xpub(bitcoin::Network::Testnet);
+        assert_eq!(xpub.public_key, nums.public_key().inner);
+        let single = DescriptorPublicKey::from_str(&nums.public_key().to_string()).unwrap();
+        assert!(NumsKey::verify_descriptor_key(&secp, &single, nums.r()));
+    }
+}
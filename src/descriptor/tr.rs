@@ -4,6 +4,7 @@ use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::{fmt, hash};
 
+use elements::hashes::sha256;
 use elements::taproot::{
     LeafVersion, TaprootBuilder, TaprootSpendInfo, TAPROOT_CONTROL_BASE_SIZE,
     TAPROOT_CONTROL_MAX_NODE_COUNT, TAPROOT_CONTROL_NODE_SIZE,
@@ -17,7 +18,7 @@ use crate::expression::{self, FromTree};
 use crate::extensions::ParseableExt;
 use crate::miniscript::Miniscript;
 use crate::policy::semantic::Policy;
-use crate::policy::Liftable;
+use crate::policy::{LiftError, Liftable};
 use crate::util::{varint_len, witness_size};
 use crate::{
     errstr, Error, Extension, ForEachKey, MiniscriptKey, NoExt, Satisfier, Tap, ToPublicKey,
@@ -25,8 +26,8 @@ use crate::{
 };
 
 /// A Taproot Tree representation.
-// Hidden leaves are not yet supported in descriptor spec. Conceptually, it should
-// be simple to integrate those here, but it is best to wait on core for the exact syntax.
+// Hidden nodes are not part of the descriptor spec yet, they are written as
+// `hidden(<hex node hash>)` until core settles on the exact syntax.
 #[derive(Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub enum TapTree<Pk: MiniscriptKey, Ext: Extension = NoExt> {
     /// A taproot tree structure
@@ -38,6 +39,13 @@ pub enum TapTree<Pk: MiniscriptKey, Ext: Extension = NoExt> {
     Leaf(Arc<Miniscript<Pk, Tap, Ext>>),
     /// A taproot leaf denoting a spending condition in terms of Simplicity
     SimplicityLeaf(Arc<simplicity::Policy<Pk>>),
+    /// A subtree known only by its node hash, as committed in the merkle
+    /// branch of a control block
+    ///
+    /// A hidden node cannot be spent from and contributes no scripts to the
+    /// tree, but it is needed to recompute the merkle root of a partially
+    /// known tree.
+    Hidden(sha256::Hash),
 }
 
 /// A taproot descriptor
@@ -119,7 +127,18 @@ impl<Pk: MiniscriptKey, Ext: Extension> TapTree<Pk, Ext> {
             TapTree::Tree(ref left_tree, ref right_tree) => {
                 1 + max(left_tree.taptree_height(), right_tree.taptree_height())
             }
-            TapTree::Leaf(..) | TapTree::SimplicityLeaf(..) => 0,
+            TapTree::Leaf(..) | TapTree::SimplicityLeaf(..) | TapTree::Hidden(..) => 0,
+        }
+    }
+
+    /// Whether the tree contains [`TapTree::Hidden`] nodes
+    pub fn has_hidden_nodes(&self) -> bool {
+        match *self {
+            TapTree::Tree(ref left, ref right) => {
+                left.has_hidden_nodes() || right.has_hidden_nodes()
+            }
+            TapTree::Leaf(..) | TapTree::SimplicityLeaf(..) => false,
+            TapTree::Hidden(..) => true,
         }
     }
 
@@ -161,7 +180,8 @@ impl<Pk: MiniscriptKey, Ext: Extension> TapTree<Pk, Ext> {
                 Arc::new(r.translate_helper(t)?),
             ),
             TapTree::Leaf(ms) => TapTree::Leaf(Arc::new(ms.translate_pk(t)?)),
-            TapTree::SimplicityLeaf(sim) => TapTree::SimplicityLeaf(Arc::new(sim.translate(&mut SimTranslator(t))?))
+            TapTree::SimplicityLeaf(sim) => TapTree::SimplicityLeaf(Arc::new(sim.translate(&mut SimTranslator(t))?)),
+            TapTree::Hidden(hash) => TapTree::Hidden(*hash),
         };
         Ok(frag)
     }
@@ -180,6 +200,7 @@ impl<Pk: MiniscriptKey, Ext: Extension> TapTree<Pk, Ext> {
             ),
             TapTree::Leaf(ms) => TapTree::Leaf(Arc::new(ms.translate_ext(t)?)),
             TapTree::SimplicityLeaf(sim) => TapTree::SimplicityLeaf(Arc::clone(sim)),
+            TapTree::Hidden(hash) => TapTree::Hidden(*hash),
         };
         Ok(frag)
     }
@@ -191,6 +212,7 @@ impl<Pk: MiniscriptKey, Ext: Extension> fmt::Display for TapTree<Pk, Ext> {
             TapTree::Tree(ref left, ref right) => write!(f, "{{{},{}}}", *left, *right),
             TapTree::Leaf(ref script) => write!(f, "{}", *script),
             TapTree::SimplicityLeaf(ref policy) => write!(f, "sim{{{}}}", policy),
+            TapTree::Hidden(ref hash) => write!(f, "hidden({})", hash),
         }
     }
 }
@@ -201,6 +223,7 @@ impl<Pk: MiniscriptKey, Ext: Extension> fmt::Debug for TapTree<Pk, Ext> {
             TapTree::Tree(ref left, ref right) => write!(f, "{{{:?},{:?}}}", *left, *right),
             TapTree::Leaf(ref script) => write!(f, "{:?}", *script),
             TapTree::SimplicityLeaf(ref policy) => write!(f, "{:?}", policy),
+            TapTree::Hidden(ref hash) => write!(f, "hidden({})", hash),
         }
     }
 }
@@ -231,6 +254,12 @@ impl<Pk: MiniscriptKey, Ext: Extension> Tr<Pk, Ext> {
         &self.tree
     }
 
+    /// Whether the taproot tree of the descriptor is only partially known,
+    /// i.e. contains [`TapTree::Hidden`] nodes
+    pub fn has_hidden_nodes(&self) -> bool {
+        self.tree.as_ref().map_or(false, TapTree::has_hidden_nodes)
+    }
+
     /// Iterate over all scripts in merkle tree. If there is no script path, the iterator
     /// yields [`None`]
     pub fn iter_scripts(&self) -> TapTreeIter<'_, Pk, Ext> {
@@ -266,10 +295,27 @@ impl<Pk: MiniscriptKey, Ext: Extension> Tr<Pk, Ext> {
             TaprootSpendInfo::new_key_spend(&secp, self.internal_key.to_x_only_pubkey(), None)
         } else {
             let mut builder = TaprootBuilder::new();
-            for (depth, script) in self.iter_scripts() {
-                builder = builder
-                    .add_leaf_with_ver(depth, script.encode(), script.version())
-                    .expect("Computing spend data on a valid Tree should always succeed");
+            // Walk the tree in the same DFS order as `iter_scripts`, also
+            // visiting hidden nodes which the iterator skips
+            let mut stack = vec![(0, self.tree.as_ref().expect("checked above"))];
+            while let Some((depth, node)) = stack.pop() {
+                let res = match *node {
+                    TapTree::Tree(ref l, ref r) => {
+                        stack.push((depth + 1, r));
+                        stack.push((depth + 1, l));
+                        continue;
+                    }
+                    TapTree::Leaf(ref ms) => {
+                        let script = TapLeafScript::Miniscript(ms);
+                        builder.add_leaf_with_ver(depth, script.encode(), script.version())
+                    }
+                    TapTree::SimplicityLeaf(ref sim) => {
+                        let script = TapLeafScript::Simplicity(sim);
+                        builder.add_leaf_with_ver(depth, script.encode(), script.version())
+                    }
+                    TapTree::Hidden(hash) => builder.add_hidden(depth, hash),
+                };
+                builder = res.expect("Computing spend data on a valid Tree should always succeed");
             }
             // Assert builder cannot error here because we have a well formed descriptor
             match builder.finalize(&secp, self.internal_key.to_x_only_pubkey()) {
@@ -318,27 +364,7 @@ impl<Pk: MiniscriptKey, Ext: Extension> Tr<Pk, Ext> {
         };
 
         tree.iter()
-            .filter_map(|(depth, script)| {
-                let script_size = script.script_size();
-                let max_sat_elems = script.max_satisfaction_witness_elements().ok()?;
-                let max_sat_size = script.max_satisfaction_size().ok()?;
-                let control_block_size = control_block_len(depth);
-
-                // stack varint difference (+1 for ctrl block, witness script already included)
-                let stack_varint_diff = varint_len(max_sat_elems + 1) - varint_len(0);
-
-                Some(
-                    stack_varint_diff +
-                    // size of elements to satisfy script
-                    max_sat_size +
-                    // second to last element: script
-                    varint_len(script_size) +
-                    script_size +
-                    // last element: control block
-                    varint_len(control_block_size) +
-                    control_block_size,
-                )
-            })
+            .filter_map(|(depth, script)| script.max_weight_to_satisfy(depth).ok())
             .max()
             .ok_or(Error::ImpossibleSatisfaction)
     }
@@ -497,6 +523,29 @@ impl<'a, Pk: MiniscriptKey, Ext: Extension> TapLeafScript<'a, Pk, Ext> {
         }
     }
 
+    /// Computes an upper bound on the weight of a witness spending the leaf
+    /// script at `depth` in the tap tree, including the witness script and
+    /// the control block.
+    pub fn max_weight_to_satisfy(&self, depth: usize) -> Result<usize, Error> {
+        let script_size = self.script_size();
+        let max_sat_elems = self.max_satisfaction_witness_elements()?;
+        let max_sat_size = self.max_satisfaction_size()?;
+        let control_block_size = control_block_len(depth);
+
+        // stack varint difference (+1 for ctrl block, witness script already included)
+        let stack_varint_diff = varint_len(max_sat_elems + 1) - varint_len(0);
+
+        Ok(stack_varint_diff +
+            // size of elements to satisfy script
+            max_sat_size +
+            // second to last element: script
+            varint_len(script_size) +
+            script_size +
+            // last element: control block
+            varint_len(control_block_size) +
+            control_block_size)
+    }
+
     /// Return an iterator over the plain public keys (and not key hash values) of the leaf script.
     pub fn iter_pk(&self) -> Box<dyn Iterator<Item=Pk> + 'a> {
         match self {
@@ -580,6 +629,8 @@ where
                 TapTree::SimplicityLeaf(ref sim) => {
                     return Some((depth, TapLeafScript::Simplicity(sim)))
                 }
+                // Hidden nodes have no scripts to yield
+                TapTree::Hidden(..) => {}
             }
         }
         None
@@ -597,6 +648,10 @@ impl_block_str!(
                 let policy = crate::simplicity::PolicyWrapper::<Pk>::from_str(args[0].name)?;
                 Ok(TapTree::SimplicityLeaf(Arc::new(policy.0)))
             }
+            expression::Tree { name, args } if *name == "hidden" && args.len() == 1 => {
+                let hash = expression::terminal(&args[0], sha256::Hash::from_str)?;
+                Ok(TapTree::Hidden(hash))
+            }
             expression::Tree { name, args } if !name.is_empty() && args.is_empty() => {
                 let script = Miniscript::<Pk, Tap, Ext>::from_str(name)?;
                 Ok(TapTree::Leaf(Arc::new(script)))
@@ -772,6 +827,9 @@ impl<Pk: MiniscriptKey, Ext: Extension> Liftable<Pk> for TapTree<Pk, Ext> {
                 }
                 TapTree::Leaf(ref leaf) => leaf.lift(),
                 TapTree::SimplicityLeaf(..) => panic!("FIXME: Cannot lift Simplicity policy to Miniscript semantic policy"),
+                // Nothing is known about the spending conditions of a hidden node,
+                // so any lifted policy would silently drop its spending paths
+                TapTree::Hidden(..) => Err(Error::LiftError(LiftError::HiddenTapNode)),
             }
         }
 
@@ -989,4 +1047,45 @@ mod tests {
             &[TapLeafScript::Miniscript(&ms), TapLeafScript::Simplicity(&sim)]
         );
     }
+
+    #[test]
+    fn tr_hidden_nodes() {
+        use elements::hashes::Hash;
+        use elements::taproot::TapLeafHash;
+
+        let internal = "03daed4f2be3a8bf278e70132fb0beb7522f570e144bf615c07e996d443dee8729";
+        let (a, b) = (
+            "028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa",
+            "02d7924d4f7d43ea965a465ae3095ff41131e5946f3c85f79e44adbcf8e27e080e",
+        );
+        let full = Tr::<bitcoin::PublicKey, NoExt>::from_str(&format!(
+            "eltr({},{{pk({}),pk({})}})",
+            internal, a, b
+        ))
+        .unwrap();
+        assert!(!full.has_hidden_nodes());
+
+        // Replace the leaf of `b` by its node hash, i.e. its leaf hash
+        let leaf_b =
+            Miniscript::<bitcoin::PublicKey, Tap>::from_str(&format!("pk({})", b)).unwrap();
+        let leaf_hash = TapLeafHash::from_script(&leaf_b.encode(), LeafVersion::default());
+        let hidden = sha256::Hash::from_slice(&leaf_hash[..]).unwrap();
+        let partial = Tr::<bitcoin::PublicKey, NoExt>::from_str(&format!(
+            "eltr({},{{pk({}),hidden({})}})",
+            internal, a, hidden
+        ))
+        .unwrap();
+        assert!(partial.has_hidden_nodes());
+        assert_eq!(partial.iter_scripts().count(), 1);
+        assert_eq!(partial, Tr::from_str(&partial.to_string()).unwrap());
+
+        // Both trees commit to the same merkle root
+        assert_eq!(partial.script_pubkey(), full.script_pubkey());
+        // The spending conditions of the hidden leaf are unknown
+        assert_eq!(
+            partial.lift().unwrap_err().to_string(),
+            Error::LiftError(LiftError::HiddenTapNode).to_string(),
+        );
+        assert!(full.lift().is_ok());
+    }
 }
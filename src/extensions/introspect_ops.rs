@@ -634,7 +634,7 @@ fn spk(pref: i8, prog: &[u8]) -> Option<elements::Script> {
 
 // Internal function to convert a script pubkey into (witness version, program)
 // This converts legacy programs to (-1, sha256::Hash(spk))
-fn spk_to_components(s: &elements::Script) -> (i8, Vec<u8>) {
+pub(crate) fn spk_to_components(s: &elements::Script) -> (i8, Vec<u8>) {
     if !s.is_witness_program() {
         (
             -1,
@@ -652,6 +652,32 @@ fn spk_to_components(s: &elements::Script) -> (i8, Vec<u8>) {
     }
 }
 
+// Internal function to convert an asset into the (commitment, prefix) pair
+// pushed by introspection opcodes. Null assets have no such representation.
+pub(crate) fn asset_to_components(a: &confidential::Asset) -> Option<(Vec<u8>, u8)> {
+    match a {
+        Asset::Null => None,
+        Asset::Explicit(a) => Some((a.into_inner().as_ref().to_vec(), 1)),
+        Asset::Confidential(c) => {
+            let ser = c.serialize();
+            Some((ser[1..].to_vec(), ser[0]))
+        }
+    }
+}
+
+// Internal function to convert a value into the (commitment, prefix) pair
+// pushed by introspection opcodes. Null values are pushed as explicit zeros.
+pub(crate) fn value_to_components(v: &confidential::Value) -> (Vec<u8>, u8) {
+    match v {
+        confidential::Value::Null => (0i64.to_le_bytes().to_vec(), 1),
+        confidential::Value::Explicit(a) => (a.to_le_bytes().to_vec(), 1),
+        confidential::Value::Confidential(c) => {
+            let ser = c.serialize();
+            (ser[1..].to_vec(), ser[0])
+        }
+    }
+}
+
 impl AssetExpr<CovExtArgs> {
     /// Push this script to builder
     /// Panics when trying to push a Null asset. This never occur in honest use-cases
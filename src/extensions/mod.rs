@@ -30,6 +30,7 @@ pub use arith::{Arith, EvalError, Expr, ExprInner};
 pub use csfs::{CheckSigFromStack, CsfsKey, CsfsMsg};
 pub use index_ops::IdxExpr;
 pub use introspect_ops::{AssetExpr, CovOps, Spk, SpkExpr, ValueExpr};
+pub(crate) use introspect_ops::{asset_to_components, spk_to_components, value_to_components};
 
 pub use self::outputs_pref::LegacyOutputsPref;
 pub use self::param::{ArgFromStr, CovExtArgs, ExtParam, NoExtParam};
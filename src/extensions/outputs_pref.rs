@@ -9,7 +9,6 @@ use elements::hashes::{sha256d, Hash};
 use elements::hex::{FromHex, ToHex};
 
 use super::{FromTokenIterError, ParseableExt, TxEnv};
-use crate::descriptor::CovError;
 use crate::miniscript::astelem::StackCtxOperations;
 use crate::miniscript::context::ScriptContextError;
 use crate::miniscript::lex::{Token as Tk, TokenIter};
@@ -47,7 +46,10 @@ impl fmt::Display for LegacyOutputsPref {
 
 impl<Pk: MiniscriptKey> Liftable<Pk> for LegacyOutputsPref {
     fn lift(&self) -> Result<policy::Semantic<Pk>, Error> {
-        Err(Error::CovError(CovError::CovenantLift))
+        Ok(policy::Semantic::Ext(
+            self.to_string(),
+            self.mall_prop().safe,
+        ))
     }
 }
 
@@ -7,7 +7,6 @@ use std::fmt;
 use elements::encode::serialize;
 
 use super::{FromTokenIterError, ParseableExt, TxEnv};
-use crate::descriptor::CovError;
 use crate::miniscript::astelem::StackCtxOperations;
 use crate::miniscript::lex::{Token as Tk, TokenIter};
 use crate::miniscript::satisfy::{Satisfaction, Witness};
@@ -35,7 +34,10 @@ impl fmt::Display for LegacyVerEq {
 
 impl<Pk: MiniscriptKey> Liftable<Pk> for LegacyVerEq {
     fn lift(&self) -> Result<policy::Semantic<Pk>, Error> {
-        Err(Error::CovError(CovError::CovenantLift))
+        Ok(policy::Semantic::Ext(
+            self.to_string(),
+            self.mall_prop().safe,
+        ))
     }
 }
 
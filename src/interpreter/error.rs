@@ -20,8 +20,6 @@ pub enum Error {
     AbsoluteLocktimeComparisonInvalid(u32, u32),
     /// Cannot Infer a taproot descriptor
     /// Key spends cannot infer the internal key of the descriptor
-    /// Inferring script spends is possible, but is hidden nodes are currently
-    /// not supported in descriptor spec
     CannotInferTrDescriptors,
     /// Error parsing taproot control block
     ControlBlockParse(taproot::TaprootError),
@@ -123,6 +121,29 @@ pub enum Error {
     },
     /// Errors related to extensions.
     ArithError(EvalError),
+    /// The number of spent utxos does not match the number of transaction inputs
+    SpentUtxosLenMismatch {
+        /// Number of transaction inputs
+        n_inputs: usize,
+        /// Number of spent utxos supplied
+        n_spent_utxos: usize,
+    },
+    /// The input is not a pegin
+    NotPegin,
+    /// The pegin witness is malformed
+    PeginWitnessMalformed(&'static str),
+    /// The mainchain transaction of the pegin witness could not be decoded
+    PeginTxDecode(bitcoin::consensus::encode::Error),
+    /// The mainchain transaction of the pegin witness does not contain the
+    /// pegged in outpoint
+    PeginOutpointMismatch,
+    /// The amount claimed by the pegin differs from the mainchain output value
+    PeginValueMismatch {
+        /// Amount in the pegin witness
+        witness_value: u64,
+        /// Value of the mainchain output
+        mainchain_value: u64,
+    },
 }
 
 impl fmt::Display for Error {
@@ -210,6 +231,28 @@ impl fmt::Display for Error {
                 pos, expected, actual
             ),
             Error::ArithError(ref e) => write!(f, "{}", e),
+            Error::SpentUtxosLenMismatch {
+                n_inputs,
+                n_spent_utxos,
+            } => write!(
+                f,
+                "Transaction has {} inputs but {} spent utxos were supplied",
+                n_inputs, n_spent_utxos
+            ),
+            Error::NotPegin => f.write_str("input is not a pegin"),
+            Error::PeginWitnessMalformed(s) => write!(f, "malformed pegin witness: {}", s),
+            Error::PeginTxDecode(ref e) => write!(f, "pegin mainchain transaction: {}", e),
+            Error::PeginOutpointMismatch => {
+                f.write_str("pegin mainchain transaction does not match the pegin outpoint")
+            }
+            Error::PeginValueMismatch {
+                witness_value,
+                mainchain_value,
+            } => write!(
+                f,
+                "pegin claims {} but the mainchain output has value {}",
+                witness_value, mainchain_value
+            ),
         }
     }
 }
@@ -260,6 +303,10 @@ impl error::Error for Error {
             IncorrectCovenantWitness => None,
             CovWitnessSizeErr { .. } => None,
             ArithError(..) => None,
+            SpentUtxosLenMismatch { .. } => None,
+            NotPegin | PeginWitnessMalformed(_) | PeginOutpointMismatch => None,
+            PeginTxDecode(e) => Some(e),
+            PeginValueMismatch { .. } => None,
         }
     }
 }
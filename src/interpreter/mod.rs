@@ -14,6 +14,7 @@ use std::str::FromStr;
 
 use bitcoin;
 use elements::hashes::{hash160, ripemd160, sha256, Hash, HashEngine};
+use elements::taproot::ControlBlock;
 use elements::{self, secp256k1_zkp, sighash, EcdsaSighashType, LockTime, Sequence, Sighash};
 
 use crate::extensions::{CovExtArgs, ParseableExt, TxEnv};
@@ -23,11 +24,19 @@ use crate::{hash256, util, Descriptor, ElementsSig, Miniscript, Terminal, ToPubl
 
 mod error;
 mod inner;
+mod pegin;
 mod stack;
+#[cfg(feature = "trace")]
+mod trace;
+mod vm;
 
 pub use self::error::Error;
 use self::error::PkEvalErrInner;
+pub use self::pegin::PeginInfo;
 pub use self::stack::{Element, Stack};
+#[cfg(feature = "trace")]
+pub use self::trace::{ExecutionTrace, TraceLine, TraceStep};
+pub use self::vm::{eval_raw_input, RawScriptError, RawScriptFailure};
 use crate::{elementssig_from_rawsig, CovenantExt, Extension, MiniscriptKey};
 
 /// An iterable Miniscript-structured representation of the spending of a coin
@@ -37,6 +46,11 @@ pub struct Interpreter<'txin, Ext: Extension> {
     /// For non-Taproot spends, the scriptCode; for Taproot script-spends, this
     /// is the leaf script; for key-spends it is `None`.
     script_code: Option<elements::Script>,
+    /// For Taproot script-spends, the (already verified) control block of the
+    /// spend; `None` otherwise.
+    tap_control_block: Option<ControlBlock>,
+    /// For pegin inputs, the data recovered from the pegin witness
+    pegin: Option<PeginInfo>,
     age: Sequence,
     lock_time: LockTime,
 }
@@ -153,6 +167,29 @@ impl<'txin> Interpreter<'txin, CovenantExt<CovExtArgs>> {
     ) -> Result<Self, Error> {
         Interpreter::from_txdata_ext(spk, script_sig, witness, age, lock_time)
     }
+
+    /// Constructs an interpreter for the claim of a pegin input
+    ///
+    /// The pegin witness of `txin` is parsed into a [`PeginInfo`], and the
+    /// claim is interpreted as a spend of an output with the claim script as
+    /// its scriptPubKey, i.e. against the Elements-side descriptor of the
+    /// `Pegin` or `LegacyPegin` descriptor. Signatures are checked against
+    /// [`PeginInfo::spent_utxo`] as the spent output.
+    pub fn from_pegin_txin(
+        txin: &'txin elements::TxIn,
+        lock_time: LockTime, // CLTV, absolute lock time.
+    ) -> Result<Self, Error> {
+        let pegin = PeginInfo::from_txin(txin)?;
+        let mut interpreter = Interpreter::from_txdata(
+            &pegin.claim_script,
+            &txin.script_sig,
+            &txin.witness.script_witness,
+            txin.sequence,
+            lock_time,
+        )?;
+        interpreter.pegin = Some(pegin);
+        Ok(interpreter)
+    }
 }
 
 impl<'txin, Ext> Interpreter<'txin, Ext>
@@ -173,23 +210,55 @@ where
         lock_time: LockTime, // CLTV, absolute lock time.
     ) -> Result<Self, Error> {
         let (inner, stack, script_code) = inner::from_txdata(spk, script_sig, witness)?;
+        // The control block is the last witness element of a script spend, and
+        // was verified against the output key while parsing `inner`.
+        let tap_control_block = match inner {
+            inner::Inner::Script(_, inner::ScriptType::Tr) => witness
+                .last()
+                .map(|ctrl_blk| ControlBlock::from_slice(ctrl_blk))
+                .transpose()
+                .map_err(Error::ControlBlockParse)?,
+            _ => None,
+        };
         Ok(Interpreter {
             inner,
             stack,
             script_code,
+            tap_control_block,
+            pegin: None,
             age,
             lock_time,
         })
     }
 
+    /// The data recovered from the pegin witness, if this interpreter was
+    /// constructed from a pegin input with [`Interpreter::from_pegin_txin`]
+    pub fn pegin(&self) -> Option<&PeginInfo> {
+        self.pegin.as_ref()
+    }
+
     /// Same as [`Interpreter::iter`], but allows for a custom verification function.
     /// See [Self::iter_assume_sigs] for a simpler API without information about Prevouts
     /// but skips the signature verification
+    ///
+    /// With the `trace` feature, the returned iterator records an
+    /// [`ExecutionTrace`] of the evaluation, available from `Iter::trace`.
     pub fn iter_custom<'iter>(
         &'iter self,
         verify_sig: Box<dyn FnMut(&KeySigPair) -> bool + 'iter>,
         txenv: Option<&'txin TxEnv<'txin, 'txin>>,
     ) -> Iter<'txin, 'iter, Ext> {
+        #[cfg(feature = "trace")]
+        let recorder = trace::Recorder::new(
+            match self.inner {
+                inner::Inner::Script(ref ms, _) | inner::Inner::CovScript(_, ref ms) => Some(ms),
+                inner::Inner::PublicKey(..) => None,
+            },
+            match self.inner {
+                inner::Inner::PublicKey(..) => 0,
+                _ => 1,
+            },
+        );
         Iter {
             verify_sig,
             public_key: if let inner::Inner::PublicKey(ref pk, _) = self.inner {
@@ -223,6 +292,8 @@ where
             has_errored: false,
             txenv,
             sig_type: self.sig_type(),
+            #[cfg(feature = "trace")]
+            recorder,
         }
     }
 
@@ -368,6 +439,11 @@ where
     /// which will check standardness and consensus limits, which the interpreter
     /// does not do on its own. Or use the `inferred_descriptor` method which
     /// does this for you.
+    ///
+    /// For taproot script spends, the inferred descriptor only knows the spent
+    /// leaf; the rest of the tree is recovered from the merkle path of the
+    /// control block as `hidden(..)` nodes. Such a descriptor has the same
+    /// script pubkey as the spent output.
     pub fn inferred_descriptor_string(&self) -> String {
         match self.inner {
             inner::Inner::PublicKey(ref pk, inner::PubkeyType::Pk) => format!("elpk({})", pk),
@@ -392,9 +468,23 @@ where
                 format!("elcovwsh({},{})", pk, ms)
             }
             inner::Inner::Script(ref ms, inner::ScriptType::Tr) => {
-                // Hidden paths are still under discussion, once the spec is finalized, we can support
-                // rawnode and raw leaf.
-                format!("eltr(hidden_paths_not_yet_supported,{})", ms)
+                let ctrl_blk = self
+                    .tap_control_block
+                    .as_ref()
+                    .expect("Control block is always set for taproot script spends");
+                // The merkle branch is ordered from the leaf to the root, so the
+                // revealed leaf ends up the deepest node of the tree
+                let mut tree = ms.to_string();
+                for hash in ctrl_blk.merkle_branch.as_inner() {
+                    // Hidden nodes of taproot trees are written as plain sha256 hashes
+                    let hash = sha256::Hash::from_byte_array(hash.to_byte_array());
+                    tree = format!("{{{},hidden({})}}", tree, hash);
+                }
+                format!(
+                    "eltr({},{})",
+                    BitcoinKey::XOnlyPublicKey(ctrl_blk.internal_key),
+                    tree
+                )
             }
         }
     }
@@ -497,6 +587,63 @@ where
     }
 }
 
+/// Constraints satisfied by a transaction input, or the error encountered
+/// while interpreting it. Reported per input by [`verify_transaction`].
+pub type InputReport = Result<Vec<SatisfiedConstraint<CovenantExt<CovExtArgs>>>, Error>;
+
+/// Interprets every input of `tx`, verifying all signatures against the
+/// Elements sighash of the input
+///
+/// `spent_utxos` must contain the outputs spent by `tx`, in input order. They
+/// are used for segwit v0 and taproot sighashes and as the spent outputs seen
+/// by introspection extensions. The `genesis_hash` is required for taproot
+/// sighash computation.
+///
+/// Returns one [`InputReport`] per input, in input order. An input is
+/// interpreted with the sequence of the input as its relative locktime and
+/// the locktime of `tx` as its absolute locktime. Pegin inputs are interpreted
+/// as claims of their claim script, see [`Interpreter::from_pegin_txin`]; their
+/// spent utxo is expected to be [`PeginInfo::spent_utxo`].
+///
+/// Inputs whose scripts are not Miniscript are reported as errors; they can
+/// be executed with the raw script VM using [`eval_raw_input`].
+pub fn verify_transaction<C: secp256k1_zkp::Verification>(
+    tx: &elements::Transaction,
+    spent_utxos: &[elements::TxOut],
+    genesis_hash: elements::BlockHash,
+    secp: &secp256k1_zkp::Secp256k1<C>,
+) -> Result<Vec<InputReport>, Error> {
+    if tx.input.len() != spent_utxos.len() {
+        return Err(Error::SpentUtxosLenMismatch {
+            n_inputs: tx.input.len(),
+            n_spent_utxos: spent_utxos.len(),
+        });
+    }
+    let reports = tx
+        .input
+        .iter()
+        .zip(spent_utxos)
+        .enumerate()
+        .map(|(idx, (txin, utxo))| {
+            let interpreter = if txin.is_pegin {
+                Interpreter::from_pegin_txin(txin, tx.lock_time)?
+            } else {
+                Interpreter::from_txdata(
+                    &utxo.script_pubkey,
+                    &txin.script_sig,
+                    &txin.witness.script_witness,
+                    txin.sequence,
+                    tx.lock_time,
+                )?
+            };
+            let txenv = TxEnv::new(tx, spent_utxos, idx).expect("Lengths checked above");
+            let constraints: InputReport = interpreter.iter(secp, &txenv, genesis_hash).collect();
+            constraints
+        })
+        .collect();
+    Ok(reports)
+}
+
 /// Type of HashLock used for SatisfiedConstraint structure
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum HashLockType {
@@ -609,6 +756,8 @@ where
     cov: Option<&'intp BitcoinKey>,
     has_errored: bool,
     sig_type: SigType,
+    #[cfg(feature = "trace")]
+    recorder: trace::Recorder<'intp, Ext>,
 }
 
 ///Iterator for Iter
@@ -655,6 +804,8 @@ where
     /// Helper function to step the iterator
     fn iter_next(&mut self) -> Option<Result<SatisfiedConstraint<Ext>, Error>> {
         while let Some(node_state) = self.state.pop() {
+            #[cfg(feature = "trace")]
+            self.recorder.begin(&node_state, &self.state, &self.stack);
             //non-empty stack
             match node_state.node.node {
                 Terminal::True => {
@@ -749,6 +900,8 @@ where
                 }
                 Terminal::Ext(ref ext) => {
                     let res = ext.evaluate(&mut self.stack, self.txenv);
+                    #[cfg(feature = "trace")]
+                    self.recorder.ext_result(&res);
                     match res {
                         Ok(true) => {
                             return Some(Ok(SatisfiedConstraint::Ext {
@@ -1078,6 +1231,9 @@ where
             };
         }
 
+        #[cfg(feature = "trace")]
+        self.recorder.finish(&self.state, &self.stack);
+
         //state empty implies that either the execution has terminated or we have a
         //Pk based descriptor or a Covenant descriptor
         if let Some(pk) = self.cov {
@@ -1305,6 +1461,8 @@ mod tests {
                 has_errored: false,
                 txenv: None,
                 sig_type: SigType::Ecdsa,
+                #[cfg(feature = "trace")]
+                recorder: trace::Recorder::new(Some(ms), 1),
             }
         }
 
@@ -1748,6 +1906,141 @@ mod tests {
         assert!(multi_a_error.is_err());
     }
 
+    #[cfg(feature = "trace")]
+    #[test]
+    fn trace_or_i() {
+        let (pks, der_sigs, ..) = setup_keys_sigs(1);
+        let sha256_hash = sha256::Hash::hash(&[0xab; 32]);
+        let elem = no_checks_ms(&format!(
+            "or_i(jtv:sha256({}),c:pk_k({}))",
+            sha256_hash, pks[0]
+        ));
+        let stack = Stack::from(vec![
+            stack::Element::Push(&der_sigs[0]),
+            stack::Element::Dissatisfied,
+        ]);
+        let mut iter = Iter {
+            verify_sig: Box::new(|_| true),
+            stack,
+            public_key: None,
+            state: vec![NodeEvaluationState {
+                node: &elem,
+                n_evaluated: 0,
+                n_satisfied: 0,
+            }],
+            age: Sequence::ZERO,
+            lock_time: LockTime::ZERO,
+            cov: None,
+            has_errored: false,
+            txenv: None,
+            sig_type: SigType::Ecdsa,
+            recorder: trace::Recorder::new(Some(&elem), 1),
+        };
+        assert!(iter.by_ref().all(|res| res.is_ok()));
+
+        let trace = iter.into_trace();
+        let steps: Vec<_> = trace
+            .steps
+            .iter()
+            .map(|step| (step.depth, step.fragment.as_str(), step.branch))
+            .collect();
+        let pk_k = format!("pk_k({})", pks[0]);
+        assert_eq!(
+            steps,
+            vec![
+                (0, "or_i", Some(1)),
+                (1, "c:", Some(0)),
+                (2, pk_k.as_str(), None)
+            ]
+        );
+        assert_eq!(
+            trace.steps[0].stack_before,
+            vec![der_sigs[0].clone(), vec![]]
+        );
+        assert_eq!(trace.steps[0].stack_after, vec![der_sigs[0].clone()]);
+        assert_eq!(trace.steps[2].stack_after, vec![vec![1]]);
+
+        // The Miniscript is laid out one fragment per line, annotated with its steps
+        let sha256 = format!("jtv:sha256({}),", sha256_hash);
+        let pk = format!("pk({})", pks[0]);
+        let lines: Vec<_> = trace
+            .lines
+            .iter()
+            .map(|line| (line.depth, line.text.as_str()))
+            .collect();
+        assert_eq!(
+            lines,
+            vec![(0, "or_i("), (1, sha256.as_str()), (1, pk.as_str()), (0, ")")]
+        );
+        let text: String = trace.lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(text, elem.to_string());
+        let steps: Vec<_> = trace.steps.iter().map(|step| step.line).collect();
+        assert_eq!(steps, vec![Some(0), Some(2), Some(2)]);
+        let display = trace.to_string();
+        let display: Vec<_> = display.lines().collect();
+        assert_eq!(display.len(), 4);
+        assert!(display[0].starts_with("or_i(  #0 ["));
+        assert!(display[0].ends_with("] branch 1"));
+        assert_eq!(display[1], format!("  {}", sha256));
+        assert!(display[2].starts_with(&format!("  {}  #1 c: [", pk)));
+        assert!(display[2].contains("  #2 ["));
+        assert!(display[2].ends_with("] -> [01]"));
+        assert_eq!(display[3], ")");
+    }
+
+    #[test]
+    fn inferred_tr_script_spend() {
+        use elements::taproot::LeafVersion;
+
+        use crate::Tap;
+
+        let (.., xpks, _, ser_schnorr_sigs) = setup_keys_sigs(4);
+        let desc = Descriptor::<bitcoin::key::XOnlyPublicKey, NoExt>::from_str(&format!(
+            "eltr({},{{pk({}),{{pk({}),pk({})}}}})",
+            xpks[0], xpks[1], xpks[2], xpks[3]
+        ))
+        .unwrap();
+        let tr = match desc {
+            Descriptor::Tr(ref tr) => tr,
+            _ => unreachable!(),
+        };
+
+        // Spend the deepest leaf, hiding the other two behind the merkle branch
+        let leaf = Miniscript::<bitcoin::key::XOnlyPublicKey, Tap>::from_str(&format!(
+            "pk({})",
+            xpks[2]
+        ))
+        .unwrap();
+        let script = leaf.encode();
+        let ctrl_blk = tr
+            .spend_info()
+            .control_block(&(script.clone(), LeafVersion::default()))
+            .unwrap();
+        let spk = desc.script_pubkey();
+        let script_sig = elements::Script::new();
+        let witness = vec![
+            ser_schnorr_sigs[2].clone(),
+            script.into_bytes(),
+            ctrl_blk.serialize(),
+        ];
+        let interpreter =
+            Interpreter::from_txdata(&spk, &script_sig, &witness, Sequence::ZERO, LockTime::ZERO)
+                .unwrap();
+
+        let inferred = Descriptor::<bitcoin::key::XOnlyPublicKey, NoExt>::from_str(
+            &interpreter.inferred_descriptor_string(),
+        )
+        .unwrap();
+        match inferred {
+            Descriptor::Tr(ref tr) => {
+                assert!(tr.has_hidden_nodes());
+                assert_eq!(tr.iter_scripts().count(), 1);
+            }
+            _ => panic!("inferred descriptor must be eltr"),
+        }
+        assert_eq!(inferred.script_pubkey(), spk);
+    }
+
     // By design there is no support for parse a miniscript with BitcoinKey
     // because it does not implement FromStr
     fn no_checks_ms(ms: &str) -> Miniscript<BitcoinKey, NoChecks> {
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Pegin inputs
+//!
+//! A pegin input spends a mainchain output locked to the federation. On the
+//! Elements side it is claimed as if it was an output with the claim script as
+//! its scriptPubKey, while the pegin witness carries the claimed amount, the
+//! claim script and the mainchain transaction with its merkle proof.
+//!
+
+use std::convert::TryFrom;
+
+use bitcoin::hashes::Hash;
+use elements::{confidential, AssetId};
+
+use super::Error;
+
+/// Number of elements of a pegin witness
+const PEGIN_WITNESS_LEN: usize = 6;
+
+/// Data of a pegin input recovered from its pegin witness
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PeginInfo {
+    /// The mainchain outpoint being pegged in
+    pub mainchain_outpoint: bitcoin::OutPoint,
+    /// The claimed amount, equal to the value of the mainchain output
+    pub value: u64,
+    /// The asset the pegin is claimed in
+    pub asset: AssetId,
+    /// Genesis hash of the mainchain
+    pub parent_genesis_hash: bitcoin::BlockHash,
+    /// The claim script, the Elements-side scriptPubKey of the pegin
+    pub claim_script: elements::Script,
+    /// The scriptPubKey of the mainchain output, paying to the federation
+    /// script tweaked with the claim script
+    pub mainchain_script_pubkey: bitcoin::ScriptBuf,
+}
+
+impl PeginInfo {
+    /// Parses the pegin witness of `txin`
+    ///
+    /// The mainchain transaction is checked to match the outpoint and the
+    /// amount of the pegin. The merkle proof is not checked against mainchain
+    /// headers, and the federation script is not checked to commit to the
+    /// claim script.
+    pub fn from_txin(txin: &elements::TxIn) -> Result<Self, Error> {
+        if !txin.is_pegin {
+            return Err(Error::NotPegin);
+        }
+        let witness = &txin.witness.pegin_witness;
+        if witness.len() != PEGIN_WITNESS_LEN {
+            return Err(Error::PeginWitnessMalformed("expected 6 witness elements"));
+        }
+        let value = match <[u8; 8]>::try_from(&witness[0][..]) {
+            Ok(bytes) => u64::from_le_bytes(bytes),
+            Err(_) => return Err(Error::PeginWitnessMalformed("value must be 8 bytes")),
+        };
+        let asset = AssetId::from_slice(&witness[1])
+            .map_err(|_| Error::PeginWitnessMalformed("asset must be 32 bytes"))?;
+        let parent_genesis_hash = bitcoin::BlockHash::from_slice(&witness[2])
+            .map_err(|_| Error::PeginWitnessMalformed("genesis hash must be 32 bytes"))?;
+        let claim_script = elements::Script::from(witness[3].clone());
+        let mainchain_tx: bitcoin::Transaction =
+            bitcoin::consensus::deserialize(&witness[4]).map_err(Error::PeginTxDecode)?;
+
+        let mainchain_outpoint = bitcoin::OutPoint::new(
+            bitcoin::Txid::from_raw_hash(txin.previous_output.txid.to_raw_hash()),
+            txin.previous_output.vout,
+        );
+        if mainchain_tx.txid() != mainchain_outpoint.txid {
+            return Err(Error::PeginOutpointMismatch);
+        }
+        let mainchain_txout = mainchain_tx
+            .output
+            .get(mainchain_outpoint.vout as usize)
+            .ok_or(Error::PeginOutpointMismatch)?;
+        if mainchain_txout.value != value {
+            return Err(Error::PeginValueMismatch {
+                witness_value: value,
+                mainchain_value: mainchain_txout.value,
+            });
+        }
+
+        Ok(PeginInfo {
+            mainchain_outpoint,
+            value,
+            asset,
+            parent_genesis_hash,
+            claim_script,
+            mainchain_script_pubkey: mainchain_txout.script_pubkey.clone(),
+        })
+    }
+
+    /// The output spent by the pegin as seen from the Elements chain
+    ///
+    /// This is an explicit output of the claimed amount and asset locked with
+    /// the claim script, to be used as the spent utxo of the input when
+    /// computing sighashes.
+    pub fn spent_utxo(&self) -> elements::TxOut {
+        elements::TxOut {
+            asset: confidential::Asset::Explicit(self.asset),
+            value: confidential::Value::Explicit(self.value),
+            nonce: confidential::Nonce::Null,
+            script_pubkey: self.claim_script.clone(),
+            witness: elements::TxOutWitness::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::{LockTime, OutPoint, Script, Sequence, TxIn, TxInWitness};
+
+    use super::*;
+    use crate::interpreter::Interpreter;
+
+    fn pegin_txin(mainchain_tx: &bitcoin::Transaction, value: u64, claim_script: &Script) -> TxIn {
+        TxIn {
+            previous_output: OutPoint {
+                txid: elements::Txid::from_raw_hash(mainchain_tx.txid().to_raw_hash()),
+                vout: 0,
+            },
+            is_pegin: true,
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            asset_issuance: Default::default(),
+            witness: TxInWitness {
+                pegin_witness: vec![
+                    value.to_le_bytes().to_vec(),
+                    vec![3; 32],
+                    vec![6; 32],
+                    claim_script.to_bytes(),
+                    bitcoin::consensus::serialize(mainchain_tx),
+                    vec![],
+                ],
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn pegin_claim() {
+        let pk = bitcoin::PublicKey::from_str(
+            "028c28a97bf8298bc0d23d8c749452a32e694b65e30a9472a3954ab30fe5324caa",
+        )
+        .unwrap();
+        let claim_desc = crate::Descriptor::<_, crate::NoExt>::new_wpkh(pk).unwrap();
+        let claim_script = claim_desc.script_pubkey();
+        let mainchain_script_pubkey = bitcoin::ScriptBuf::from(vec![0x00; 34]);
+        let mainchain_tx = bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![bitcoin::TxOut {
+                value: 100_000,
+                script_pubkey: mainchain_script_pubkey.clone(),
+            }],
+        };
+
+        let mut txin = pegin_txin(&mainchain_tx, 100_000, &claim_script);
+        txin.witness.script_witness = vec![vec![0x30; 72], pk.to_bytes()];
+        let interpreter = Interpreter::from_pegin_txin(&txin, LockTime::ZERO).unwrap();
+        assert_eq!(
+            interpreter.inferred_descriptor_string(),
+            format!("elwpkh({})", pk)
+        );
+        let pegin = interpreter.pegin().unwrap();
+        assert_eq!(pegin.mainchain_outpoint.txid, mainchain_tx.txid());
+        assert_eq!(pegin.value, 100_000);
+        assert_eq!(pegin.mainchain_script_pubkey, mainchain_script_pubkey);
+        assert_eq!(pegin.spent_utxo().script_pubkey, claim_script);
+
+        // Amount claimed differs from the mainchain output
+        let bad_value = pegin_txin(&mainchain_tx, 200_000, &claim_script);
+        assert!(matches!(
+            PeginInfo::from_txin(&bad_value),
+            Err(Error::PeginValueMismatch {
+                witness_value: 200_000,
+                mainchain_value: 100_000,
+            })
+        ));
+
+        // Not a pegin
+        let mut not_pegin = txin.clone();
+        not_pegin.is_pegin = false;
+        assert!(matches!(
+            PeginInfo::from_txin(&not_pegin),
+            Err(Error::NotPegin)
+        ));
+    }
+}
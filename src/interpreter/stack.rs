@@ -3,6 +3,7 @@
 
 //! Interpreter stack
 
+use std::marker::PhantomData;
 use std::ops::Index;
 
 use elements::hashes::{hash160, ripemd160, sha256, Hash};
@@ -91,24 +92,33 @@ impl<'txin> Element<'txin> {
 
 /// Stack Data structure representing the stack input to Miniscript. This Stack
 /// is created from the combination of ScriptSig and Witness stack.
-#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash, Default)]
-pub struct Stack<'txin>(pub(super) Vec<Element<'txin>>);
+///
+/// The raw script VM reuses it with elements which may be computed during
+/// execution rather than borrowed from the transaction.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
+pub struct Stack<'txin, E = Element<'txin>>(pub(super) Vec<E>, PhantomData<&'txin [u8]>);
+
+impl<'txin, E> Default for Stack<'txin, E> {
+    fn default() -> Self {
+        Stack(vec![], PhantomData)
+    }
+}
 
-impl<'txin> From<Vec<Element<'txin>>> for Stack<'txin> {
-    fn from(v: Vec<Element<'txin>>) -> Self {
-        Stack(v)
+impl<'txin, E> From<Vec<E>> for Stack<'txin, E> {
+    fn from(v: Vec<E>) -> Self {
+        Stack(v, PhantomData)
     }
 }
 
-impl<'txin> Index<usize> for Stack<'txin> {
-    type Output = Element<'txin>;
+impl<'txin, E> Index<usize> for Stack<'txin, E> {
+    type Output = E;
 
     fn index(&self, index: usize) -> &Self::Output {
         &self.0[index]
     }
 }
 
-impl<'txin> Stack<'txin> {
+impl<'txin, E> Stack<'txin, E> {
     /// Whether the stack is empty
     pub fn is_empty(&self) -> bool {
         self.0.is_empty()
@@ -120,26 +130,28 @@ impl<'txin> Stack<'txin> {
     }
 
     /// Removes the top stack element, if the stack is nonempty
-    pub fn pop(&mut self) -> Option<Element<'txin>> {
+    pub fn pop(&mut self) -> Option<E> {
         self.0.pop()
     }
 
     /// Pushes an element onto the top of the stack
-    pub fn push(&mut self, elem: Element<'txin>) {
+    pub fn push(&mut self, elem: E) {
         self.0.push(elem);
     }
 
     /// Returns a new stack representing the top `k` elements of the stack,
     /// removing these elements from the original
-    pub fn split_off(&mut self, k: usize) -> Vec<Element<'txin>> {
+    pub fn split_off(&mut self, k: usize) -> Vec<E> {
         self.0.split_off(k)
     }
 
     /// Returns a reference to the top stack element, if the stack is nonempty
-    pub fn last(&self) -> Option<&Element<'txin>> {
+    pub fn last(&self) -> Option<&E> {
         self.0.last()
     }
+}
 
+impl<'txin> Stack<'txin> {
     /// Helper function to evaluate a Pk Node which takes the
     /// top of the stack as input signature and validates it.
     /// Sat: If the signature witness is correct, 1 is pushed
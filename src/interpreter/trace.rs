@@ -0,0 +1,364 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Interpreter execution traces
+//!
+//! Available with the `trace` feature. Records every fragment visited by an
+//! interpreter [`Iter`] along with the stack it consumed and produced, so that
+//! a failing spend can be followed through the Miniscript step by step.
+//!
+//! The `Display` form of a trace lays the Miniscript out one fragment per line
+//! and annotates every line with the numbered steps which evaluated it.
+//!
+
+use std::collections::HashMap;
+use std::fmt;
+
+use elements::hex::ToHex;
+
+use super::{BitcoinKey, Error, Iter, NodeEvaluationState, Stack};
+use crate::miniscript::context::NoChecks;
+use crate::{Extension, Miniscript, Terminal};
+
+/// A single evaluation step of a Miniscript fragment
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceStep {
+    /// Depth of the fragment in the Miniscript, the root being at depth 0
+    pub depth: usize,
+    /// The fragment visited; wrappers are written as `a:`, `v:`, etc. and
+    /// combinators by name only
+    pub fragment: String,
+    /// The wrapper character if the fragment is a wrapper
+    pub wrapper: Option<char>,
+    /// Number of children of the fragment already evaluated when the step started
+    pub n_evaluated: usize,
+    /// Stack before the step, top of the stack last
+    pub stack_before: Vec<Vec<u8>>,
+    /// Stack after the step, top of the stack last
+    pub stack_after: Vec<Vec<u8>>,
+    /// Index of the child evaluated next, if the step descended into one
+    pub branch: Option<usize>,
+    /// Result of [`crate::extensions::ParseableExt::evaluate`] for extension fragments
+    pub ext_result: Option<Result<bool, String>>,
+    /// Index in [`ExecutionTrace::lines`] of the line displaying the fragment
+    pub line: Option<usize>,
+}
+
+/// A line of the executed Miniscript as laid out in an [`ExecutionTrace`]
+///
+/// A line holds a fragment along with its wrappers, or the closing parenthesis
+/// of a combinator, so that the concatenation of the texts of all the lines is
+/// the `Display` form of the Miniscript.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceLine {
+    /// Nesting depth of the line, the root fragment being at depth 0
+    pub depth: usize,
+    /// Text of the line
+    pub text: String,
+}
+
+/// Structured trace of a Miniscript execution
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ExecutionTrace {
+    /// The executed Miniscript in its `Display` form
+    pub script: String,
+    /// The executed Miniscript laid out one fragment per line
+    pub lines: Vec<TraceLine>,
+    /// Steps in execution order
+    pub steps: Vec<TraceStep>,
+}
+
+impl fmt::Display for ExecutionTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.lines.is_empty() {
+            return writeln!(f, "{}", self.script);
+        }
+        for (i, line) in self.lines.iter().enumerate() {
+            write!(f, "{:indent$}{}", "", line.text, indent = 2 * line.depth)?;
+            for (n, step) in self.steps.iter().enumerate() {
+                if step.line == Some(i) {
+                    fmt_step(f, n, step)?;
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+// Annotation of a line with the step `n` of the trace
+fn fmt_step(f: &mut fmt::Formatter<'_>, n: usize, step: &TraceStep) -> fmt::Result {
+    write!(f, "  #{}", n)?;
+    if let Some(ch) = step.wrapper {
+        write!(f, " {}:", ch)?;
+    }
+    if step.n_evaluated > 0 {
+        write!(f, " (resumed after {} children)", step.n_evaluated)?;
+    }
+    write!(f, " [")?;
+    fmt_stack(f, &step.stack_before)?;
+    write!(f, "] -> [")?;
+    fmt_stack(f, &step.stack_after)?;
+    write!(f, "]")?;
+    if let Some(branch) = step.branch {
+        write!(f, " branch {}", branch)?;
+    }
+    match step.ext_result {
+        Some(Ok(res)) => write!(f, " evaluate: {}", res),
+        Some(Err(ref e)) => write!(f, " evaluate: error: {}", e),
+        None => Ok(()),
+    }
+}
+
+fn fmt_stack(f: &mut fmt::Formatter<'_>, stack: &[Vec<u8>]) -> fmt::Result {
+    for (i, elem) in stack.iter().enumerate() {
+        if i > 0 {
+            f.write_str(" ")?;
+        }
+        if elem.is_empty() {
+            f.write_str("<>")?;
+        } else {
+            write!(f, "{}", elem.to_hex())?;
+        }
+    }
+    Ok(())
+}
+
+fn stack_snapshot(stack: &Stack<'_>) -> Vec<Vec<u8>> {
+    stack
+        .0
+        .iter()
+        .map(|elem| elem.into_slice().to_vec())
+        .collect()
+}
+
+// Name of a fragment as displayed in a trace along with its wrapper character
+fn fragment_name<Ext: Extension>(
+    node: &Miniscript<BitcoinKey, NoChecks, Ext>,
+) -> (String, Option<char>) {
+    let wrapper = match node.node {
+        Terminal::Alt(..) => Some('a'),
+        Terminal::Swap(..) => Some('s'),
+        Terminal::Check(..) => Some('c'),
+        Terminal::DupIf(..) => Some('d'),
+        Terminal::Verify(..) => Some('v'),
+        Terminal::NonZero(..) => Some('j'),
+        Terminal::ZeroNotEqual(..) => Some('n'),
+        _ => None,
+    };
+    if let Some(ch) = wrapper {
+        return (format!("{}:", ch), Some(ch));
+    }
+    let name = match node.node {
+        Terminal::AndV(..) => "and_v".to_owned(),
+        Terminal::AndB(..) => "and_b".to_owned(),
+        Terminal::AndOr(..) => "andor".to_owned(),
+        Terminal::OrB(..) => "or_b".to_owned(),
+        Terminal::OrC(..) => "or_c".to_owned(),
+        Terminal::OrD(..) => "or_d".to_owned(),
+        Terminal::OrI(..) => "or_i".to_owned(),
+        Terminal::Thresh(k, ref subs) => format!("thresh({} of {})", k, subs.len()),
+        ref leaf => leaf.to_string(),
+    };
+    (name, None)
+}
+
+// The wrapper character of a fragment along with the wrapped fragment, the
+// constant child of `t:`, `l:` and `u:` being part of the wrapper
+fn wrapped<Ext: Extension>(
+    node: &Miniscript<BitcoinKey, NoChecks, Ext>,
+) -> Option<(char, &Miniscript<BitcoinKey, NoChecks, Ext>)> {
+    match node.node {
+        Terminal::Alt(ref sub) => Some(('a', &**sub)),
+        Terminal::Swap(ref sub) => Some(('s', &**sub)),
+        Terminal::Check(ref sub) => Some(('c', &**sub)),
+        Terminal::DupIf(ref sub) => Some(('d', &**sub)),
+        Terminal::Verify(ref sub) => Some(('v', &**sub)),
+        Terminal::NonZero(ref sub) => Some(('j', &**sub)),
+        Terminal::ZeroNotEqual(ref sub) => Some(('n', &**sub)),
+        Terminal::AndV(ref sub, ref r) if r.node == Terminal::True => Some(('t', &**sub)),
+        Terminal::OrI(ref sub, ref r) if r.node == Terminal::False => Some(('u', &**sub)),
+        Terminal::OrI(ref l, ref sub) if l.node == Terminal::False => Some(('l', &**sub)),
+        _ => None,
+    }
+}
+
+// Lay `node` out as written by its `Display` implementation, one fragment and
+// its wrappers per line, recording the line of every node by address
+fn layout<Ext: Extension>(
+    node: &Miniscript<BitcoinKey, NoChecks, Ext>,
+    depth: usize,
+    lines: &mut Vec<TraceLine>,
+    line_of: &mut HashMap<usize, usize>,
+) {
+    let line = lines.len();
+    let mut prefix = String::new();
+    let mut inner = node;
+    while let Some((ch, sub)) = wrapped(inner) {
+        for child in inner.branches() {
+            if !std::ptr::eq(child, sub) {
+                line_of.insert(child as *const _ as usize, line);
+            }
+        }
+        line_of.insert(inner as *const _ as usize, line);
+        prefix.push(ch);
+        inner = sub;
+    }
+    line_of.insert(inner as *const _ as usize, line);
+
+    let mut children = inner.branches();
+    if children.is_empty() {
+        lines.push(TraceLine {
+            depth,
+            text: node.to_string(),
+        });
+        return;
+    }
+    if !prefix.is_empty() {
+        prefix.push(':');
+    }
+    let name = match inner.node {
+        Terminal::AndOr(_, _, ref c) if c.node == Terminal::False => {
+            line_of.insert(&**c as *const _ as usize, line);
+            children.pop();
+            "and_n(".to_owned()
+        }
+        Terminal::Thresh(k, _) => format!("thresh({},", k),
+        _ => format!("{}(", fragment_name(inner).0),
+    };
+    lines.push(TraceLine {
+        depth,
+        text: prefix + &name,
+    });
+    let n_children = children.len();
+    for (i, child) in children.into_iter().enumerate() {
+        layout(child, depth + 1, lines, line_of);
+        if i + 1 < n_children {
+            lines.last_mut().expect("a line per child").text.push(',');
+        }
+    }
+    lines.push(TraceLine {
+        depth,
+        text: ")".to_owned(),
+    });
+}
+
+// Records steps of an `Iter`. A step is completed lazily when the next one
+// starts, as the evaluation of a fragment may return early from the iterator.
+pub(super) struct Recorder<'intp, Ext: Extension> {
+    trace: ExecutionTrace,
+    // Depth of every fragment on the evaluation state stack
+    depths: Vec<usize>,
+    // Line of the trace of every fragment, by address
+    line_of: HashMap<usize, usize>,
+    pending: Option<PendingStep<'intp, Ext>>,
+}
+
+struct PendingStep<'intp, Ext: Extension> {
+    step: TraceStep,
+    node: &'intp Miniscript<BitcoinKey, NoChecks, Ext>,
+    state_len: usize,
+}
+
+impl<'intp, Ext: Extension> Recorder<'intp, Ext> {
+    pub(super) fn new(
+        script: Option<&'intp Miniscript<BitcoinKey, NoChecks, Ext>>,
+        state_len: usize,
+    ) -> Self {
+        let mut lines = vec![];
+        let mut line_of = HashMap::new();
+        if let Some(ms) = script {
+            layout(ms, 0, &mut lines, &mut line_of);
+        }
+        Recorder {
+            trace: ExecutionTrace {
+                script: script.map(ToString::to_string).unwrap_or_default(),
+                lines,
+                steps: vec![],
+            },
+            depths: vec![0; state_len],
+            line_of,
+            pending: None,
+        }
+    }
+
+    // Start a step for a fragment just popped from the evaluation state stack
+    pub(super) fn begin(
+        &mut self,
+        node_state: &NodeEvaluationState<'intp, Ext>,
+        state: &[NodeEvaluationState<'intp, Ext>],
+        stack: &Stack<'_>,
+    ) {
+        self.finish(state, stack);
+        let depth = self.depths.pop().unwrap_or(0);
+        let (fragment, wrapper) = fragment_name(node_state.node);
+        let line = self
+            .line_of
+            .get(&(node_state.node as *const _ as usize))
+            .copied();
+        self.pending = Some(PendingStep {
+            step: TraceStep {
+                depth,
+                fragment,
+                wrapper,
+                n_evaluated: node_state.n_evaluated,
+                stack_before: stack_snapshot(stack),
+                stack_after: vec![],
+                branch: None,
+                ext_result: None,
+                line,
+            },
+            node: node_state.node,
+            state_len: state.len(),
+        });
+    }
+
+    // Record the result of evaluating the extension of the current step
+    pub(super) fn ext_result(&mut self, res: &Result<bool, Error>) {
+        if let Some(ref mut pending) = self.pending {
+            pending.step.ext_result = Some(match *res {
+                Ok(b) => Ok(b),
+                Err(ref e) => Err(e.to_string()),
+            });
+        }
+    }
+
+    // Complete the pending step, if any, given the current evaluation state
+    pub(super) fn finish(&mut self, state: &[NodeEvaluationState<'intp, Ext>], stack: &Stack<'_>) {
+        if let Some(mut pending) = self.pending.take() {
+            let depth = pending.step.depth;
+            for pushed in state.iter().skip(pending.state_len) {
+                if std::ptr::eq(pushed.node, pending.node) {
+                    self.depths.push(depth);
+                } else {
+                    self.depths.push(depth + 1);
+                    pending.step.branch = pending
+                        .node
+                        .branches()
+                        .iter()
+                        .position(|child| std::ptr::eq(*child, pushed.node));
+                }
+            }
+            pending.step.stack_after = stack_snapshot(stack);
+            self.trace.steps.push(pending.step);
+        }
+    }
+}
+
+impl<'intp, 'txin: 'intp, Ext> Iter<'intp, 'txin, Ext>
+where
+    Ext: Extension,
+{
+    /// The execution trace recorded so far
+    ///
+    /// The trace is complete once the iterator has been exhausted.
+    pub fn trace(&mut self) -> &ExecutionTrace {
+        self.recorder.finish(&self.state, &self.stack);
+        &self.recorder.trace
+    }
+
+    /// Consumes the iterator, returning the execution trace recorded so far
+    pub fn into_trace(mut self) -> ExecutionTrace {
+        self.recorder.finish(&self.state, &self.stack);
+        self.recorder.trace
+    }
+}
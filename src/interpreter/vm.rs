@@ -0,0 +1,2028 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Raw Script VM
+//!
+//! A fallback interpreter for scripts that do not parse as Miniscript. Unlike
+//! the Miniscript [`super::Interpreter`], which recovers the spending
+//! conditions of an input, the VM executes the scripts opcode by opcode and
+//! only reports whether the input is validly spent, or the opcode at which it
+//! failed.
+//!
+//! The VM supports standard Bitcoin opcodes, the opcodes re-enabled by
+//! Elements (`OP_CAT`, `OP_SUBSTR`, bitwise operations, ...), and in Tapscript
+//! the Elements introspection, 64-bit arithmetic and conversion opcodes along
+//! with `OP_CHECKSIGFROMSTACK`. Streaming SHA256 and elliptic curve opcodes
+//! are not supported. Only consensus rules are enforced, standardness rules
+//! such as minimal pushes or NULLFAIL in legacy scripts are not checked.
+//!
+//! The VM runs on the interpreter [`Stack`], whose elements either borrow
+//! from the witness or hold the data produced by opcodes such as
+//! introspection and arithmetic.
+//!
+
+use std::borrow::Cow;
+use std::{error, fmt};
+
+use elements::hashes::{hash160, ripemd160, sha1, sha256, sha256d, Hash};
+use elements::opcodes::{self, all::*};
+use elements::schnorr::TweakedPublicKey;
+use elements::script::Builder;
+use elements::taproot::{ControlBlock, LeafVersion};
+use elements::{encode, secp256k1_zkp, sighash, EcdsaSighashType, Script};
+
+use super::{Error, Stack};
+use crate::elementssig_from_rawsig;
+use crate::extensions::{
+    asset_to_components, spk_to_components, value_to_components, EvalError, TxEnv,
+};
+use crate::util::is_v1_p2tr;
+
+/// Maximum size of a stack element
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+/// Maximum combined size of the stack and the alt stack
+const MAX_STACK_SIZE: usize = 1000;
+/// Maximum number of non-push opcodes in legacy and segwit v0 scripts
+const MAX_OPS_PER_SCRIPT: usize = 201;
+/// Maximum number of keys in a `CHECKMULTISIG`
+const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+/// Locktimes below this threshold are block heights, timestamps otherwise
+const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+/// First byte of a taproot annex
+const TAPROOT_ANNEX_PREFIX: u8 = 0x50;
+/// Validation weight budget of a Tapscript on top of its witness size
+const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+/// Validation weight consumed by each non-empty signature check in Tapscript
+const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
+
+/// Error raised while executing a script in the raw script VM
+#[derive(Debug)]
+pub enum RawScriptError {
+    /// An opcode required more stack elements than available
+    StackUnderflow,
+    /// The stack and alt stack hold more than 1000 elements
+    StackSize,
+    /// A push or an opcode result exceeds 520 bytes
+    PushSize,
+    /// More than 201 non-push opcodes in a legacy or segwit v0 script
+    OpCount,
+    /// A script number operand exceeds its maximum size
+    NumOverflow,
+    /// An operand has an invalid size or value for the opcode
+    InvalidOperand,
+    /// `IF`/`ELSE`/`ENDIF` are not balanced
+    UnbalancedConditional,
+    /// The argument of `IF`/`NOTIF` in Tapscript is not minimal
+    MinimalIf,
+    /// A `VERIFY` opcode failed
+    VerifyFailed,
+    /// `OP_RETURN` was executed
+    OpReturn,
+    /// A disabled opcode is present in the script
+    DisabledOpcode,
+    /// A reserved or invalid opcode was executed
+    BadOpcode,
+    /// The opcode is not supported by the VM
+    UnsupportedOpcode,
+    /// A timelock opcode is not satisfied by the transaction
+    UnsatisfiedLocktime,
+    /// A public key could not be parsed
+    PubkeyEncoding,
+    /// A signature could not be parsed
+    SigEncoding,
+    /// A non-empty signature failed to verify in Tapscript
+    SigVerification,
+    /// The signature checks of a Tapscript exceed its validation weight budget
+    ValidationWeight,
+    /// The scriptSig of a P2SH or segwit spend is not push only
+    SigPushOnly,
+    /// The script does not match the committed script hash, or the
+    /// control block does not verify against the output key
+    WitnessProgramMismatch,
+    /// The witness is malformed for the spent output
+    WitnessMalformed,
+    /// The scriptSig of a native segwit spend is not empty
+    WitnessMalleated,
+    /// The script completed with a false or empty stack
+    EvalFalse,
+    /// A segwit script left more than one element on the stack
+    CleanStack,
+    /// Introspection errors
+    Eval(EvalError),
+    /// Sighash and signature errors
+    Interpreter(Error),
+}
+
+impl fmt::Display for RawScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RawScriptError::StackUnderflow => f.write_str("stack underflow"),
+            RawScriptError::StackSize => f.write_str("stack size limit exceeded"),
+            RawScriptError::PushSize => f.write_str("push size limit exceeded"),
+            RawScriptError::OpCount => f.write_str("opcode count limit exceeded"),
+            RawScriptError::NumOverflow => f.write_str("script number overflow"),
+            RawScriptError::InvalidOperand => f.write_str("invalid operand"),
+            RawScriptError::UnbalancedConditional => f.write_str("unbalanced conditional"),
+            RawScriptError::MinimalIf => f.write_str("non-minimal IF argument"),
+            RawScriptError::VerifyFailed => f.write_str("VERIFY failed"),
+            RawScriptError::OpReturn => f.write_str("OP_RETURN executed"),
+            RawScriptError::DisabledOpcode => f.write_str("disabled opcode"),
+            RawScriptError::BadOpcode => f.write_str("bad opcode"),
+            RawScriptError::UnsupportedOpcode => f.write_str("opcode not supported"),
+            RawScriptError::UnsatisfiedLocktime => f.write_str("locktime requirement not met"),
+            RawScriptError::PubkeyEncoding => f.write_str("invalid public key"),
+            RawScriptError::SigEncoding => f.write_str("invalid signature encoding"),
+            RawScriptError::SigVerification => f.write_str("signature verification failed"),
+            RawScriptError::ValidationWeight => f.write_str("validation weight budget exceeded"),
+            RawScriptError::SigPushOnly => f.write_str("scriptSig is not push only"),
+            RawScriptError::WitnessProgramMismatch => f.write_str("witness program mismatch"),
+            RawScriptError::WitnessMalformed => f.write_str("malformed witness"),
+            RawScriptError::WitnessMalleated => f.write_str("non-empty scriptSig for segwit spend"),
+            RawScriptError::EvalFalse => f.write_str("script evaluated to false"),
+            RawScriptError::CleanStack => f.write_str("stack not clean after execution"),
+            RawScriptError::Eval(ref e) => fmt::Display::fmt(e, f),
+            RawScriptError::Interpreter(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl error::Error for RawScriptError {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match *self {
+            RawScriptError::Interpreter(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Failure of the raw script VM, along with the location of the failure
+#[derive(Debug)]
+pub struct RawScriptFailure {
+    /// The script being executed when the failure occurred, if any
+    pub script: Option<Script>,
+    /// Byte offset of the failing opcode in `script`, if the failure was
+    /// caused by an opcode
+    pub pos: Option<usize>,
+    /// The failing opcode
+    pub opcode: Option<opcodes::All>,
+    /// The cause of the failure
+    pub error: RawScriptError,
+}
+
+impl RawScriptFailure {
+    fn new(error: RawScriptError) -> Self {
+        RawScriptFailure {
+            script: None,
+            pos: None,
+            opcode: None,
+            error,
+        }
+    }
+}
+
+impl From<RawScriptError> for RawScriptFailure {
+    fn from(error: RawScriptError) -> Self {
+        RawScriptFailure::new(error)
+    }
+}
+
+impl fmt::Display for RawScriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.pos, self.opcode) {
+            (Some(pos), Some(op)) => write!(f, "{:?} at byte {}: {}", op, pos, self.error),
+            _ => fmt::Display::fmt(&self.error, f),
+        }
+    }
+}
+
+impl error::Error for RawScriptFailure {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        Some(&self.error)
+    }
+}
+
+/// Executes the scripts of the input `txenv.idx()` against the transaction
+/// environment, without requiring them to be Miniscript
+///
+/// This is an opt-in fallback for inputs that cannot be parsed by
+/// [`super::Interpreter`]. Bare, P2SH, segwit v0 and taproot spends are
+/// supported; outputs with unknown witness versions are anyone-can-spend. The
+/// `genesis_hash` is required for taproot sighash computation.
+pub fn eval_raw_input<C: secp256k1_zkp::Verification>(
+    secp: &secp256k1_zkp::Secp256k1<C>,
+    txenv: &TxEnv<'_, '_>,
+    genesis_hash: elements::BlockHash,
+) -> Result<(), RawScriptFailure> {
+    let txin = &txenv.tx().input[txenv.idx()];
+    let spk = &txenv.spent_utxos()[txenv.idx()].script_pubkey;
+    let witness = &txin.witness.script_witness;
+    let ctx = Ctx {
+        secp,
+        txenv,
+        genesis_hash,
+    };
+
+    let mut stack = ctx.execute(&txin.script_sig, SigVersion::Base, Stack::default())?;
+    let script_sig_len = stack.len();
+    let is_push_only = txin.script_sig.is_push_only();
+
+    let mut program = spk.clone();
+    if spk.is_p2sh() {
+        if !is_push_only {
+            return Err(RawScriptError::SigPushOnly.into());
+        }
+        let redeem = match stack.pop() {
+            Some(redeem) => Script::from(redeem.into_owned()),
+            None => return Err(RawScriptError::StackUnderflow.into()),
+        };
+        if *spk != Script::new_p2sh(&hash160::Hash::hash(redeem.as_bytes()).into()) {
+            return Err(RawScriptError::WitnessProgramMismatch.into());
+        }
+        if redeem.is_witness_program() {
+            // Nested segwit, the scriptSig must be exactly the push of the program
+            if script_sig_len != 1 {
+                return Err(RawScriptError::WitnessMalleated.into());
+            }
+            program = redeem;
+        } else {
+            if !witness.is_empty() {
+                return Err(RawScriptError::WitnessMalformed.into());
+            }
+            let stack = ctx.execute(&redeem, SigVersion::Base, stack)?;
+            return check_final_stack(&redeem, &stack, false);
+        }
+    } else if spk.is_witness_program() {
+        if !txin.script_sig.is_empty() {
+            return Err(RawScriptError::WitnessMalleated.into());
+        }
+    } else {
+        if !witness.is_empty() {
+            return Err(RawScriptError::WitnessMalformed.into());
+        }
+        let stack = ctx.execute(spk, SigVersion::Base, stack)?;
+        return check_final_stack(spk, &stack, false);
+    }
+
+    ctx.execute_witness_program(&program, witness)
+}
+
+fn check_final_stack(
+    script: &Script,
+    stack: &VmStack<'_>,
+    clean_stack: bool,
+) -> Result<(), RawScriptFailure> {
+    let err = match stack.last() {
+        Some(top) if !as_bool(top) => RawScriptError::EvalFalse,
+        None => RawScriptError::EvalFalse,
+        Some(_) if clean_stack && stack.len() != 1 => RawScriptError::CleanStack,
+        Some(_) => return Ok(()),
+    };
+    Err(RawScriptFailure {
+        script: Some(script.clone()),
+        ..RawScriptFailure::new(err)
+    })
+}
+
+// Stack elements borrow from the witness, or are computed by opcodes
+type VmStack<'a> = Stack<'a, Cow<'a, [u8]>>;
+
+fn witness_stack(witness: &[Vec<u8>]) -> VmStack<'_> {
+    Stack::from(
+        witness
+            .iter()
+            .map(|elem| Cow::Borrowed(&elem[..]))
+            .collect::<Vec<_>>(),
+    )
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum SigVersion {
+    Base,
+    WitnessV0,
+    Tapscript,
+}
+
+// Environment shared by all scripts executed for an input
+struct Ctx<'a, 'tx, 'ptx, C: secp256k1_zkp::Verification> {
+    secp: &'a secp256k1_zkp::Secp256k1<C>,
+    txenv: &'a TxEnv<'tx, 'ptx>,
+    genesis_hash: elements::BlockHash,
+}
+
+impl<'a, 'tx, 'ptx, C: secp256k1_zkp::Verification> Ctx<'a, 'tx, 'ptx, C> {
+    fn execute(
+        &self,
+        script: &Script,
+        sig_version: SigVersion,
+        stack: VmStack<'a>,
+    ) -> Result<VmStack<'a>, RawScriptFailure> {
+        let txin = &self.txenv.tx().input[self.txenv.idx()];
+        let witness_size = encode::serialize(&txin.witness.script_witness).len();
+        let mut vm = Vm {
+            ctx: self,
+            script,
+            sig_version,
+            stack,
+            alt_stack: Stack::default(),
+            exec: vec![],
+            codesep_pos: 0,
+            n_ops: 0,
+            validation_weight_left: VALIDATION_WEIGHT_OFFSET + witness_size as i64,
+        };
+        match vm.run() {
+            Ok(()) => Ok(vm.stack),
+            Err((pos, opcode, error)) => Err(RawScriptFailure {
+                script: Some(script.clone()),
+                pos,
+                opcode,
+                error,
+            }),
+        }
+    }
+
+    fn execute_witness_program(
+        &self,
+        program: &Script,
+        witness: &'a [Vec<u8>],
+    ) -> Result<(), RawScriptFailure> {
+        if program.is_v0_p2wsh() {
+            let (script, stack) = match witness.split_last() {
+                Some((script, stack)) => (Script::from(script.clone()), stack),
+                None => return Err(RawScriptError::WitnessMalformed.into()),
+            };
+            if *program != Script::new_v0_wsh(&sha256::Hash::hash(script.as_bytes()).into()) {
+                return Err(RawScriptError::WitnessProgramMismatch.into());
+            }
+            check_stack_items(stack)?;
+            let stack = self.execute(&script, SigVersion::WitnessV0, witness_stack(stack))?;
+            check_final_stack(&script, &stack, true)
+        } else if program.is_v0_p2wpkh() {
+            if witness.len() != 2 {
+                return Err(RawScriptError::WitnessMalformed.into());
+            }
+            let script = Script::new_p2pkh(&hash160::Hash::hash(&witness[1]).into());
+            if *program != Script::new_v0_wpkh(&hash160::Hash::hash(&witness[1]).into()) {
+                return Err(RawScriptError::WitnessProgramMismatch.into());
+            }
+            let stack = self.execute(&script, SigVersion::WitnessV0, witness_stack(witness))?;
+            check_final_stack(&script, &stack, true)
+        } else if is_v1_p2tr(program) {
+            self.execute_taproot(program, witness)
+        } else {
+            // Unknown witness versions are anyone-can-spend
+            Ok(())
+        }
+    }
+
+    fn execute_taproot(
+        &self,
+        program: &Script,
+        witness: &'a [Vec<u8>],
+    ) -> Result<(), RawScriptFailure> {
+        let output_key = secp256k1_zkp::XOnlyPublicKey::from_slice(&program[2..])
+            .map_err(|_| RawScriptError::WitnessProgramMismatch)?;
+        if witness.len() >= 2 && witness[witness.len() - 1].first() == Some(&TAPROOT_ANNEX_PREFIX) {
+            // The sighash computation does not support the annex
+            return Err(RawScriptError::Interpreter(Error::TapAnnexUnsupported).into());
+        }
+        match witness.len() {
+            0 => Err(RawScriptError::WitnessMalformed.into()),
+            1 => {
+                let sig = elements::SchnorrSig::from_slice(&witness[0])
+                    .map_err(|_| RawScriptError::SigEncoding)?;
+                let mut cache = sighash::SighashCache::new(self.txenv.tx());
+                let msg = cache
+                    .taproot_key_spend_signature_hash(
+                        self.txenv.idx(),
+                        &sighash::Prevouts::All(self.txenv.spent_utxos()),
+                        sig.hash_ty,
+                        self.genesis_hash,
+                    )
+                    .map_err(|e| RawScriptError::Interpreter(Error::SighashError(e)))?;
+                let msg = secp256k1_zkp::Message::from_slice(msg.as_ref()).expect("32 byte");
+                self.secp
+                    .verify_schnorr(&sig.sig, &msg, &output_key)
+                    .map_err(|_| RawScriptError::SigVerification.into())
+            }
+            _ => {
+                let (ctrl_blk, rest) = witness.split_last().expect("len checked above");
+                let (script, rest) = rest.split_last().expect("len checked above");
+                let ctrl_blk = ControlBlock::from_slice(ctrl_blk)
+                    .map_err(|e| RawScriptError::Interpreter(Error::ControlBlockParse(e)))?;
+                let script = Script::from(script.clone());
+                if !ctrl_blk.verify_taproot_commitment(
+                    self.secp,
+                    &TweakedPublicKey::new(output_key),
+                    &script,
+                ) {
+                    return Err(RawScriptError::WitnessProgramMismatch.into());
+                }
+                if ctrl_blk.leaf_version != LeafVersion::default() {
+                    // Unknown leaf versions are anyone-can-spend
+                    return Ok(());
+                }
+                if has_op_success(&script) {
+                    return Ok(());
+                }
+                check_stack_items(rest)?;
+                let stack = self.execute(&script, SigVersion::Tapscript, witness_stack(rest))?;
+                check_final_stack(&script, &stack, true)
+            }
+        }
+    }
+}
+
+fn check_stack_items(stack: &[Vec<u8>]) -> Result<(), RawScriptFailure> {
+    if stack
+        .iter()
+        .any(|elem| elem.len() > MAX_SCRIPT_ELEMENT_SIZE)
+    {
+        Err(RawScriptError::PushSize.into())
+    } else {
+        Ok(())
+    }
+}
+
+// Whether a tapscript contains an OP_SUCCESSx opcode, making it succeed
+// unconditionally. Opcodes re-enabled by Elements are not OP_SUCCESS.
+fn has_op_success(script: &Script) -> bool {
+    let mut pos = 0;
+    while let Some(Ok((next, ins))) = decode(script.as_bytes(), pos) {
+        if let Ins::Op(op) = ins {
+            let b = op.into_u8();
+            let success = b == 80
+                || b == 98
+                || (137..=138).contains(&b)
+                || (141..=142).contains(&b)
+                || (149..=151).contains(&b)
+                || (187..=254).contains(&b)
+                    && !matches!(b, 0xc1 | 0xc2)
+                    && !(0xc4..=0xe4).contains(&b);
+            if success {
+                return true;
+            }
+        }
+        pos = next;
+    }
+    false
+}
+
+// A decoded script instruction
+enum Ins<'s> {
+    Push(&'s [u8]),
+    Op(opcodes::All),
+}
+
+// Decodes the instruction at `pos`, returning the position of the next one
+fn decode(script: &[u8], pos: usize) -> Option<Result<(usize, Ins<'_>), RawScriptError>> {
+    let b = *script.get(pos)?;
+    let (len_size, len) = match b {
+        0x01..=0x4b => (0, b as usize),
+        0x4c..=0x4e => {
+            let len_size = match b {
+                0x4c => 1,
+                0x4d => 2,
+                _ => 4,
+            };
+            let len_bytes = match script.get(pos + 1..pos + 1 + len_size) {
+                Some(len_bytes) => len_bytes,
+                None => return Some(Err(RawScriptError::BadOpcode)),
+            };
+            let len = len_bytes
+                .iter()
+                .rev()
+                .fold(0usize, |acc, b| (acc << 8) | *b as usize);
+            (len_size, len)
+        }
+        _ => return Some(Ok((pos + 1, Ins::Op(opcodes::All::from(b))))),
+    };
+    let start = pos + 1 + len_size;
+    match script.get(start..start + len) {
+        Some(data) => Some(Ok((start + len, Ins::Push(data)))),
+        None => Some(Err(RawScriptError::BadOpcode)),
+    }
+}
+
+// Reads a script number of at most `max_len` bytes
+fn read_num(v: &[u8], max_len: usize) -> Result<i64, RawScriptError> {
+    if v.len() > max_len {
+        return Err(RawScriptError::NumOverflow);
+    }
+    let (last, _) = match v.split_last() {
+        Some(split) => split,
+        None => return Ok(0),
+    };
+    let mut ret = v
+        .iter()
+        .enumerate()
+        .fold(0i64, |acc, (i, b)| acc | ((*b as i64) << (8 * i)));
+    if last & 0x80 != 0 {
+        ret &= !(0x80i64 << (8 * (v.len() - 1)));
+        ret = -ret;
+    }
+    Ok(ret)
+}
+
+// Serializes a script number
+fn num_bytes(n: i64) -> Vec<u8> {
+    let mut ret = vec![];
+    let neg = n < 0;
+    let mut abs = n.unsigned_abs();
+    while abs > 0 {
+        ret.push((abs & 0xff) as u8);
+        abs >>= 8;
+    }
+    if let Some(last) = ret.last_mut() {
+        if *last & 0x80 != 0 {
+            ret.push(if neg { 0x80 } else { 0 });
+        } else if neg {
+            *last |= 0x80;
+        }
+    }
+    ret
+}
+
+fn as_bool(v: &[u8]) -> bool {
+    match v.split_last() {
+        Some((last, rest)) => rest.iter().any(|b| *b != 0) || (*last != 0 && *last != 0x80),
+        None => false,
+    }
+}
+
+fn bool_bytes(b: bool) -> Vec<u8> {
+    if b {
+        vec![1]
+    } else {
+        vec![]
+    }
+}
+
+fn read_le64(v: &[u8]) -> Result<i64, RawScriptError> {
+    let mut bytes = [0u8; 8];
+    if v.len() != 8 {
+        return Err(RawScriptError::InvalidOperand);
+    }
+    bytes.copy_from_slice(v);
+    Ok(i64::from_le_bytes(bytes))
+}
+
+type VmResult<T> = Result<T, RawScriptError>;
+
+// Execution state of a single script
+struct Vm<'a, 'b, 'tx, 'ptx, C: secp256k1_zkp::Verification> {
+    ctx: &'b Ctx<'a, 'tx, 'ptx, C>,
+    script: &'b Script,
+    sig_version: SigVersion,
+    stack: VmStack<'a>,
+    alt_stack: VmStack<'a>,
+    // Whether each enclosing IF branch is executed
+    exec: Vec<bool>,
+    // Start of the scriptCode, just after the last executed OP_CODESEPARATOR
+    codesep_pos: usize,
+    n_ops: usize,
+    // Remaining budget for signature checks in Tapscript
+    validation_weight_left: i64,
+}
+
+impl<'a, 'b, 'tx, 'ptx, C: secp256k1_zkp::Verification> Vm<'a, 'b, 'tx, 'ptx, C> {
+    fn run(&mut self) -> Result<(), (Option<usize>, Option<opcodes::All>, RawScriptError)> {
+        let bytes = self.script.as_bytes();
+        let mut pos = 0;
+        while let Some(ins) = decode(bytes, pos) {
+            let (next, ins) = ins.map_err(|e| (Some(pos), None, e))?;
+            let executing = self.exec.iter().all(|b| *b);
+            match ins {
+                Ins::Push(data) => {
+                    if data.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err((Some(pos), None, RawScriptError::PushSize));
+                    }
+                    if executing {
+                        self.push_data(data.to_vec());
+                    }
+                }
+                Ins::Op(op) => {
+                    self.step(op, next, executing)
+                        .map_err(|e| (Some(pos), Some(op), e))?;
+                }
+            }
+            if self.stack.len() + self.alt_stack.len() > MAX_STACK_SIZE {
+                return Err((Some(pos), None, RawScriptError::StackSize));
+            }
+            pos = next;
+        }
+        if self.exec.is_empty() {
+            Ok(())
+        } else {
+            Err((None, None, RawScriptError::UnbalancedConditional))
+        }
+    }
+
+    fn pop(&mut self) -> VmResult<Cow<'a, [u8]>> {
+        self.stack.pop().ok_or(RawScriptError::StackUnderflow)
+    }
+
+    fn pop_num(&mut self) -> VmResult<i64> {
+        read_num(&self.pop()?, 4)
+    }
+
+    fn pop_bool(&mut self) -> VmResult<bool> {
+        Ok(as_bool(&self.pop()?))
+    }
+
+    fn pop_le64(&mut self) -> VmResult<i64> {
+        read_le64(&self.pop()?)
+    }
+
+    // Element at depth `i` from the top of the stack
+    fn peek(&self, i: usize) -> VmResult<&Cow<'a, [u8]>> {
+        self.stack
+            .len()
+            .checked_sub(i + 1)
+            .map(|idx| &self.stack[idx])
+            .ok_or(RawScriptError::StackUnderflow)
+    }
+
+    fn require(&self, n: usize) -> VmResult<()> {
+        if self.stack.len() < n {
+            Err(RawScriptError::StackUnderflow)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn push(&mut self, elem: Vec<u8>) -> VmResult<()> {
+        if elem.len() > MAX_SCRIPT_ELEMENT_SIZE {
+            return Err(RawScriptError::PushSize);
+        }
+        self.push_data(elem);
+        Ok(())
+    }
+
+    // Pushes data which is not bounded by the element size limit
+    fn push_data(&mut self, data: Vec<u8>) {
+        self.stack.push(Cow::Owned(data));
+    }
+
+    fn verify(&mut self) -> VmResult<()> {
+        if self.pop_bool()? {
+            Ok(())
+        } else {
+            Err(RawScriptError::VerifyFailed)
+        }
+    }
+
+    fn is_tapscript(&self) -> bool {
+        self.sig_version == SigVersion::Tapscript
+    }
+
+    fn step(&mut self, op: opcodes::All, next: usize, executing: bool) -> VmResult<()> {
+        let code = op.into_u8();
+        if code > OP_PUSHNUM_16.into_u8() && !self.is_tapscript() {
+            self.n_ops += 1;
+            if self.n_ops > MAX_OPS_PER_SCRIPT {
+                return Err(RawScriptError::OpCount);
+            }
+        }
+        // Disabled opcodes fail even in unexecuted branches
+        if matches!(op, OP_2MUL | OP_2DIV | OP_MUL | OP_DIV | OP_MOD) {
+            return Err(RawScriptError::DisabledOpcode);
+        }
+        if matches!(op, OP_VERIF | OP_VERNOTIF) {
+            return Err(RawScriptError::BadOpcode);
+        }
+        let is_conditional = matches!(op, OP_IF | OP_NOTIF | OP_ELSE | OP_ENDIF);
+        if !executing && !is_conditional {
+            return Ok(());
+        }
+
+        match op {
+            OP_PUSHBYTES_0 => self.push_data(vec![]),
+            OP_PUSHNUM_NEG1 => self.push_data(num_bytes(-1)),
+            _ if (OP_PUSHNUM_1.into_u8()..=OP_PUSHNUM_16.into_u8()).contains(&code) => {
+                let n = code - OP_PUSHNUM_1.into_u8() + 1;
+                self.push_data(num_bytes(n as i64))
+            }
+            // Control flow
+            OP_NOP => {}
+            OP_IF | OP_NOTIF => {
+                let mut cond = false;
+                if executing {
+                    let arg = self.pop()?;
+                    if self.is_tapscript() && !(arg.is_empty() || *arg == [1]) {
+                        return Err(RawScriptError::MinimalIf);
+                    }
+                    cond = as_bool(&arg) == (op == OP_IF);
+                }
+                self.exec.push(cond);
+            }
+            OP_ELSE => match self.exec.last_mut() {
+                Some(cond) => *cond = !*cond,
+                None => return Err(RawScriptError::UnbalancedConditional),
+            },
+            OP_ENDIF => {
+                if self.exec.pop().is_none() {
+                    return Err(RawScriptError::UnbalancedConditional);
+                }
+            }
+            OP_VERIFY => self.verify()?,
+            OP_RETURN => return Err(RawScriptError::OpReturn),
+            // Stack operations
+            OP_TOALTSTACK => {
+                let elem = self.pop()?;
+                self.alt_stack.push(elem);
+            }
+            OP_FROMALTSTACK => {
+                let elem = self.alt_stack.pop().ok_or(RawScriptError::StackUnderflow)?;
+                self.stack.push(elem);
+            }
+            OP_2DROP => {
+                self.require(2)?;
+                self.stack.0.truncate(self.stack.len() - 2);
+            }
+            OP_2DUP => {
+                let (a, b) = (self.peek(1)?.clone(), self.peek(0)?.clone());
+                self.stack.0.extend([a, b]);
+            }
+            OP_3DUP => {
+                let (a, b, c) = (
+                    self.peek(2)?.clone(),
+                    self.peek(1)?.clone(),
+                    self.peek(0)?.clone(),
+                );
+                self.stack.0.extend([a, b, c]);
+            }
+            OP_2OVER => {
+                let (a, b) = (self.peek(3)?.clone(), self.peek(2)?.clone());
+                self.stack.0.extend([a, b]);
+            }
+            OP_2ROT => {
+                self.require(6)?;
+                let len = self.stack.len();
+                let moved: Vec<_> = self.stack.0.drain(len - 6..len - 4).collect();
+                self.stack.0.extend(moved);
+            }
+            OP_2SWAP => {
+                self.require(4)?;
+                let len = self.stack.len();
+                self.stack.0.swap(len - 4, len - 2);
+                self.stack.0.swap(len - 3, len - 1);
+            }
+            OP_IFDUP => {
+                let top = self.peek(0)?.clone();
+                if as_bool(&top) {
+                    self.stack.push(top);
+                }
+            }
+            OP_DEPTH => self.push_data(num_bytes(self.stack.len() as i64)),
+            OP_DROP => {
+                self.pop()?;
+            }
+            OP_DUP => {
+                let top = self.peek(0)?.clone();
+                self.stack.push(top);
+            }
+            OP_NIP => {
+                self.require(2)?;
+                let len = self.stack.len();
+                self.stack.0.remove(len - 2);
+            }
+            OP_OVER => {
+                let elem = self.peek(1)?.clone();
+                self.stack.push(elem);
+            }
+            OP_PICK | OP_ROLL => {
+                let n = self.pop_num()?;
+                if n < 0 {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                let n = n as usize;
+                let elem = self.peek(n)?.clone();
+                if op == OP_ROLL {
+                    let len = self.stack.len();
+                    self.stack.0.remove(len - 1 - n);
+                }
+                self.stack.push(elem);
+            }
+            OP_ROT => {
+                self.require(3)?;
+                let len = self.stack.len();
+                let elem = self.stack.0.remove(len - 3);
+                self.stack.push(elem);
+            }
+            OP_SWAP => {
+                self.require(2)?;
+                let len = self.stack.len();
+                self.stack.0.swap(len - 2, len - 1);
+            }
+            OP_TUCK => {
+                self.require(2)?;
+                let top = self.peek(0)?.clone();
+                let len = self.stack.len();
+                self.stack.0.insert(len - 2, top);
+            }
+            // Splice operations, re-enabled in Elements
+            OP_CAT => {
+                let b = self.pop()?;
+                let mut a = self.pop()?.into_owned();
+                a.extend_from_slice(&b);
+                self.push(a)?;
+            }
+            OP_SUBSTR => {
+                let len = self.pop_num()?;
+                let begin = self.pop_num()?;
+                let data = self.pop()?;
+                if len < 0 || begin < 0 {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                let begin = (begin as usize).min(data.len());
+                let end = begin.saturating_add(len as usize).min(data.len());
+                self.push_data(data[begin..end].to_vec());
+            }
+            OP_LEFT | OP_RIGHT => {
+                let n = self.pop_num()?;
+                let data = self.pop()?;
+                if n < 0 {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                let n = (n as usize).min(data.len());
+                let res = if op == OP_LEFT {
+                    data[..n].to_vec()
+                } else {
+                    data[n..].to_vec()
+                };
+                self.push_data(res);
+            }
+            OP_SIZE => {
+                let size = self.peek(0)?.len();
+                self.push_data(num_bytes(size as i64));
+            }
+            // Bitwise operations, re-enabled in Elements
+            OP_INVERT => {
+                let mut a = self.pop()?.into_owned();
+                a.iter_mut().for_each(|b| *b = !*b);
+                self.push_data(a);
+            }
+            OP_AND | OP_OR | OP_XOR => {
+                let b = self.pop()?;
+                let mut a = self.pop()?.into_owned();
+                if a.len() != b.len() {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                for (x, y) in a.iter_mut().zip(b.iter()) {
+                    *x = match op {
+                        OP_AND => *x & *y,
+                        OP_OR => *x | *y,
+                        _ => *x ^ *y,
+                    };
+                }
+                self.push_data(a);
+            }
+            OP_LSHIFT | OP_RSHIFT => {
+                let n = self.pop_num()?;
+                let a = self.pop()?;
+                if n < 0 {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                self.push_data(shift(&a, n as usize, op == OP_LSHIFT));
+            }
+            OP_EQUAL | OP_EQUALVERIFY => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push_data(bool_bytes(a == b));
+                if op == OP_EQUALVERIFY {
+                    self.verify()?;
+                }
+            }
+            // Arithmetic
+            OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                let a = self.pop_num()?;
+                let res = match op {
+                    OP_1ADD => a + 1,
+                    OP_1SUB => a - 1,
+                    OP_NEGATE => -a,
+                    OP_ABS => a.abs(),
+                    OP_NOT => (a == 0) as i64,
+                    _ => (a != 0) as i64,
+                };
+                self.push_data(num_bytes(res));
+            }
+            OP_ADD
+            | OP_SUB
+            | OP_BOOLAND
+            | OP_BOOLOR
+            | OP_NUMEQUAL
+            | OP_NUMEQUALVERIFY
+            | OP_NUMNOTEQUAL
+            | OP_LESSTHAN
+            | OP_GREATERTHAN
+            | OP_LESSTHANOREQUAL
+            | OP_GREATERTHANOREQUAL
+            | OP_MIN
+            | OP_MAX => {
+                let b = self.pop_num()?;
+                let a = self.pop_num()?;
+                let res = match op {
+                    OP_ADD => a + b,
+                    OP_SUB => a - b,
+                    OP_BOOLAND => (a != 0 && b != 0) as i64,
+                    OP_BOOLOR => (a != 0 || b != 0) as i64,
+                    OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                    OP_NUMNOTEQUAL => (a != b) as i64,
+                    OP_LESSTHAN => (a < b) as i64,
+                    OP_GREATERTHAN => (a > b) as i64,
+                    OP_LESSTHANOREQUAL => (a <= b) as i64,
+                    OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                    OP_MIN => a.min(b),
+                    _ => a.max(b),
+                };
+                self.push_data(num_bytes(res));
+                if op == OP_NUMEQUALVERIFY {
+                    self.verify()?;
+                }
+            }
+            OP_WITHIN => {
+                let max = self.pop_num()?;
+                let min = self.pop_num()?;
+                let x = self.pop_num()?;
+                self.push_data(bool_bytes(min <= x && x < max));
+            }
+            // Crypto
+            OP_RIPEMD160 => {
+                let a = self.pop()?;
+                self.push_data(ripemd160::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_SHA1 => {
+                let a = self.pop()?;
+                self.push_data(sha1::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_SHA256 => {
+                let a = self.pop()?;
+                self.push_data(sha256::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_HASH160 => {
+                let a = self.pop()?;
+                self.push_data(hash160::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_HASH256 => {
+                let a = self.pop()?;
+                self.push_data(sha256d::Hash::hash(&a).to_byte_array().to_vec());
+            }
+            OP_CODESEPARATOR => {
+                if self.is_tapscript() {
+                    // The tapscript sighash would need to commit to the position
+                    return Err(RawScriptError::UnsupportedOpcode);
+                }
+                self.codesep_pos = next;
+            }
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                let pk = self.pop()?;
+                let sig = self.pop()?;
+                let res = self.check_sig(&sig, &pk)?;
+                self.push_data(bool_bytes(res));
+                if op == OP_CHECKSIGVERIFY {
+                    self.verify()?;
+                }
+            }
+            OP_CHECKSIGADD if self.is_tapscript() => {
+                let pk = self.pop()?;
+                let n = self.pop_num()?;
+                let sig = self.pop()?;
+                let res = self.check_sig(&sig, &pk)?;
+                self.push_data(num_bytes(n + res as i64));
+            }
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY if !self.is_tapscript() => {
+                let res = self.check_multisig()?;
+                self.push_data(bool_bytes(res));
+                if op == OP_CHECKMULTISIGVERIFY {
+                    self.verify()?;
+                }
+            }
+            OP_CHECKSIGFROMSTACK | OP_CHECKSIGFROMSTACKVERIFY => {
+                let pk = self.pop()?;
+                let msg = self.pop()?;
+                let sig = self.pop()?;
+                let res = self.check_sig_from_stack(&sig, &msg, &pk)?;
+                self.push_data(bool_bytes(res));
+                if op == OP_CHECKSIGFROMSTACKVERIFY {
+                    self.verify()?;
+                }
+            }
+            // Timelocks
+            OP_CLTV => self.check_lock_time()?,
+            OP_CSV => self.check_sequence()?,
+            _ if code == 0xb0 || (0xb3..=0xb9).contains(&code) => {} // OP_NOP1, OP_NOP4-10
+            // Tapscript only Elements opcodes
+            _ if self.is_tapscript() && (0xc4..=0xe4).contains(&code) => self.step_elements(op)?,
+            _ => return Err(RawScriptError::BadOpcode),
+        }
+        Ok(())
+    }
+
+    // Elements introspection, arithmetic and conversion opcodes
+    fn step_elements(&mut self, op: opcodes::All) -> VmResult<()> {
+        let env = self.ctx.txenv;
+        let tx = env.tx();
+        match op {
+            OP_INSPECTINPUTOUTPOINT
+            | OP_INSPECTINPUTASSET
+            | OP_INSPECTINPUTVALUE
+            | OP_INSPECTINPUTSCRIPTPUBKEY
+            | OP_INSPECTINPUTSEQUENCE
+            | OP_INSPECTINPUTISSUANCE => {
+                let idx = self.pop_num()?;
+                let n_inputs = tx.input.len();
+                if idx < 0 || idx as usize >= n_inputs {
+                    return Err(RawScriptError::Eval(EvalError::InputIndexOutOfBounds(
+                        idx as usize,
+                        n_inputs,
+                    )));
+                }
+                let txin = &tx.input[idx as usize];
+                let utxo = env.spent_utxos().get(idx as usize).ok_or_else(|| {
+                    RawScriptError::Eval(EvalError::UtxoIndexOutOfBounds(
+                        idx as usize,
+                        env.spent_utxos().len(),
+                    ))
+                })?;
+                match op {
+                    OP_INSPECTINPUTOUTPOINT => {
+                        let mut flag = 0u8;
+                        if txin.is_pegin {
+                            flag |= 0x40;
+                        }
+                        if txin.has_issuance() {
+                            flag |= 0x80;
+                        }
+                        let txid = txin.previous_output.txid.to_byte_array().to_vec();
+                        self.push_data(txid);
+                        self.push_data(txin.previous_output.vout.to_le_bytes().to_vec());
+                        self.push_data(vec![flag]);
+                    }
+                    OP_INSPECTINPUTASSET => {
+                        let (comm, pref) = asset_to_components(&utxo.asset).ok_or(
+                            RawScriptError::Eval(EvalError::NonExplicitInput(idx as usize)),
+                        )?;
+                        self.push_data(comm);
+                        self.push_data(vec![pref]);
+                    }
+                    OP_INSPECTINPUTVALUE => {
+                        let (comm, pref) = value_to_components(&utxo.value);
+                        self.push_data(comm);
+                        self.push_data(vec![pref]);
+                    }
+                    OP_INSPECTINPUTSCRIPTPUBKEY => {
+                        let (ver, prog) = spk_to_components(&utxo.script_pubkey);
+                        self.push_data(prog);
+                        self.push_data(num_bytes(ver as i64));
+                    }
+                    OP_INSPECTINPUTSEQUENCE => {
+                        let seq = txin.sequence.to_consensus_u32();
+                        self.push_data(seq.to_le_bytes().to_vec());
+                    }
+                    _ => {
+                        if !txin.has_issuance() {
+                            self.push_data(vec![]);
+                        } else {
+                            // [<inflation keys> <inflation_pref> <value> <value_pref> <entropy> <blindingnonce>]
+                            let issuance = &txin.asset_issuance;
+                            let (keys, keys_pref) = value_to_components(&issuance.inflation_keys);
+                            let (amt, amt_pref) = value_to_components(&issuance.amount);
+                            self.push_data(keys);
+                            self.push_data(vec![keys_pref]);
+                            self.push_data(amt);
+                            self.push_data(vec![amt_pref]);
+                            self.push_data(issuance.asset_entropy.to_vec());
+                            self.push_data(issuance.asset_blinding_nonce.as_ref().to_vec());
+                        }
+                    }
+                }
+            }
+            OP_PUSHCURRENTINPUTINDEX => self.push_data(num_bytes(env.idx() as i64)),
+            OP_INSPECTOUTPUTASSET
+            | OP_INSPECTOUTPUTVALUE
+            | OP_INSPECTOUTPUTNONCE
+            | OP_INSPECTOUTPUTSCRIPTPUBKEY => {
+                let idx = self.pop_num()?;
+                let n_outputs = tx.output.len();
+                if idx < 0 || idx as usize >= n_outputs {
+                    return Err(RawScriptError::Eval(EvalError::OutputIndexOutOfBounds(
+                        idx as usize,
+                        n_outputs,
+                    )));
+                }
+                let txout = &tx.output[idx as usize];
+                match op {
+                    OP_INSPECTOUTPUTASSET => {
+                        let (comm, pref) = asset_to_components(&txout.asset).ok_or(
+                            RawScriptError::Eval(EvalError::NonExplicitOutput(idx as usize)),
+                        )?;
+                        self.push_data(comm);
+                        self.push_data(vec![pref]);
+                    }
+                    OP_INSPECTOUTPUTVALUE => {
+                        let (comm, pref) = value_to_components(&txout.value);
+                        self.push_data(comm);
+                        self.push_data(vec![pref]);
+                    }
+                    OP_INSPECTOUTPUTNONCE => {
+                        if txout.nonce.is_null() {
+                            self.push_data(vec![]);
+                        } else {
+                            self.push_data(encode::serialize(&txout.nonce));
+                        }
+                    }
+                    _ => {
+                        let (ver, prog) = spk_to_components(&txout.script_pubkey);
+                        self.push_data(prog);
+                        self.push_data(num_bytes(ver as i64));
+                    }
+                }
+            }
+            OP_INSPECTVERSION => self.push_data(tx.version.to_le_bytes().to_vec()),
+            OP_INSPECTLOCKTIME => {
+                let lock_time = tx.lock_time.to_consensus_u32();
+                self.push_data(lock_time.to_le_bytes().to_vec());
+            }
+            OP_INSPECTNUMINPUTS => self.push_data(num_bytes(tx.input.len() as i64)),
+            OP_INSPECTNUMOUTPUTS => self.push_data(num_bytes(tx.output.len() as i64)),
+            OP_TXWEIGHT => self.push_data((tx.weight() as i64).to_le_bytes().to_vec()),
+            // 64-bit arithmetic pushes the result and 1, or only 0 on overflow
+            OP_ADD64 | OP_SUB64 | OP_MUL64 => {
+                let b = self.pop_le64()?;
+                let a = self.pop_le64()?;
+                let res = match op {
+                    OP_ADD64 => a.checked_add(b),
+                    OP_SUB64 => a.checked_sub(b),
+                    _ => a.checked_mul(b),
+                };
+                self.push_le64_result(res.map(|res| vec![res]));
+            }
+            OP_DIV64 => {
+                let b = self.pop_le64()?;
+                let a = self.pop_le64()?;
+                // Pushes the remainder and the quotient
+                let res = a.checked_div_euclid(b).map(|q| vec![a.rem_euclid(b), q]);
+                self.push_le64_result(res);
+            }
+            OP_NEG64 => {
+                let a = self.pop_le64()?;
+                self.push_le64_result(a.checked_neg().map(|res| vec![res]));
+            }
+            OP_LESSTHAN64 | OP_LESSTHANOREQUAL64 | OP_GREATERTHAN64 | OP_GREATERTHANOREQUAL64 => {
+                let b = self.pop_le64()?;
+                let a = self.pop_le64()?;
+                let res = match op {
+                    OP_LESSTHAN64 => a < b,
+                    OP_LESSTHANOREQUAL64 => a <= b,
+                    OP_GREATERTHAN64 => a > b,
+                    _ => a >= b,
+                };
+                self.push_data(bool_bytes(res));
+            }
+            OP_SCRIPTNUMTOLE64 => {
+                let a = read_num(&self.pop()?, 8)?;
+                self.push_data(a.to_le_bytes().to_vec());
+            }
+            OP_LE64TOSCRIPTNUM => {
+                let a = self.pop_le64()?;
+                self.push_data(num_bytes(a));
+            }
+            OP_LE32TOLE64 => {
+                let a = self.pop()?;
+                let mut bytes = [0u8; 4];
+                if a.len() != 4 {
+                    return Err(RawScriptError::InvalidOperand);
+                }
+                bytes.copy_from_slice(&a);
+                let a = u32::from_le_bytes(bytes) as i64;
+                self.push_data(a.to_le_bytes().to_vec());
+            }
+            _ => return Err(RawScriptError::UnsupportedOpcode),
+        }
+        Ok(())
+    }
+
+    fn push_le64_result(&mut self, res: Option<Vec<i64>>) {
+        match res {
+            Some(res) => {
+                for x in res {
+                    self.push_data(x.to_le_bytes().to_vec());
+                }
+                self.push_data(vec![1]);
+            }
+            None => self.push_data(vec![]),
+        }
+    }
+
+    // The scriptCode signed by `sigs`. Legacy signatures cannot sign
+    // themselves, so they are removed from it as by FindAndDelete.
+    fn script_code(&self, sigs: &[&[u8]]) -> Script {
+        let mut script_code = self.script.as_bytes()[self.codesep_pos..].to_vec();
+        if self.sig_version == SigVersion::Base {
+            for sig in sigs {
+                script_code = find_and_delete(&script_code, sig);
+            }
+        }
+        Script::from(script_code)
+    }
+
+    fn check_sig(&mut self, sig: &[u8], pk: &[u8]) -> VmResult<bool> {
+        if self.is_tapscript() {
+            return self.check_schnorr_sig(sig, pk);
+        }
+        self.check_ecdsa_sig(sig, pk, &self.script_code(&[sig]))
+    }
+
+    fn check_ecdsa_sig(&self, sig: &[u8], pk: &[u8], script_code: &Script) -> VmResult<bool> {
+        let pk = bitcoin::PublicKey::from_slice(pk).map_err(|_| RawScriptError::PubkeyEncoding)?;
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        let (sig, hash_ty) =
+            elementssig_from_rawsig(sig).map_err(|_| RawScriptError::SigEncoding)?;
+        let msg = self.ecdsa_sighash(hash_ty, script_code)?;
+        Ok(self.ctx.secp.verify_ecdsa(&msg, &sig, &pk.inner).is_ok())
+    }
+
+    fn ecdsa_sighash(
+        &self,
+        hash_ty: EcdsaSighashType,
+        script_code: &Script,
+    ) -> VmResult<secp256k1_zkp::Message> {
+        let env = self.ctx.txenv;
+        let mut cache = sighash::SighashCache::new(env.tx());
+        let sighash = if self.sig_version == SigVersion::Base {
+            cache.legacy_sighash(env.idx(), script_code, hash_ty)
+        } else {
+            let value = env.spent_utxos()[env.idx()].value;
+            cache.segwitv0_sighash(env.idx(), script_code, value, hash_ty)
+        };
+        Ok(secp256k1_zkp::Message::from_slice(sighash.as_ref()).expect("32 byte"))
+    }
+
+    // Each non-empty signature checked in Tapscript consumes validation weight
+    fn consume_validation_weight(&mut self) -> VmResult<()> {
+        self.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+        if self.validation_weight_left < 0 {
+            Err(RawScriptError::ValidationWeight)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn check_schnorr_sig(&mut self, sig: &[u8], pk: &[u8]) -> VmResult<bool> {
+        if !sig.is_empty() {
+            self.consume_validation_weight()?;
+        }
+        if pk.is_empty() {
+            return Err(RawScriptError::PubkeyEncoding);
+        }
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        if pk.len() != 32 {
+            // Unknown public key types are treated as valid signatures
+            return Ok(true);
+        }
+        let pk = secp256k1_zkp::XOnlyPublicKey::from_slice(pk)
+            .map_err(|_| RawScriptError::PubkeyEncoding)?;
+        let sig = elements::SchnorrSig::from_slice(sig).map_err(|_| RawScriptError::SigEncoding)?;
+        let env = self.ctx.txenv;
+        let leaf_hash = sighash::ScriptPath::with_defaults(self.script).leaf_hash();
+        let msg = sighash::SighashCache::new(env.tx())
+            .taproot_script_spend_signature_hash(
+                env.idx(),
+                &sighash::Prevouts::All(env.spent_utxos()),
+                leaf_hash,
+                sig.hash_ty,
+                self.ctx.genesis_hash,
+            )
+            .map_err(|e| RawScriptError::Interpreter(Error::SighashError(e)))?;
+        let msg = secp256k1_zkp::Message::from_slice(msg.as_ref()).expect("32 byte");
+        // Failing non-empty signatures abort the script in Tapscript
+        self.ctx
+            .secp
+            .verify_schnorr(&sig.sig, &msg, &pk)
+            .map(|_| true)
+            .map_err(|_| RawScriptError::SigVerification)
+    }
+
+    // Legacy CHECKSIGFROMSTACK verifies an ECDSA signature of the SHA256 of
+    // the message, while the Tapscript variant verifies a BIP340 signature
+    // of the message itself.
+    fn check_sig_from_stack(&mut self, sig: &[u8], msg: &[u8], pk: &[u8]) -> VmResult<bool> {
+        if sig.is_empty() {
+            return Ok(false);
+        }
+        if self.is_tapscript() {
+            self.consume_validation_weight()?;
+            if pk.len() != 32 {
+                return Ok(true);
+            }
+            let pk = secp256k1_zkp::XOnlyPublicKey::from_slice(pk)
+                .map_err(|_| RawScriptError::PubkeyEncoding)?;
+            let sig = secp256k1_zkp::schnorr::Signature::from_slice(sig)
+                .map_err(|_| RawScriptError::SigEncoding)?;
+            // Only 32 byte messages can be verified with the secp bindings
+            let msg = secp256k1_zkp::Message::from_slice(msg)
+                .map_err(|_| RawScriptError::UnsupportedOpcode)?;
+            self.ctx
+                .secp
+                .verify_schnorr(&sig, &msg, &pk)
+                .map(|_| true)
+                .map_err(|_| RawScriptError::SigVerification)
+        } else {
+            let pk =
+                bitcoin::PublicKey::from_slice(pk).map_err(|_| RawScriptError::PubkeyEncoding)?;
+            let sig = secp256k1_zkp::ecdsa::Signature::from_der(sig)
+                .map_err(|_| RawScriptError::SigEncoding)?;
+            let msg =
+                secp256k1_zkp::Message::from_slice(&sha256::Hash::hash(msg)[..]).expect("32 byte");
+            Ok(self.ctx.secp.verify_ecdsa(&msg, &sig, &pk.inner).is_ok())
+        }
+    }
+
+    fn check_multisig(&mut self) -> VmResult<bool> {
+        let n = self.pop_num()?;
+        if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&n) {
+            return Err(RawScriptError::InvalidOperand);
+        }
+        self.n_ops += n as usize;
+        if self.n_ops > MAX_OPS_PER_SCRIPT {
+            return Err(RawScriptError::OpCount);
+        }
+        let mut pks = (0..n).map(|_| self.pop()).collect::<VmResult<Vec<_>>>()?;
+        let k = self.pop_num()?;
+        if k < 0 || k > n {
+            return Err(RawScriptError::InvalidOperand);
+        }
+        let mut sigs = (0..k).map(|_| self.pop()).collect::<VmResult<Vec<_>>>()?;
+        // The extra element consumed by CHECKMULTISIG
+        self.pop()?;
+
+        // Keys and signatures were popped top first, match them in order
+        pks.reverse();
+        sigs.reverse();
+        let sig_slices: Vec<&[u8]> = sigs.iter().map(|sig| &sig[..]).collect();
+        let script_code = self.script_code(&sig_slices);
+        let mut pks = pks.iter();
+        for sig in &sigs {
+            loop {
+                let pk = match pks.next() {
+                    Some(pk) => pk,
+                    None => return Ok(false),
+                };
+                if self.check_ecdsa_sig(sig, pk, &script_code)? {
+                    break;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn check_lock_time(&self) -> VmResult<()> {
+        let n = read_num(self.peek(0)?, 5)?;
+        if n < 0 {
+            return Err(RawScriptError::InvalidOperand);
+        }
+        let env = self.ctx.txenv;
+        let lock_time = env.tx().lock_time.to_consensus_u32() as i64;
+        let same_unit = (lock_time < LOCKTIME_THRESHOLD) == (n < LOCKTIME_THRESHOLD);
+        let final_input = env.tx().input[env.idx()].sequence.is_final();
+        if same_unit && n <= lock_time && !final_input {
+            Ok(())
+        } else {
+            Err(RawScriptError::UnsatisfiedLocktime)
+        }
+    }
+
+    fn check_sequence(&self) -> VmResult<()> {
+        const DISABLE_FLAG: i64 = 1 << 31;
+        const TYPE_FLAG: i64 = 1 << 22;
+        const MASK: i64 = TYPE_FLAG | 0xffff;
+
+        let n = read_num(self.peek(0)?, 5)?;
+        if n < 0 {
+            return Err(RawScriptError::InvalidOperand);
+        }
+        if n & DISABLE_FLAG != 0 {
+            return Ok(());
+        }
+        let env = self.ctx.txenv;
+        let seq = env.tx().input[env.idx()].sequence.to_consensus_u32() as i64;
+        let ok = env.tx().version >= 2
+            && seq & DISABLE_FLAG == 0
+            && (seq & TYPE_FLAG) == (n & TYPE_FLAG)
+            && (n & MASK) <= (seq & MASK);
+        if ok {
+            Ok(())
+        } else {
+            Err(RawScriptError::UnsatisfiedLocktime)
+        }
+    }
+}
+
+// Removes the pushes of `sig` from `script`, as done to the scriptCode of
+// legacy signature checks
+fn find_and_delete(script: &[u8], sig: &[u8]) -> Vec<u8> {
+    let pattern = Builder::new().push_slice(sig).into_script().into_bytes();
+    let mut res = Vec::with_capacity(script.len());
+    let mut pos = 0;
+    while pos < script.len() {
+        // Only matches at instruction boundaries are removed
+        while script[pos..].starts_with(&pattern) {
+            pos += pattern.len();
+        }
+        let next = match decode(script, pos) {
+            Some(Ok((next, _))) => next,
+            _ => script.len(),
+        };
+        res.extend_from_slice(&script[pos..next]);
+        pos = next;
+    }
+    res
+}
+
+// Shifts a little-endian byte string by `n` bits, keeping its length
+fn shift(a: &[u8], n: usize, left: bool) -> Vec<u8> {
+    let len = a.len();
+    let mut res = vec![0u8; len];
+    let (byte_shift, bit_shift) = (n / 8, n % 8);
+    for i in 0..len {
+        let (src, carry) = if left {
+            (i.checked_sub(byte_shift), i.checked_sub(byte_shift + 1))
+        } else {
+            (i.checked_add(byte_shift), i.checked_add(byte_shift + 1))
+        };
+        let get = |j: Option<usize>| j.and_then(|j| a.get(j)).copied().unwrap_or(0) as u16;
+        res[i] = if bit_shift == 0 {
+            get(src) as u8
+        } else if left {
+            ((get(src) << bit_shift) | (get(carry) >> (8 - bit_shift))) as u8
+        } else {
+            ((get(src) >> bit_shift) | (get(carry) << (8 - bit_shift))) as u8
+        };
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use elements::secp256k1_zkp::{KeyPair, Message, Scalar, Secp256k1};
+    use elements::taproot::{TapLeafHash, TapTweakHash, TaprootBuilder, TaprootSpendInfo};
+    use elements::{
+        confidential, AssetId, LockTime, OutPoint, SchnorrSighashType, Sequence, Transaction, TxIn,
+        TxOut,
+    };
+
+    use super::*;
+
+    fn spend_tx(spk: &Script, witness: Vec<Vec<u8>>) -> (Transaction, Vec<TxOut>) {
+        let asset = AssetId::from_slice(&[3; 32]).unwrap();
+        let utxos = vec![TxOut {
+            value: confidential::Value::Explicit(1_000),
+            script_pubkey: spk.clone(),
+            asset: confidential::Asset::Explicit(asset),
+            nonce: confidential::Nonce::Null,
+            witness: elements::TxOutWitness::default(),
+        }];
+        let mut txin = TxIn {
+            previous_output: OutPoint::default(),
+            is_pegin: false,
+            script_sig: Script::new(),
+            sequence: Sequence::MAX,
+            asset_issuance: Default::default(),
+            witness: Default::default(),
+        };
+        txin.witness.script_witness = witness;
+        let tx = Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![txin],
+            output: vec![utxos[0].clone()],
+        };
+        (tx, utxos)
+    }
+
+    fn eval_tx(tx: &Transaction, utxos: &[TxOut]) -> Result<(), RawScriptFailure> {
+        let env = TxEnv::new(tx, utxos, 0).unwrap();
+        let secp = Secp256k1::verification_only();
+        eval_raw_input(&secp, &env, elements::BlockHash::all_zeros())
+    }
+
+    fn eval_wsh(script: &Script, stack: Vec<Vec<u8>>) -> Result<(), RawScriptFailure> {
+        let spk = Script::new_v0_wsh(&sha256::Hash::hash(script.as_bytes()).into());
+        let mut witness = stack;
+        witness.push(script.to_bytes());
+        let (tx, utxos) = spend_tx(&spk, witness);
+        eval_tx(&tx, &utxos)
+    }
+
+    fn key_pair(i: u8) -> KeyPair {
+        KeyPair::from_seckey_slice(&Secp256k1::signing_only(), &[i; 32]).unwrap()
+    }
+
+    // Taproot output whose only leaf is `script`
+    fn tap_spend_info(script: &Script) -> TaprootSpendInfo {
+        let internal_key = key_pair(1).x_only_public_key().0;
+        TaprootBuilder::new()
+            .add_leaf(0, script.clone())
+            .unwrap()
+            .finalize(&Secp256k1::verification_only(), internal_key)
+            .unwrap()
+    }
+
+    fn tap_spk(spend_info: &TaprootSpendInfo) -> Script {
+        Script::new_v1_p2tr_tweaked(spend_info.output_key())
+    }
+
+    fn eval_tap(script: &Script, stack: Vec<Vec<u8>>) -> Result<(), RawScriptFailure> {
+        let spend_info = tap_spend_info(script);
+        let ctrl_blk = spend_info
+            .control_block(&(script.clone(), LeafVersion::default()))
+            .unwrap();
+        let mut witness = stack;
+        witness.push(script.to_bytes());
+        witness.push(ctrl_blk.serialize());
+        let (tx, utxos) = spend_tx(&tap_spk(&spend_info), witness);
+        eval_tx(&tx, &utxos)
+    }
+
+    fn schnorr_sig(msg: &[u8], keypair: &KeyPair) -> Vec<u8> {
+        let msg = Message::from_slice(msg).unwrap();
+        let sig = Secp256k1::new().sign_schnorr_with_aux_rand(&msg, keypair, &[0; 32]);
+        elements::SchnorrSig {
+            sig,
+            hash_ty: SchnorrSighashType::Default,
+        }
+        .to_vec()
+    }
+
+    // Signature of `keypair` for spending the leaf `script` with `eval_tap`
+    fn tap_script_sig(script: &Script, keypair: &KeyPair) -> Vec<u8> {
+        let (tx, utxos) = spend_tx(&tap_spk(&tap_spend_info(script)), vec![]);
+        let leaf_hash = TapLeafHash::from_script(script, LeafVersion::default());
+        let sighash = sighash::SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &sighash::Prevouts::All(&utxos[..]),
+                leaf_hash,
+                SchnorrSighashType::Default,
+                elements::BlockHash::all_zeros(),
+            )
+            .unwrap();
+        schnorr_sig(sighash.as_ref(), keypair)
+    }
+
+    fn le64(n: i64) -> [u8; 8] {
+        n.to_le_bytes()
+    }
+
+    #[test]
+    fn raw_script_num() {
+        for n in [
+            0,
+            1,
+            -1,
+            127,
+            128,
+            -128,
+            255,
+            256,
+            0x7fff_ffff,
+            -0x7fff_ffff,
+        ] {
+            assert_eq!(read_num(&num_bytes(n), 5).unwrap(), n);
+        }
+        assert_eq!(num_bytes(-1), vec![0x81]);
+        assert_eq!(num_bytes(128), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn raw_script_wsh() {
+        // <a> <b> CAT SHA256 <h> EQUAL, not a Miniscript
+        let preimage = b"hello world".to_vec();
+        let hash = sha256::Hash::hash(&preimage);
+        let script = Builder::new()
+            .push_opcode(OP_CAT)
+            .push_opcode(OP_SHA256)
+            .push_slice(&hash.to_byte_array())
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        let stack = vec![b"hello ".to_vec(), b"world".to_vec()];
+        assert!(eval_wsh(&script, stack).is_ok());
+
+        let stack = vec![b"hello".to_vec(), b"world".to_vec()];
+        let err = eval_wsh(&script, stack).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::EvalFalse));
+
+        // The failing opcode is reported along with its position
+        let script = Builder::new()
+            .push_opcode(OP_PUSHNUM_1)
+            .push_opcode(OP_PUSHNUM_2)
+            .push_opcode(OP_NUMEQUALVERIFY)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        let err = eval_wsh(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::VerifyFailed));
+        assert_eq!(err.pos, Some(2));
+        assert_eq!(err.opcode, Some(OP_NUMEQUALVERIFY));
+
+        // Disabled opcodes fail even if not executed
+        let script = Builder::new()
+            .push_opcode(OP_PUSHBYTES_0)
+            .push_opcode(OP_IF)
+            .push_opcode(OP_MUL)
+            .push_opcode(OP_ENDIF)
+            .push_opcode(OP_PUSHNUM_1)
+            .into_script();
+        let err = eval_wsh(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::DisabledOpcode));
+    }
+
+    #[test]
+    fn raw_script_p2sh() {
+        // <a> <b> ADD <5> EQUAL
+        let redeem = Builder::new()
+            .push_opcode(OP_ADD)
+            .push_int(5)
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        let spk = Script::new_p2sh(&hash160::Hash::hash(redeem.as_bytes()).into());
+        let script_sig = |a, b| {
+            Builder::new()
+                .push_int(a)
+                .push_int(b)
+                .push_slice(redeem.as_bytes())
+                .into_script()
+        };
+        let (mut tx, utxos) = spend_tx(&spk, vec![]);
+        tx.input[0].script_sig = script_sig(2, 3);
+        assert!(eval_tx(&tx, &utxos).is_ok());
+
+        tx.input[0].script_sig = script_sig(2, 2);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::EvalFalse));
+
+        // The redeem script must hash to the committed script hash
+        let other = Script::new_p2sh(&hash160::Hash::hash(b"other").into());
+        let (mut tx, utxos) = spend_tx(&other, vec![]);
+        tx.input[0].script_sig = script_sig(2, 3);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::WitnessProgramMismatch));
+
+        // The scriptSig must be push only
+        let (mut tx, utxos) = spend_tx(&spk, vec![]);
+        tx.input[0].script_sig = Builder::new()
+            .push_int(1)
+            .push_int(1)
+            .push_opcode(OP_ADD)
+            .push_int(3)
+            .push_slice(redeem.as_bytes())
+            .into_script();
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::SigPushOnly));
+    }
+
+    #[test]
+    fn raw_script_find_and_delete() {
+        // The redeem script embeds the signature checked by its CHECKSIG,
+        // which only verifies if the signature is removed from the scriptCode
+        let secp = Secp256k1::new();
+        let sk = secp256k1_zkp::SecretKey::from_slice(&[2; 32]).unwrap();
+        let pk = bitcoin::PublicKey::new(secp256k1_zkp::PublicKey::from_secret_key(&secp, &sk));
+        let script_code = Builder::new()
+            .push_opcode(OP_DROP)
+            .push_key(&pk)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+
+        // The spent scriptPubKey depends on the signature, so it is only set
+        // after signing, and the output of the transaction does not reuse it
+        let (mut tx, mut utxos) = spend_tx(&Script::new(), vec![]);
+        let sighash =
+            sighash::SighashCache::new(&tx).legacy_sighash(0, &script_code, EcdsaSighashType::All);
+        let msg = Message::from_slice(sighash.as_ref()).unwrap();
+        let mut sig = secp.sign_ecdsa(&msg, &sk).serialize_der().to_vec();
+        sig.push(0x01); // sighash_all
+
+        let redeem = Builder::new()
+            .push_slice(&sig)
+            .push_opcode(OP_DROP)
+            .push_key(&pk)
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        assert_eq!(
+            find_and_delete(redeem.as_bytes(), &sig),
+            script_code.to_bytes()
+        );
+        utxos[0].script_pubkey = Script::new_p2sh(&hash160::Hash::hash(redeem.as_bytes()).into());
+        tx.input[0].script_sig = Builder::new()
+            .push_slice(&sig)
+            .push_slice(redeem.as_bytes())
+            .into_script();
+        assert!(eval_tx(&tx, &utxos).is_ok());
+
+        // Pushes are only removed at instruction boundaries
+        let data = Builder::new().push_slice(&[0x01, 0xab]).into_script();
+        assert_eq!(find_and_delete(data.as_bytes(), &[0xab]), data.to_bytes());
+    }
+
+    #[test]
+    fn raw_script_timelocks() {
+        let cltv = Builder::new()
+            .push_int(100)
+            .push_opcode(OP_CLTV)
+            .into_script();
+        let spk = Script::new_v0_wsh(&sha256::Hash::hash(cltv.as_bytes()).into());
+        let (mut tx, utxos) = spend_tx(&spk, vec![cltv.to_bytes()]);
+        tx.lock_time = LockTime::from_consensus(100);
+        tx.input[0].sequence = Sequence::from_consensus(0xffff_fffe);
+        assert!(eval_tx(&tx, &utxos).is_ok());
+
+        // The locktime is not enforced for final inputs
+        tx.input[0].sequence = Sequence::MAX;
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::UnsatisfiedLocktime));
+
+        tx.input[0].sequence = Sequence::from_consensus(0xffff_fffe);
+        tx.lock_time = LockTime::from_consensus(99);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::UnsatisfiedLocktime));
+
+        // Heights do not satisfy timestamps
+        tx.lock_time = LockTime::from_consensus(500_000_100);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::UnsatisfiedLocktime));
+
+        let csv = Builder::new()
+            .push_int(10)
+            .push_opcode(OP_CSV)
+            .into_script();
+        let spk = Script::new_v0_wsh(&sha256::Hash::hash(csv.as_bytes()).into());
+        let (mut tx, utxos) = spend_tx(&spk, vec![csv.to_bytes()]);
+        tx.input[0].sequence = Sequence::from_consensus(10);
+        assert!(eval_tx(&tx, &utxos).is_ok());
+
+        tx.input[0].sequence = Sequence::from_consensus(9);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::UnsatisfiedLocktime));
+
+        // Relative locktimes require version 2 transactions
+        tx.input[0].sequence = Sequence::from_consensus(10);
+        tx.version = 1;
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::UnsatisfiedLocktime));
+    }
+
+    #[test]
+    fn raw_script_introspection() {
+        // The current input holds 1000 units and the only output has the asset [3; 32]
+        let script = Builder::new()
+            .push_opcode(OP_PUSHCURRENTINPUTINDEX)
+            .push_opcode(OP_INSPECTINPUTVALUE)
+            .push_int(1)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&le64(1_000))
+            .push_opcode(OP_EQUALVERIFY)
+            .push_int(0)
+            .push_opcode(OP_INSPECTOUTPUTASSET)
+            .push_int(1)
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&[3; 32])
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_INSPECTNUMOUTPUTS)
+            .push_int(1)
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        let script = Builder::new()
+            .push_int(1)
+            .push_opcode(OP_INSPECTINPUTVALUE)
+            .into_script();
+        let err = eval_tap(&script, vec![]).unwrap_err();
+        assert!(matches!(
+            err.error,
+            RawScriptError::Eval(EvalError::InputIndexOutOfBounds(1, 1))
+        ));
+
+        // Introspection is only available in Tapscript
+        let script = Builder::new()
+            .push_opcode(OP_INSPECTNUMOUTPUTS)
+            .into_script();
+        let err = eval_wsh(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::BadOpcode));
+    }
+
+    #[test]
+    fn raw_script_arith64() {
+        // 2 + 3 == 5
+        let script = Builder::new()
+            .push_slice(&le64(2))
+            .push_slice(&le64(3))
+            .push_opcode(OP_ADD64)
+            .push_opcode(OP_VERIFY)
+            .push_slice(&le64(5))
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        // Overflows push 0 instead of the result
+        let script = Builder::new()
+            .push_slice(&le64(i64::MAX))
+            .push_slice(&le64(1))
+            .push_opcode(OP_ADD64)
+            .push_opcode(OP_NOT)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        // -7 = 2 * -4 + 1 pushes the remainder and the quotient
+        let script = Builder::new()
+            .push_slice(&le64(-7))
+            .push_slice(&le64(2))
+            .push_opcode(OP_DIV64)
+            .push_opcode(OP_VERIFY)
+            .push_slice(&le64(-4))
+            .push_opcode(OP_EQUALVERIFY)
+            .push_slice(&le64(1))
+            .push_opcode(OP_EQUAL)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        // Conversions from and to script numbers
+        let script = Builder::new()
+            .push_int(-5)
+            .push_opcode(OP_SCRIPTNUMTOLE64)
+            .push_opcode(OP_DUP)
+            .push_slice(&le64(-5))
+            .push_opcode(OP_EQUALVERIFY)
+            .push_opcode(OP_LE64TOSCRIPTNUM)
+            .push_int(-5)
+            .push_opcode(OP_NUMEQUAL)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        // Operands must be exactly 8 bytes
+        let script = Builder::new()
+            .push_int(1)
+            .push_int(1)
+            .push_opcode(OP_ADD64)
+            .into_script();
+        let err = eval_tap(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::InvalidOperand));
+    }
+
+    #[test]
+    fn raw_script_csfs() {
+        let secp = Secp256k1::new();
+        let keypair = key_pair(2);
+        let msg = sha256::Hash::hash(b"message").to_byte_array();
+
+        // Tapscript verifies a BIP340 signature of the message
+        let script = Builder::new()
+            .push_slice(&keypair.x_only_public_key().0.serialize())
+            .push_opcode(OP_CHECKSIGFROMSTACK)
+            .into_script();
+        let sig = secp.sign_schnorr_with_aux_rand(
+            &Message::from_slice(&msg).unwrap(),
+            &keypair,
+            &[0; 32],
+        );
+        let stack = vec![sig.as_ref().to_vec(), msg.to_vec()];
+        assert!(eval_tap(&script, stack).is_ok());
+
+        let stack = vec![sig.as_ref().to_vec(), vec![0; 32]];
+        let err = eval_tap(&script, stack).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::SigVerification));
+
+        // Legacy scripts verify an ECDSA signature of the SHA256 of the message
+        let pk = keypair.public_key();
+        let script = Builder::new()
+            .push_slice(&pk.serialize())
+            .push_opcode(OP_CHECKSIGFROMSTACK)
+            .into_script();
+        let digest = Message::from_slice(&sha256::Hash::hash(&msg)[..]).unwrap();
+        let sig = secp
+            .sign_ecdsa(&digest, &keypair.secret_key())
+            .serialize_der()
+            .to_vec();
+        assert!(eval_wsh(&script, vec![sig.clone(), msg.to_vec()]).is_ok());
+
+        let err = eval_wsh(&script, vec![sig, vec![0; 32]]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::EvalFalse));
+    }
+
+    #[test]
+    fn raw_script_op_success() {
+        // OP_SUCCESS makes a Tapscript succeed, even if it is never executed
+        let script = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_RESERVED)
+            .into_script();
+        assert!(eval_tap(&script, vec![]).is_ok());
+
+        // Elements re-enabled OP_CAT, which is not an OP_SUCCESS in Tapscript
+        let script = Builder::new()
+            .push_opcode(OP_RETURN)
+            .push_opcode(OP_CAT)
+            .into_script();
+        let err = eval_tap(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::OpReturn));
+
+        // OP_RESERVED outside of Tapscript fails when executed
+        let script = Builder::new().push_opcode(OP_RESERVED).into_script();
+        let err = eval_wsh(&script, vec![]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::BadOpcode));
+    }
+
+    #[test]
+    fn raw_script_taproot_key_spend() {
+        let secp = Secp256k1::new();
+        let leaf = Builder::new().push_opcode(OP_PUSHNUM_1).into_script();
+        let spend_info = tap_spend_info(&leaf);
+        let (mut tx, utxos) = spend_tx(&tap_spk(&spend_info), vec![]);
+
+        let tweak =
+            TapTweakHash::from_key_and_tweak(spend_info.internal_key(), spend_info.merkle_root());
+        let tweak = Scalar::from_be_bytes(tweak.to_byte_array()).unwrap();
+        let keypair = key_pair(1).add_xonly_tweak(&secp, &tweak).unwrap();
+        let sighash = sighash::SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(
+                0,
+                &sighash::Prevouts::All(&utxos[..]),
+                SchnorrSighashType::Default,
+                elements::BlockHash::all_zeros(),
+            )
+            .unwrap();
+        let sig = schnorr_sig(sighash.as_ref(), &keypair);
+
+        tx.input[0].witness.script_witness = vec![sig];
+        assert!(eval_tx(&tx, &utxos).is_ok());
+
+        // Signature of the untweaked internal key
+        let sig = schnorr_sig(sighash.as_ref(), &key_pair(1));
+        tx.input[0].witness.script_witness = vec![sig];
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::SigVerification));
+    }
+
+    #[test]
+    fn raw_script_taproot_script_spend() {
+        let keypair = key_pair(2);
+        let script = Builder::new()
+            .push_slice(&keypair.x_only_public_key().0.serialize())
+            .push_opcode(OP_CHECKSIG)
+            .into_script();
+        let sig = tap_script_sig(&script, &keypair);
+        assert!(eval_tap(&script, vec![sig]).is_ok());
+
+        let err = eval_tap(&script, vec![tap_script_sig(&script, &key_pair(3))]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::SigVerification));
+
+        // Empty signatures fail without aborting the script
+        let err = eval_tap(&script, vec![vec![]]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::EvalFalse));
+
+        // The control block must commit to the script
+        let spend_info = tap_spend_info(&script);
+        let other = Builder::new().push_opcode(OP_PUSHNUM_1).into_script();
+        let ctrl_blk = tap_spend_info(&other)
+            .control_block(&(other.clone(), LeafVersion::default()))
+            .unwrap();
+        let witness = vec![other.to_bytes(), ctrl_blk.serialize()];
+        let (tx, utxos) = spend_tx(&tap_spk(&spend_info), witness);
+        let err = eval_tx(&tx, &utxos).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::WitnessProgramMismatch));
+    }
+
+    #[test]
+    fn raw_script_validation_weight() {
+        // <pk> (2DUP CHECKSIGVERIFY)^n CHECKSIG checks the same signature n + 1 times
+        let keypair = key_pair(2);
+        let script = |n| {
+            let mut builder = Builder::new().push_slice(&keypair.x_only_public_key().0.serialize());
+            for _ in 0..n {
+                builder = builder.push_opcode(OP_2DUP).push_opcode(OP_CHECKSIGVERIFY);
+            }
+            builder.push_opcode(OP_CHECKSIG).into_script()
+        };
+        // The budget of 50 plus the witness size covers the first checks
+        let script_1 = script(1);
+        let sig = tap_script_sig(&script_1, &keypair);
+        assert!(eval_tap(&script_1, vec![sig]).is_ok());
+
+        let script_10 = script(10);
+        let sig = tap_script_sig(&script_10, &keypair);
+        let err = eval_tap(&script_10, vec![sig]).unwrap_err();
+        assert!(matches!(err.error, RawScriptError::ValidationWeight));
+        assert_eq!(err.opcode, Some(OP_CHECKSIGVERIFY));
+    }
+
+    #[test]
+    fn raw_script_shift() {
+        assert_eq!(shift(&[0x01, 0x80], 1, true), vec![0x02, 0x00]);
+        assert_eq!(shift(&[0x01, 0x80], 1, false), vec![0x00, 0x40]);
+        assert_eq!(shift(&[0x01, 0x02], 8, true), vec![0x00, 0x01]);
+        assert_eq!(shift(&[0x01, 0x02], 8, false), vec![0x02, 0x00]);
+    }
+}
@@ -158,6 +158,7 @@ pub use crate::miniscript::decode::Terminal;
 pub use crate::miniscript::satisfy::{
     elementssig_from_rawsig, elementssig_to_rawsig, ElementsSig, Preimage32, Satisfier,
 };
+pub use crate::miniscript::signer::{SigningKeys, SigningSatisfier};
 pub use crate::miniscript::Miniscript;
 // minimal implementation of contract hash module
 mod contracthash {
@@ -30,6 +30,7 @@ pub mod iter;
 pub mod lex;
 pub mod limits;
 pub mod satisfy;
+pub mod signer;
 pub mod types;
 
 use std::cmp;
@@ -0,0 +1,617 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! # Signing Satisfier
+//!
+//! A [`Satisfier`] which holds private keys and signs on demand while a
+//! miniscript is being satisfied, instead of pre-computing signatures for
+//! every key and every tapleaf.
+//!
+//! The satisfaction of a miniscript looks up a signature for every key of
+//! every branch and every tapleaf, so that a [`SigningSatisfier`] used as a
+//! [`Satisfier`] signs for every key it knows. To only sign for the keys of
+//! the selected satisfaction, use [`SigningSatisfier::satisfy_descriptor`].
+//!
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::mem;
+
+use bitcoin::key::XOnlyPublicKey;
+use elements::hashes::Hash;
+use elements::secp256k1_zkp::{
+    self, ecdsa, schnorr, KeyPair, Message, Parity, Scalar, Secp256k1, SecretKey,
+};
+use elements::sighash::{Prevouts, SighashCache};
+use elements::taproot::{TapBranchHash, TapLeafHash, TapTweakHash};
+use elements::{self, BlockHash, EcdsaSighashType, SchnorrSighashType, Script};
+
+use crate::extensions::{CsfsMsg, ParseableExt, TxEnv};
+use crate::{Descriptor, ElementsSig, Error, Extension, MiniscriptKey, Satisfier, ToPublicKey};
+
+/// Source of the private keys used by the [`SigningSatisfier`].
+///
+/// Implement this trait to sign with keys held elsewhere, e.g. derived
+/// on demand from an extended private key.
+pub trait SigningKeys {
+    /// Returns the secret key for `pk`, if it is known
+    fn secret_key(&self, pk: &bitcoin::PublicKey) -> Option<SecretKey>;
+}
+
+impl SigningKeys for HashMap<bitcoin::PublicKey, SecretKey> {
+    fn secret_key(&self, pk: &bitcoin::PublicKey) -> Option<SecretKey> {
+        self.get(pk).copied()
+    }
+}
+
+impl SigningKeys for BTreeMap<bitcoin::PublicKey, SecretKey> {
+    fn secret_key(&self, pk: &bitcoin::PublicKey) -> Option<SecretKey> {
+        self.get(pk).copied()
+    }
+}
+
+impl<'a, K: SigningKeys> SigningKeys for &'a K {
+    fn secret_key(&self, pk: &bitcoin::PublicKey) -> Option<SecretKey> {
+        (**self).secret_key(pk)
+    }
+}
+
+// Information required to compute the sighash of the input being satisfied
+#[derive(Debug, Clone)]
+enum SighashCtx {
+    Legacy {
+        script_code: Script,
+        hash_ty: EcdsaSighashType,
+    },
+    Segwitv0 {
+        script_code: Script,
+        hash_ty: EcdsaSighashType,
+    },
+    Tap {
+        genesis_hash: BlockHash,
+        hash_ty: SchnorrSighashType,
+        internal_key: XOnlyPublicKey,
+        merkle_root: Option<TapBranchHash>,
+    },
+}
+
+// A signature looked up during a satisfaction
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SigRequest {
+    Ecdsa(bitcoin::PublicKey),
+    TapKeySpend,
+    TapLeafScript(XOnlyPublicKey, TapLeafHash),
+    Csfs(XOnlyPublicKey, CsfsMsg),
+}
+
+// A signature answering a `SigRequest`
+#[derive(Debug, Clone)]
+enum Sig {
+    Ecdsa(ElementsSig),
+    Schnorr(elements::SchnorrSig),
+    Csfs(schnorr::Signature),
+}
+
+// How signature lookups are answered
+#[derive(Debug)]
+enum Mode {
+    // Sign every request for a known key
+    Sign,
+    // Answer placeholder signatures, recording the requests in the order of
+    // their placeholders
+    Plan(Vec<SigRequest>),
+    // Only sign the requests used by the planned satisfaction
+    Planned(BTreeSet<SigRequest>),
+}
+
+// The marker of the `i`th placeholder signature, a valid scalar which is
+// found in the serialization of the placeholder
+fn placeholder_marker(i: usize) -> [u8; 32] {
+    let mut ret = [0x5a; 32];
+    ret[24..].copy_from_slice(&(i as u64).to_be_bytes());
+    ret
+}
+
+/// A satisfier that holds private keys and the transaction being signed,
+/// and produces ecdsa, schnorr and csfs signatures lazily when they are
+/// looked up during satisfaction.
+///
+/// The secret key is looked up before computing the sighash of a signature,
+/// and every signature is computed at most once. See
+/// [`SigningSatisfier::satisfy_descriptor`] to only sign for the keys used
+/// by the satisfaction.
+///
+/// The [`TxEnv`] is also used to answer the transaction introspection
+/// lookups required by covenant extensions.
+pub struct SigningSatisfier<'s, 'tx, 'ptx, C: secp256k1_zkp::Signing, K: SigningKeys> {
+    secp: &'s Secp256k1<C>,
+    keys: K,
+    env: TxEnv<'tx, 'ptx>,
+    ctx: SighashCtx,
+    mode: RefCell<Mode>,
+    sigs: RefCell<BTreeMap<SigRequest, Sig>>,
+}
+
+impl<'s, 'tx, 'ptx, C, K> SigningSatisfier<'s, 'tx, 'ptx, C, K>
+where
+    C: secp256k1_zkp::Signing + secp256k1_zkp::Verification,
+    K: SigningKeys,
+{
+    /// Create a new signing satisfier for a pre-segwit spend. The `script_code`
+    /// is the scriptPubkey for bare outputs, and the redeem script for p2sh.
+    pub fn new_legacy(
+        secp: &'s Secp256k1<C>,
+        keys: K,
+        env: TxEnv<'tx, 'ptx>,
+        script_code: Script,
+        hash_ty: EcdsaSighashType,
+    ) -> Self {
+        Self {
+            secp,
+            keys,
+            env,
+            ctx: SighashCtx::Legacy {
+                script_code,
+                hash_ty,
+            },
+            mode: RefCell::new(Mode::Sign),
+            sigs: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Create a new signing satisfier for a segwit v0 spend. The `script_code`
+    /// is the witness script, see [`crate::Descriptor::script_code`].
+    pub fn new_segwitv0(
+        secp: &'s Secp256k1<C>,
+        keys: K,
+        env: TxEnv<'tx, 'ptx>,
+        script_code: Script,
+        hash_ty: EcdsaSighashType,
+    ) -> Self {
+        Self {
+            secp,
+            keys,
+            env,
+            ctx: SighashCtx::Segwitv0 {
+                script_code,
+                hash_ty,
+            },
+            mode: RefCell::new(Mode::Sign),
+            sigs: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Create a new signing satisfier for a taproot spend. The `internal_key`
+    /// and `merkle_root` are used to tweak the internal key for key spends.
+    pub fn new_tap(
+        secp: &'s Secp256k1<C>,
+        keys: K,
+        env: TxEnv<'tx, 'ptx>,
+        genesis_hash: BlockHash,
+        hash_ty: SchnorrSighashType,
+        internal_key: XOnlyPublicKey,
+        merkle_root: Option<TapBranchHash>,
+    ) -> Self {
+        Self {
+            secp,
+            keys,
+            env,
+            ctx: SighashCtx::Tap {
+                genesis_hash,
+                hash_ty,
+                internal_key,
+                merkle_root,
+            },
+            mode: RefCell::new(Mode::Sign),
+            sigs: RefCell::new(BTreeMap::new()),
+        }
+    }
+
+    /// Number of signatures computed by the satisfier so far
+    pub fn num_signatures(&self) -> usize {
+        self.sigs.borrow().len()
+    }
+
+    /// Returns the satisfying witness and scriptSig of `desc`, only signing
+    /// for the keys used by the satisfaction.
+    ///
+    /// The descriptor is first satisfied with placeholder signatures for the
+    /// keys of the satisfier, which selects the satisfaction without
+    /// computing any sighash, and then satisfied again with signatures for
+    /// the keys of the selected satisfaction only.
+    pub fn satisfy_descriptor<Pk, Ext>(
+        &self,
+        desc: &Descriptor<Pk, Ext>,
+    ) -> Result<(Vec<Vec<u8>>, Script), Error>
+    where
+        Pk: MiniscriptKey + ToPublicKey,
+        Ext: Extension + ParseableExt,
+    {
+        *self.mode.borrow_mut() = Mode::Plan(vec![]);
+        let planned = desc.get_satisfaction(self);
+        let requests = match mem::replace(&mut *self.mode.borrow_mut(), Mode::Sign) {
+            Mode::Plan(requests) => requests,
+            _ => unreachable!("the mode is only changed by satisfy_descriptor"),
+        };
+        let (witness, script_sig) = planned?;
+
+        let contains =
+            |bytes: &[u8], marker: &[u8; 32]| bytes.windows(marker.len()).any(|w| w == &marker[..]);
+        let used = requests
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| {
+                let marker = placeholder_marker(*i);
+                contains(script_sig.as_bytes(), &marker)
+                    || witness.iter().any(|elem| contains(&elem[..], &marker))
+            })
+            .map(|(_, request)| request)
+            .collect();
+        *self.mode.borrow_mut() = Mode::Planned(used);
+        let ret = desc.get_satisfaction(self);
+        *self.mode.borrow_mut() = Mode::Sign;
+        ret
+    }
+
+    // Answer the signature lookup `request` according to the mode of the
+    // satisfier, `placeholder` building a placeholder signature from the
+    // serialization of its compact form, and `sign` computing the signature.
+    fn lookup_sig<P, S>(&self, request: SigRequest, placeholder: P, sign: S) -> Option<Sig>
+    where
+        P: FnOnce(&[u8; 64]) -> Sig,
+        S: FnOnce() -> Option<Sig>,
+    {
+        match *self.mode.borrow_mut() {
+            Mode::Sign => {}
+            Mode::Plan(ref mut requests) => {
+                let i = match requests.iter().position(|r| *r == request) {
+                    Some(i) => i,
+                    None => {
+                        requests.push(request);
+                        requests.len() - 1
+                    }
+                };
+                let marker = placeholder_marker(i);
+                let mut compact = [0; 64];
+                compact[..32].copy_from_slice(&marker);
+                compact[32..].copy_from_slice(&marker);
+                return Some(placeholder(&compact));
+            }
+            Mode::Planned(ref used) => {
+                if !used.contains(&request) {
+                    return None;
+                }
+            }
+        }
+        if let Some(sig) = self.sigs.borrow().get(&request) {
+            return Some(sig.clone());
+        }
+        let sig = sign()?;
+        self.sigs.borrow_mut().insert(request, sig.clone());
+        Some(sig)
+    }
+
+    // Find the keypair for an x-only key. The secret key may be stored
+    // under either parity of the full public key.
+    fn keypair(&self, pk: &XOnlyPublicKey) -> Option<KeyPair> {
+        [Parity::Even, Parity::Odd]
+            .iter()
+            .find_map(|parity| {
+                self.keys
+                    .secret_key(&bitcoin::PublicKey::new(pk.public_key(*parity)))
+            })
+            .map(|sk| KeyPair::from_secret_key(self.secp, &sk))
+    }
+
+    fn sign_schnorr(&self, keypair: &KeyPair, msg: &Message) -> schnorr::Signature {
+        self.secp.sign_schnorr_no_aux_rand(msg, keypair)
+    }
+}
+
+impl<'s, 'tx, 'ptx, C, K, Pk> Satisfier<Pk> for SigningSatisfier<'s, 'tx, 'ptx, C, K>
+where
+    C: secp256k1_zkp::Signing + secp256k1_zkp::Verification,
+    K: SigningKeys,
+    Pk: MiniscriptKey + ToPublicKey,
+{
+    fn lookup_ecdsa_sig(&self, pk: &Pk) -> Option<ElementsSig> {
+        let hash_ty = match self.ctx {
+            SighashCtx::Legacy { hash_ty, .. } | SighashCtx::Segwitv0 { hash_ty, .. } => hash_ty,
+            SighashCtx::Tap { .. } => return None,
+        };
+        let pk = pk.to_public_key();
+        let sk = self.keys.secret_key(&pk)?;
+        let placeholder = |compact: &[u8; 64]| {
+            let sig = ecdsa::Signature::from_compact(compact).expect("Markers are valid scalars");
+            Sig::Ecdsa((sig, hash_ty))
+        };
+        let sign = || {
+            let idx = self.env.idx();
+            let mut cache = SighashCache::new(self.env.tx());
+            let sighash = match self.ctx {
+                SighashCtx::Legacy {
+                    ref script_code, ..
+                } => cache.legacy_sighash(idx, script_code, hash_ty),
+                SighashCtx::Segwitv0 {
+                    ref script_code, ..
+                } => {
+                    let value = self.env.spent_utxos()[idx].value;
+                    cache.segwitv0_sighash(idx, script_code, value, hash_ty)
+                }
+                SighashCtx::Tap { .. } => return None,
+            };
+            let msg = Message::from_slice(sighash.as_ref()).expect("Sighashes are 32 bytes");
+            Some(Sig::Ecdsa((self.secp.sign_ecdsa(&msg, &sk), hash_ty)))
+        };
+        match self.lookup_sig(SigRequest::Ecdsa(pk), placeholder, sign)? {
+            Sig::Ecdsa(sig) => Some(sig),
+            _ => None,
+        }
+    }
+
+    fn lookup_tap_key_spend_sig(&self) -> Option<elements::SchnorrSig> {
+        let (genesis_hash, hash_ty, internal_key, merkle_root) = match self.ctx {
+            SighashCtx::Tap {
+                genesis_hash,
+                hash_ty,
+                internal_key,
+                merkle_root,
+            } => (genesis_hash, hash_ty, internal_key, merkle_root),
+            _ => return None,
+        };
+        let keypair = self.keypair(&internal_key)?;
+        let placeholder = |compact: &[u8; 64]| {
+            let sig = schnorr::Signature::from_slice(compact).expect("64 bytes");
+            Sig::Schnorr(elements::SchnorrSig { sig, hash_ty })
+        };
+        let sign = || {
+            let tweak = TapTweakHash::from_key_and_tweak(internal_key, merkle_root);
+            let tweak = Scalar::from_be_bytes(tweak.to_byte_array()).ok()?;
+            let keypair = keypair.add_xonly_tweak(self.secp, &tweak).ok()?;
+
+            let prevouts = Prevouts::All(self.env.spent_utxos());
+            let sighash = SighashCache::new(self.env.tx())
+                .taproot_key_spend_signature_hash(self.env.idx(), &prevouts, hash_ty, genesis_hash)
+                .ok()?;
+            let msg = Message::from_slice(sighash.as_ref()).expect("Sighashes are 32 bytes");
+            Some(Sig::Schnorr(elements::SchnorrSig {
+                sig: self.sign_schnorr(&keypair, &msg),
+                hash_ty,
+            }))
+        };
+        match self.lookup_sig(SigRequest::TapKeySpend, placeholder, sign)? {
+            Sig::Schnorr(sig) => Some(sig),
+            _ => None,
+        }
+    }
+
+    fn lookup_tap_leaf_script_sig(
+        &self,
+        pk: &Pk,
+        lh: &TapLeafHash,
+    ) -> Option<elements::SchnorrSig> {
+        let (genesis_hash, hash_ty) = match self.ctx {
+            SighashCtx::Tap {
+                genesis_hash,
+                hash_ty,
+                ..
+            } => (genesis_hash, hash_ty),
+            _ => return None,
+        };
+        let pk = pk.to_x_only_pubkey();
+        let keypair = self.keypair(&pk)?;
+        let placeholder = |compact: &[u8; 64]| {
+            let sig = schnorr::Signature::from_slice(compact).expect("64 bytes");
+            Sig::Schnorr(elements::SchnorrSig { sig, hash_ty })
+        };
+        let sign = || {
+            let prevouts = Prevouts::All(self.env.spent_utxos());
+            let sighash = SighashCache::new(self.env.tx())
+                .taproot_script_spend_signature_hash(
+                    self.env.idx(),
+                    &prevouts,
+                    *lh,
+                    hash_ty,
+                    genesis_hash,
+                )
+                .ok()?;
+            let msg = Message::from_slice(sighash.as_ref()).expect("Sighashes are 32 bytes");
+            Some(Sig::Schnorr(elements::SchnorrSig {
+                sig: self.sign_schnorr(&keypair, &msg),
+                hash_ty,
+            }))
+        };
+        match self.lookup_sig(SigRequest::TapLeafScript(pk, *lh), placeholder, sign)? {
+            Sig::Schnorr(sig) => Some(sig),
+            _ => None,
+        }
+    }
+
+    fn lookup_csfs_sig(&self, pk: &XOnlyPublicKey, msg: &CsfsMsg) -> Option<schnorr::Signature> {
+        let keypair = self.keypair(pk)?;
+        // Only 32 byte messages can be signed by rust-secp
+        let secp_msg = Message::from_slice(msg.as_inner()).ok()?;
+        let placeholder = |compact: &[u8; 64]| {
+            Sig::Csfs(schnorr::Signature::from_slice(compact).expect("64 bytes"))
+        };
+        let sign = || Some(Sig::Csfs(self.sign_schnorr(&keypair, &secp_msg)));
+        match self.lookup_sig(SigRequest::Csfs(*pk, msg.clone()), placeholder, sign)? {
+            Sig::Csfs(sig) => Some(sig),
+            _ => None,
+        }
+    }
+
+    fn lookup_tx(&self) -> Option<&elements::Transaction> {
+        Some(self.env.tx())
+    }
+
+    fn lookup_spent_utxos(&self) -> Option<&[elements::TxOut]> {
+        Some(self.env.spent_utxos())
+    }
+
+    fn lookup_curr_inp(&self) -> Option<usize> {
+        Some(self.env.idx())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use elements::{confidential, AssetId, LockTime, OutPoint, Sequence, TxIn, TxOut};
+
+    use super::*;
+
+    fn key_pair(secp: &Secp256k1<secp256k1_zkp::All>, i: u8) -> (bitcoin::PublicKey, SecretKey) {
+        let sk = SecretKey::from_slice(&[i; 32]).unwrap();
+        let pk = bitcoin::PublicKey::new(secp256k1_zkp::PublicKey::from_secret_key(secp, &sk));
+        (pk, sk)
+    }
+
+    // A transaction spending an output of `desc`, and the spent output
+    fn spending_tx(desc: &Descriptor<bitcoin::PublicKey>) -> (elements::Transaction, Vec<TxOut>) {
+        let asset = AssetId::from_slice(&[3; 32]).unwrap();
+        let utxos = vec![TxOut {
+            value: confidential::Value::Explicit(1_000),
+            script_pubkey: desc.script_pubkey(),
+            asset: confidential::Asset::Explicit(asset),
+            nonce: confidential::Nonce::Null,
+            witness: elements::TxOutWitness::default(),
+        }];
+        let tx = elements::Transaction {
+            version: 2,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint {
+                    txid: elements::Txid::all_zeros(),
+                    vout: 0,
+                },
+                is_pegin: false,
+                script_sig: Script::new(),
+                sequence: Sequence::MAX,
+                asset_issuance: Default::default(),
+                witness: Default::default(),
+            }],
+            output: vec![],
+        };
+        (tx, utxos)
+    }
+
+    #[test]
+    fn sign_segwitv0_on_demand() {
+        let secp = Secp256k1::new();
+        let (pk_a, _) = key_pair(&secp, 1);
+        let (pk_b, sk_b) = key_pair(&secp, 2);
+        let desc = Descriptor::<bitcoin::PublicKey>::from_str(&format!(
+            "elwsh(or_d(pk({}),pk({})))",
+            pk_a, pk_b
+        ))
+        .unwrap();
+        let (tx, utxos) = spending_tx(&desc);
+        let env = TxEnv::new(&tx, &utxos, 0).unwrap();
+        let script_code = desc.script_code().unwrap();
+
+        // Only the key for the second branch is known
+        let mut keys = HashMap::new();
+        keys.insert(pk_b, sk_b);
+        let sat = SigningSatisfier::new_segwitv0(
+            &secp,
+            &keys,
+            env,
+            script_code.clone(),
+            EcdsaSighashType::All,
+        );
+        assert!(Satisfier::<bitcoin::PublicKey>::lookup_ecdsa_sig(&sat, &pk_a).is_none());
+        let (sig, hash_ty) =
+            Satisfier::<bitcoin::PublicKey>::lookup_ecdsa_sig(&sat, &pk_b).unwrap();
+        assert_eq!(hash_ty, EcdsaSighashType::All);
+
+        let sighash = SighashCache::new(&tx).segwitv0_sighash(
+            0,
+            &script_code,
+            utxos[0].value,
+            EcdsaSighashType::All,
+        );
+        let msg = Message::from_slice(sighash.as_ref()).unwrap();
+        assert!(secp.verify_ecdsa(&msg, &sig, &pk_b.inner).is_ok());
+
+        // The satisfaction chooses the branch that we can sign for
+        let (witness, script_sig) = desc.get_satisfaction(&sat).unwrap();
+        assert!(script_sig.is_empty());
+        assert_eq!(witness.len(), 3);
+        // Signatures are memoized
+        assert_eq!(sat.num_signatures(), 1);
+    }
+
+    #[test]
+    fn sign_only_used_keys() {
+        let secp = Secp256k1::new();
+        let (pk_a, sk_a) = key_pair(&secp, 1);
+        let (pk_b, sk_b) = key_pair(&secp, 2);
+        let (pk_c, sk_c) = key_pair(&secp, 3);
+        let mut keys = BTreeMap::new();
+        keys.insert(pk_a, sk_a);
+        keys.insert(pk_b, sk_b);
+        keys.insert(pk_c, sk_c);
+
+        // A satisfaction looks up the signatures of every key
+        let desc = Descriptor::<bitcoin::PublicKey>::from_str(&format!(
+            "elwsh(or_d(pk({}),and_v(v:pk({}),pk({}))))",
+            pk_a, pk_b, pk_c
+        ))
+        .unwrap();
+        let (tx, utxos) = spending_tx(&desc);
+        let script_code = desc.script_code().unwrap();
+        let new_sat = || {
+            let env = TxEnv::new(&tx, &utxos, 0).unwrap();
+            SigningSatisfier::new_segwitv0(
+                &secp,
+                &keys,
+                env,
+                script_code.clone(),
+                EcdsaSighashType::All,
+            )
+        };
+        let sat = new_sat();
+        let expected = desc.get_satisfaction(&sat).unwrap();
+        assert_eq!(sat.num_signatures(), 3);
+
+        // Only the key of the selected branch is signed for
+        let sat = new_sat();
+        assert_eq!(sat.satisfy_descriptor(&desc).unwrap(), expected);
+        assert_eq!(sat.num_signatures(), 1);
+
+        // Same for the leaves of a TapTree
+        let internal_key = bitcoin::PublicKey::from_str(
+            "02e96fe52ef0e22d2f131dd425ce1893073a3c6ad20e8cac36726393dfb4856a4c",
+        )
+        .unwrap();
+        let desc = Descriptor::<bitcoin::PublicKey>::from_str(&format!(
+            "eltr({},{{pk({}),and_v(v:pk({}),pk({}))}})",
+            internal_key, pk_a, pk_b, pk_c
+        ))
+        .unwrap();
+        let tr = match desc {
+            Descriptor::Tr(ref tr) => tr,
+            _ => unreachable!(),
+        };
+        let (tx, utxos) = spending_tx(&desc);
+        let new_sat = || {
+            let env = TxEnv::new(&tx, &utxos, 0).unwrap();
+            SigningSatisfier::new_tap(
+                &secp,
+                &keys,
+                env,
+                BlockHash::all_zeros(),
+                SchnorrSighashType::Default,
+                internal_key.inner.x_only_public_key().0,
+                tr.spend_info().merkle_root(),
+            )
+        };
+        let sat = new_sat();
+        let expected = desc.get_satisfaction(&sat).unwrap();
+        assert_eq!(sat.num_signatures(), 3);
+        let sat = new_sat();
+        assert_eq!(sat.satisfy_descriptor(&desc).unwrap(), expected);
+        assert_eq!(sat.num_signatures(), 1);
+    }
+}
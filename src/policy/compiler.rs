@@ -4,7 +4,8 @@
 //! # Policy Compiler
 //!
 //! Optimizing compiler from concrete policies to Miniscript.
-//! Currently the policy compiler does not support any extensions
+//! Extension terminals are placed using the correctness, malleability and
+//! extra data reported by their [`Extension`] implementation.
 
 use std::collections::vec_deque::VecDeque;
 use std::collections::BTreeMap;
@@ -13,15 +14,114 @@ use std::marker::PhantomData;
 use std::sync::Arc;
 use std::{cmp, error, f64, fmt, hash, mem};
 
-use crate::miniscript::context::SigType;
+use crate::miniscript::context::{ScriptContextError, SigType};
 use crate::miniscript::limits::MAX_PUBKEYS_PER_MULTISIG;
 use crate::miniscript::types::{self, ErrorKind, ExtData, Property, Type};
 use crate::miniscript::ScriptContext;
 use crate::policy::Concrete;
 use crate::{policy, Extension, Miniscript, MiniscriptKey, Terminal};
 
-type PolicyCache<Pk, Ctx> =
-    BTreeMap<(Concrete<Pk>, OrdF64, Option<OrdF64>), BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>>;
+/// Maximum number of compilations of the same type kept for a (sub)policy
+/// when searching for compilations within the resource limits, the most
+/// expensive ones being discarded beyond it
+const MAX_RESOURCE_AWARE_COMPILATIONS: usize = 8;
+
+/// State of a compilation: the best compilations of the sub-policies, keyed
+/// by their sat and dissat probabilities, along with the search options.
+struct PolicyCache<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    compilations: BTreeMap<
+        (Concrete<Pk, Ext>, OrdF64, Option<OrdF64>),
+        BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    >,
+    /// The cost model of the compilation
+    config: CompilerConfig,
+    /// Whether compilations which use fewer resources than a cheaper one are
+    /// kept, to find compilations within the resource limits of `Ctx`
+    resource_aware: bool,
+    /// The compilations rejected for the sub-policies, keyed like
+    /// `compilations`, only recorded when explaining a compilation
+    #[allow(clippy::type_complexity)]
+    rejections: Option<
+        BTreeMap<
+            (Concrete<Pk, Ext>, OrdF64, Option<OrdF64>),
+            Vec<(AstElemExt<Pk, Ctx, Ext>, RejectionReason)>,
+        >,
+    >,
+}
+
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> PolicyCache<Pk, Ctx, Ext> {
+    fn new(config: CompilerConfig, resource_aware: bool) -> Self {
+        PolicyCache {
+            compilations: BTreeMap::new(),
+            config,
+            resource_aware,
+            rejections: None,
+        }
+    }
+}
+
+/// Cost model of the policy compiler
+///
+/// The compiler minimizes the expected cost of spending the compiled script,
+/// which is the sum of the script size and of the satisfaction and
+/// dissatisfaction witness sizes weighted by their probabilities. Each of
+/// these sizes is multiplied by its weight. Scaling all the weights does not
+/// change the chosen compilation but only the costs reported for it.
+///
+/// The default configuration weights script and witness bytes equally, as
+/// segwit and taproot scripts are revealed in the witness of the spending
+/// transaction.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct CompilerConfig {
+    /// Weight of a byte of script
+    pub script_size_weight: f64,
+    /// Weight of a byte of satisfaction witness
+    pub sat_weight: f64,
+    /// Weight of a byte of dissatisfaction witness
+    pub dissat_weight: f64,
+    /// Whether Taproot compilations should use a key of the policy which can
+    /// spend on its own as the internal key, so that it spends through the
+    /// key path. When `false`, the policy is entirely compiled into script
+    /// leaves under the provided unspendable key, if any.
+    pub prefer_key_path: bool,
+    /// Maximum script size of the leaves of Taproot compilations, on top of
+    /// the limits of the [`Tap`](crate::Tap) context
+    pub max_tap_leaf_size: Option<usize>,
+    /// Whether the keys of the `multi` and `multi_a` fragments emitted by the
+    /// compiler are sorted, so that the compilation does not depend on the
+    /// order of the keys in the thresholds of the policy
+    pub sort_multi_keys: bool,
+    /// Whether Taproot compilations split the key-only thresholds among their
+    /// leaves into one leaf per subset of keys able to spend, when this lowers
+    /// the weight of spending through the threshold
+    pub split_key_thresholds: bool,
+}
+
+impl Default for CompilerConfig {
+    fn default() -> Self {
+        CompilerConfig {
+            script_size_weight: 1.0,
+            sat_weight: 1.0,
+            dissat_weight: 1.0,
+            prefer_key_path: true,
+            max_tap_leaf_size: None,
+            sort_multi_keys: false,
+            split_key_thresholds: false,
+        }
+    }
+}
+
+impl CompilerConfig {
+    /// Checks that the weights are finite and non-negative
+    pub fn sanity_check(&self) -> Result<(), CompilerError> {
+        let weights = [self.script_size_weight, self.sat_weight, self.dissat_weight];
+        if weights.iter().all(|w| w.is_finite() && *w >= 0.0) {
+            Ok(())
+        } else {
+            Err(CompilerError::InvalidConfig)
+        }
+    }
+}
 
 ///Ordered f64 for comparison
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -49,11 +149,12 @@ pub enum CompilerError {
     TopLevelNonSafe,
     /// Non-Malleable compilation  does exists for the given sub-policy.
     ImpossibleNonMalleableCompilation,
-    /// At least one satisfaction path in the optimal Miniscript has exceeded
-    /// the consensus or standardness limits.
-    /// There may exist other miniscripts which are under these limits but the
-    /// compiler currently does not find them.
+    /// No compilation within the consensus and standardness limits was found,
+    /// even when searching among the compilations which use fewer resources
+    /// than the cheapest ones.
     LimitsExceeded,
+    /// The [`CompilerConfig`] has a negative or non-finite weight.
+    InvalidConfig,
     ///Policy related errors
     PolicyError(policy::concrete::PolicyError),
 }
@@ -68,8 +169,11 @@ impl fmt::Display for CompilerError {
                 f.write_str("The compiler could not find any non-malleable compilation")
             }
             CompilerError::LimitsExceeded => f.write_str(
-                "At least one spending path has exceeded the standardness or consensus limits",
+                "Every compilation has a spending path exceeding the standardness or consensus limits",
             ),
+            CompilerError::InvalidConfig => {
+                f.write_str("Compiler weights must be finite and non-negative")
+            }
             CompilerError::PolicyError(ref e) => fmt::Display::fmt(e, f),
         }
     }
@@ -80,7 +184,10 @@ impl error::Error for CompilerError {
         use self::CompilerError::*;
 
         match self {
-            TopLevelNonSafe | ImpossibleNonMalleableCompilation | LimitsExceeded => None,
+            TopLevelNonSafe
+            | ImpossibleNonMalleableCompilation
+            | LimitsExceeded
+            | InvalidConfig => None,
             PolicyError(e) => Some(e),
         }
     }
@@ -93,6 +200,93 @@ impl From<policy::concrete::PolicyError> for CompilerError {
     }
 }
 
+/// Reason why the compiler rejected a compilation of a policy
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum RejectionReason {
+    /// The compilation is malleable
+    Malleable,
+    /// The compilation is not safe, so it can not be the top level script
+    NonSafe,
+    /// The compilation exceeds the consensus or standardness limits of the
+    /// script context, or is otherwise invalid in it
+    ExceedsLimits(ScriptContextError),
+    /// A cheaper compilation, which can be used wherever this one can, was
+    /// found
+    Dominated,
+    /// Too many compilations of the same type using different resources
+    /// were found, and this one was among the most expensive
+    Pruned,
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            RejectionReason::Malleable => f.write_str("malleable"),
+            RejectionReason::NonSafe => f.write_str("not safe at top level"),
+            RejectionReason::ExceedsLimits(ref e) => write!(f, "invalid in context: {}", e),
+            RejectionReason::Dominated => f.write_str("a cheaper compilation of a subtype exists"),
+            RejectionReason::Pruned => {
+                f.write_str("too many cheaper compilations within the resource limits exist")
+            }
+        }
+    }
+}
+
+/// A compilation of a policy node considered by the compiler
+#[derive(Clone, Debug)]
+pub struct Candidate<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    /// The compilation, whose type is `ms.ty`
+    pub ms: Arc<Miniscript<Pk, Ctx, Ext>>,
+    /// The expected cost of the compilation under the cost model of the
+    /// compilation, given the sat and dissat probabilities of the node
+    pub cost: f64,
+    /// Why the compilation was rejected, `None` if it was kept
+    pub rejection: Option<RejectionReason>,
+}
+
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> fmt::Display
+    for Candidate<Pk, Ctx, Ext>
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ({:?}, cost {:.2})",
+            self.ms, self.ms.ty.corr.base, self.cost
+        )?;
+        if let Some(ref reason) = self.rejection {
+            write!(f, ": {}", reason)?;
+        }
+        Ok(())
+    }
+}
+
+/// The candidates of a policy node compiled with given sat and dissat
+/// probabilities
+#[derive(Clone, Debug)]
+pub struct NodeExplanation<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    /// The policy node
+    pub policy: Concrete<Pk, Ext>,
+    /// The probability that the node is satisfied
+    pub sat_prob: f64,
+    /// The probability that the node is dissatisfied, `None` if it is never
+    /// dissatisfied
+    pub dissat_prob: Option<f64>,
+    /// The cheapest compilations kept by the compiler, by increasing cost
+    pub candidates: Vec<Candidate<Pk, Ctx, Ext>>,
+    /// The cheapest compilations rejected by the compiler, by increasing cost
+    pub rejected: Vec<Candidate<Pk, Ctx, Ext>>,
+}
+
+/// Explanation of a compilation, see [`explain_compilation`]
+#[derive(Clone, Debug)]
+pub struct Explanation<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    /// The result of the compilation, as returned by
+    /// [`best_compilation_with_config`]
+    pub result: Result<Miniscript<Pk, Ctx, Ext>, CompilerError>,
+    /// The candidates of every policy node, in pre-order of the policy
+    pub nodes: Vec<NodeExplanation<Pk, Ctx, Ext>>,
+}
+
 /// Hash required for using OrdF64 as key for hashmap
 impl hash::Hash for OrdF64 {
     fn hash<H: hash::Hasher>(&self, state: &mut H) {
@@ -118,6 +312,11 @@ struct CompilationKey {
     /// that all possible compilations of a (sub)policy have the same sat-prob
     /// and only differ in dissat_prob.
     dissat_prob: Option<OrdF64>,
+
+    /// The resources used by the compilation, only set when searching for
+    /// compilations within the resource limits. Compilations using fewer
+    /// resources are then kept even if they are more expensive.
+    resources: Option<Resources>,
 }
 
 impl CompilationKey {
@@ -127,6 +326,10 @@ impl CompilationKey {
         self.ty.is_subtype(other.ty)
             && self.expensive_verify == other.expensive_verify
             && self.dissat_prob == other.dissat_prob
+            && match (self.resources, other.resources) {
+                (Some(res), Some(other_res)) => res.fits_in(other_res),
+                _ => true,
+            }
     }
 
     /// Helper to create compilation key from components
@@ -135,10 +338,50 @@ impl CompilationKey {
             ty,
             expensive_verify,
             dissat_prob: dissat_prob.map(OrdF64),
+            resources: None,
         }
     }
 }
 
+/// Resources of a compilation which are bounded by the consensus and
+/// standardness limits of the script contexts
+#[derive(Copy, Clone, PartialEq, Eq, Debug, PartialOrd, Ord)]
+struct Resources {
+    /// The script size
+    script_size: usize,
+    /// The worst case number of executed opcodes when satisfying
+    ops: usize,
+    /// The worst case number of satisfaction witness elements
+    sat_stack_elems: usize,
+    /// The worst case satisfaction witness size
+    sat_size: usize,
+    /// The worst case number of stack elements during satisfaction
+    exec_stack_elems: usize,
+}
+
+impl Resources {
+    fn of<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+        ms: &Miniscript<Pk, Ctx, Ext>,
+    ) -> Resources {
+        Resources {
+            script_size: ms.ext.pk_cost,
+            ops: ms.ext.ops.op_count().unwrap_or(0),
+            sat_stack_elems: ms.ext.stack_elem_count_sat.unwrap_or(0),
+            sat_size: ms.ext.max_sat_size.map_or(0, |(w, s)| cmp::max(w, s)),
+            exec_stack_elems: ms.ext.exec_stack_elem_count_sat.unwrap_or(0),
+        }
+    }
+
+    /// Whether `self` uses at most the resources used by `other`
+    fn fits_in(self, other: Resources) -> bool {
+        self.script_size <= other.script_size
+            && self.ops <= other.ops
+            && self.sat_stack_elems <= other.sat_stack_elems
+            && self.sat_size <= other.sat_size
+            && self.exec_stack_elems <= other.exec_stack_elems
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct CompilerExtData {
     /// If this node is the direct child of a disjunction, this field must
@@ -230,8 +473,22 @@ impl Property for CompilerExtData {
         }
     }
 
-    fn from_ext<E: Extension>(_e: &E) -> Self {
-        unreachable!("NoExt context should not have extensions")
+    fn from_ext<E: Extension>(e: &E) -> Self {
+        // Extensions report their witness sizes through `ExtData`; use the
+        // segwit witness component as the sat/dissat cost.
+        let ext = e.extra_prop();
+        CompilerExtData {
+            branch_prob: None,
+            sat_cost: ext
+                .max_sat_size
+                .map(|(w, _)| w as f64)
+                .unwrap_or(f64::INFINITY),
+            dissat_cost: if e.corr_prop().dissatisfiable {
+                ext.max_dissat_size.map(|(w, _)| w as f64)
+            } else {
+                None
+            },
+        }
     }
 
     fn cast_alt(self) -> Result<Self, types::ErrorKind> {
@@ -457,31 +714,44 @@ impl Property for CompilerExtData {
 
 /// Miniscript AST fragment with additional data needed by the compiler
 #[derive(Clone, Debug)]
-struct AstElemExt<Pk: MiniscriptKey, Ctx: ScriptContext> {
+struct AstElemExt<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
     /// The actual Miniscript fragment with type information
-    ms: Arc<Miniscript<Pk, Ctx>>,
+    ms: Arc<Miniscript<Pk, Ctx, Ext>>,
     /// Its "type" in terms of compiler data
     comp_ext_data: CompilerExtData,
 }
 
-impl<Pk: MiniscriptKey, Ctx: ScriptContext> AstElemExt<Pk, Ctx> {
-    /// Compute a 1-dimensional cost, given a probability of satisfaction
-    /// and a probability of dissatisfaction; if `dissat_prob` is `None`
-    /// then it is assumed that dissatisfaction never occurs
-    fn cost_1d(&self, sat_prob: f64, dissat_prob: Option<f64>) -> f64 {
-        self.ms.ext.pk_cost as f64
-            + self.comp_ext_data.sat_cost * sat_prob
-            + match (dissat_prob, self.comp_ext_data.dissat_cost) {
-                (Some(prob), Some(cost)) => prob * cost,
-                (Some(_), None) => f64::INFINITY,
-                (None, Some(_)) => 0.0,
-                (None, None) => 0.0,
-            }
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> AstElemExt<Pk, Ctx, Ext> {
+    /// Compute a 1-dimensional cost under the cost model `config`, given a
+    /// probability of satisfaction and a probability of dissatisfaction; if
+    /// `dissat_prob` is `None` then it is assumed that dissatisfaction never
+    /// occurs
+    fn cost_1d(&self, config: &CompilerConfig, sat_prob: f64, dissat_prob: Option<f64>) -> f64 {
+        let dissat_cost = match (dissat_prob, self.comp_ext_data.dissat_cost) {
+            (Some(prob), Some(cost)) => weighted(config.dissat_weight * prob, cost),
+            (Some(_), None) => return f64::INFINITY,
+            (None, Some(_)) => 0.0,
+            (None, None) => 0.0,
+        };
+        weighted(config.script_size_weight, self.ms.ext.pk_cost as f64)
+            + weighted(config.sat_weight * sat_prob, self.comp_ext_data.sat_cost)
+            + dissat_cost
     }
 }
 
-impl<Pk: MiniscriptKey, Ctx: ScriptContext> AstElemExt<Pk, Ctx> {
-    fn terminal(ast: Terminal<Pk, Ctx>) -> AstElemExt<Pk, Ctx> {
+/// Weight a cost, a zero weight ignoring the cost even when it is infinite,
+/// as for the satisfaction of an extension of unknown witness size, so that
+/// the weighted cost is never NaN
+fn weighted(weight: f64, cost: f64) -> f64 {
+    if weight == 0.0 {
+        0.0
+    } else {
+        weight * cost
+    }
+}
+
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> AstElemExt<Pk, Ctx, Ext> {
+    fn terminal(ast: Terminal<Pk, Ctx, Ext>) -> AstElemExt<Pk, Ctx, Ext> {
         AstElemExt {
             comp_ext_data: CompilerExtData::type_check(&ast, |_| None).unwrap(),
             ms: Arc::new(Miniscript::from_ast(ast).expect("Terminal creation must always succeed")),
@@ -489,10 +759,10 @@ impl<Pk: MiniscriptKey, Ctx: ScriptContext> AstElemExt<Pk, Ctx> {
     }
 
     fn binary(
-        ast: Terminal<Pk, Ctx>,
-        l: &AstElemExt<Pk, Ctx>,
-        r: &AstElemExt<Pk, Ctx>,
-    ) -> Result<AstElemExt<Pk, Ctx>, types::Error<Pk, Ctx>> {
+        ast: Terminal<Pk, Ctx, Ext>,
+        l: &AstElemExt<Pk, Ctx, Ext>,
+        r: &AstElemExt<Pk, Ctx, Ext>,
+    ) -> Result<AstElemExt<Pk, Ctx, Ext>, types::Error<Pk, Ctx, Ext>> {
         let lookup_ext = |n| match n {
             0 => Some(l.comp_ext_data),
             1 => Some(r.comp_ext_data),
@@ -515,11 +785,11 @@ impl<Pk: MiniscriptKey, Ctx: ScriptContext> AstElemExt<Pk, Ctx> {
     }
 
     fn ternary(
-        ast: Terminal<Pk, Ctx>,
-        a: &AstElemExt<Pk, Ctx>,
-        b: &AstElemExt<Pk, Ctx>,
-        c: &AstElemExt<Pk, Ctx>,
-    ) -> Result<AstElemExt<Pk, Ctx>, types::Error<Pk, Ctx>> {
+        ast: Terminal<Pk, Ctx, Ext>,
+        a: &AstElemExt<Pk, Ctx, Ext>,
+        b: &AstElemExt<Pk, Ctx, Ext>,
+        c: &AstElemExt<Pk, Ctx, Ext>,
+    ) -> Result<AstElemExt<Pk, Ctx, Ext>, types::Error<Pk, Ctx, Ext>> {
         let lookup_ext = |n| match n {
             0 => Some(a.comp_ext_data),
             1 => Some(b.comp_ext_data),
@@ -545,15 +815,15 @@ impl<Pk: MiniscriptKey, Ctx: ScriptContext> AstElemExt<Pk, Ctx> {
 
 /// Different types of casts possible for each node.
 #[derive(Copy, Clone)]
-struct Cast<Pk: MiniscriptKey, Ctx: ScriptContext> {
-    node: fn(Arc<Miniscript<Pk, Ctx>>) -> Terminal<Pk, Ctx>,
+struct Cast<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    node: fn(Arc<Miniscript<Pk, Ctx, Ext>>) -> Terminal<Pk, Ctx, Ext>,
     ast_type: fn(types::Type) -> Result<types::Type, ErrorKind>,
     ext_data: fn(types::ExtData) -> Result<types::ExtData, ErrorKind>,
     comp_ext_data: fn(CompilerExtData) -> Result<CompilerExtData, types::ErrorKind>,
 }
 
-impl<Pk: MiniscriptKey, Ctx: ScriptContext> Cast<Pk, Ctx> {
-    fn cast(&self, ast: &AstElemExt<Pk, Ctx>) -> Result<AstElemExt<Pk, Ctx>, ErrorKind> {
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> Cast<Pk, Ctx, Ext> {
+    fn cast(&self, ast: &AstElemExt<Pk, Ctx, Ext>) -> Result<AstElemExt<Pk, Ctx, Ext>, ErrorKind> {
         Ok(AstElemExt {
             ms: Arc::new(Miniscript {
                 ty: (self.ast_type)(ast.ms.ty)?,
@@ -566,7 +836,7 @@ impl<Pk: MiniscriptKey, Ctx: ScriptContext> Cast<Pk, Ctx> {
     }
 }
 
-fn all_casts<Pk: MiniscriptKey, Ctx: ScriptContext>() -> [Cast<Pk, Ctx>; 10] {
+fn all_casts<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>() -> [Cast<Pk, Ctx, Ext>; 10] {
     [
         Cast {
             ext_data: types::ExtData::cast_check,
@@ -660,25 +930,38 @@ fn all_casts<Pk: MiniscriptKey, Ctx: ScriptContext>() -> [Cast<Pk, Ctx>; 10] {
 /// the map.
 /// In general, we maintain the invariant that if anything is inserted into the
 /// map, it's cast closure must also be considered for best compilations.
-fn insert_elem<Pk: MiniscriptKey, Ctx: ScriptContext>(
-    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    elem: AstElemExt<Pk, Ctx>,
+fn insert_elem<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    elem: AstElemExt<Pk, Ctx, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
+    config: &CompilerConfig,
+    resource_aware: bool,
+    rejected: Option<&mut Vec<(AstElemExt<Pk, Ctx, Ext>, RejectionReason)>>,
 ) -> bool {
     // return malleable types directly. If a elem is malleable under current context,
     // all the casts to it are also going to be malleable
     if !elem.ms.ty.mall.non_malleable && Ctx::check_terminal_non_malleable(&elem.ms.node).is_ok() {
+        if let Some(rejected) = rejected {
+            rejected.push((elem, RejectionReason::Malleable));
+        }
         return false;
     }
 
-    if Ctx::check_local_validity(&elem.ms).is_err() {
+    if let Err(e) = Ctx::check_local_validity(&elem.ms) {
+        if let Some(rejected) = rejected {
+            rejected.push((elem, RejectionReason::ExceedsLimits(e)));
+        }
         return false;
     }
 
-    let elem_cost = elem.cost_1d(sat_prob, dissat_prob);
+    let elem_cost = elem.cost_1d(config, sat_prob, dissat_prob);
 
-    let elem_key = CompilationKey::from_type(elem.ms.ty, elem.ms.ext.has_free_verify, dissat_prob);
+    let mut elem_key =
+        CompilationKey::from_type(elem.ms.ty, elem.ms.ext.has_free_verify, dissat_prob);
+    if resource_aware {
+        elem_key.resources = Some(Resources::of(&elem.ms));
+    }
 
     // Check whether the new element is worse than any existing element. If there
     // is an element which is a subtype of the current element and has better
@@ -686,23 +969,80 @@ fn insert_elem<Pk: MiniscriptKey, Ctx: ScriptContext>(
     let is_worse = map
         .iter()
         .map(|(existing_key, existing_elem)| {
-            let existing_elem_cost = existing_elem.cost_1d(sat_prob, dissat_prob);
+            let existing_elem_cost = existing_elem.cost_1d(config, sat_prob, dissat_prob);
             existing_key.is_subtype(elem_key) && existing_elem_cost <= elem_cost
         })
         .any(|x| x);
     if !is_worse {
         // If the element is not worse any element in the map, remove elements
         // whose subtype is the current element and have worse cost.
-        *map = mem::take(map)
+        let (kept, removed): (BTreeMap<_, _>, BTreeMap<_, _>) = mem::take(map)
             .into_iter()
-            .filter(|(existing_key, existing_elem)| {
-                let existing_elem_cost = existing_elem.cost_1d(sat_prob, dissat_prob);
+            .partition(|(existing_key, existing_elem)| {
+                let existing_elem_cost = existing_elem.cost_1d(config, sat_prob, dissat_prob);
                 !(elem_key.is_subtype(*existing_key) && existing_elem_cost >= elem_cost)
-            })
-            .collect();
+            });
+        *map = kept;
+        let mut rejected = rejected;
+        if let Some(ref mut rejected) = rejected {
+            rejected.extend(
+                removed
+                    .into_values()
+                    .map(|elem| (elem, RejectionReason::Dominated)),
+            );
+        }
         map.insert(elem_key, elem);
+        if resource_aware {
+            if let Some(pruned) = prune_resource_aware(map, elem_key, sat_prob, dissat_prob, config)
+            {
+                let is_elem = pruned.0 == elem_key;
+                if let Some(rejected) = rejected {
+                    rejected.push((pruned.1, RejectionReason::Pruned));
+                }
+                return !is_elem;
+            }
+        }
+        true
+    } else {
+        if let Some(rejected) = rejected {
+            rejected.push((elem, RejectionReason::Dominated));
+        }
+        false
     }
-    !is_worse
+}
+
+/// Bound the number of compilations of the type of `key` kept in the map of
+/// a resource-aware search by removing the most expensive one, which is
+/// returned, when there are more than [`MAX_RESOURCE_AWARE_COMPILATIONS`].
+/// The compilations of the same type using different resources do not
+/// dominate each other, so that their number is otherwise unbounded.
+fn prune_resource_aware<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    key: CompilationKey,
+    sat_prob: f64,
+    dissat_prob: Option<f64>,
+    config: &CompilerConfig,
+) -> Option<(CompilationKey, AstElemExt<Pk, Ctx, Ext>)> {
+    let same_type = |other: &CompilationKey| {
+        other.ty == key.ty
+            && other.expensive_verify == key.expensive_verify
+            && other.dissat_prob == key.dissat_prob
+    };
+    let mut num_same_type = 0;
+    let mut most_expensive: Option<(OrdF64, CompilationKey)> = None;
+    for (other_key, elem) in map.iter().filter(|(k, _)| same_type(*k)) {
+        num_same_type += 1;
+        let cost = OrdF64(elem.cost_1d(config, sat_prob, dissat_prob));
+        if most_expensive.map_or(true, |(max, _)| cost >= max) {
+            most_expensive = Some((cost, *other_key));
+        }
+    }
+    if num_same_type <= MAX_RESOURCE_AWARE_COMPILATIONS {
+        return None;
+    }
+    let (_, pruned_key) = most_expensive.expect("the map has compilations of this type");
+    let pruned = map.remove(&pruned_key).expect("the key is in the map");
+    Some((pruned_key, pruned))
 }
 
 /// Insert the cast-closure of  in the `astelem_ext`. The cast_stack
@@ -713,24 +1053,43 @@ fn insert_elem<Pk: MiniscriptKey, Ctx: ScriptContext>(
 /// At the start and end of this function, we maintain that the invariant that
 /// all map is smallest possible closure of all compilations of a policy with
 /// given sat and dissat probabilities.
-fn insert_elem_closure<Pk: MiniscriptKey, Ctx: ScriptContext>(
-    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    astelem_ext: AstElemExt<Pk, Ctx>,
+fn insert_elem_closure<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    astelem_ext: AstElemExt<Pk, Ctx, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
+    config: &CompilerConfig,
+    resource_aware: bool,
+    mut rejected: Option<&mut Vec<(AstElemExt<Pk, Ctx, Ext>, RejectionReason)>>,
 ) {
-    let mut cast_stack: VecDeque<AstElemExt<Pk, Ctx>> = VecDeque::new();
-    if insert_elem(map, astelem_ext.clone(), sat_prob, dissat_prob) {
+    let mut cast_stack: VecDeque<AstElemExt<Pk, Ctx, Ext>> = VecDeque::new();
+    if insert_elem(
+        map,
+        astelem_ext.clone(),
+        sat_prob,
+        dissat_prob,
+        config,
+        resource_aware,
+        rejected.as_deref_mut(),
+    ) {
         cast_stack.push_back(astelem_ext);
     }
 
-    let casts: [Cast<Pk, Ctx>; 10] = all_casts::<Pk, Ctx>();
+    let casts: [Cast<Pk, Ctx, Ext>; 10] = all_casts::<Pk, Ctx, Ext>();
     while !cast_stack.is_empty() {
         let current = cast_stack.pop_front().unwrap();
 
         for cast in &casts {
             if let Ok(new_ext) = cast.cast(&current) {
-                if insert_elem(map, new_ext.clone(), sat_prob, dissat_prob) {
+                if insert_elem(
+                    map,
+                    new_ext.clone(),
+                    sat_prob,
+                    dissat_prob,
+                    config,
+                    resource_aware,
+                    rejected.as_deref_mut(),
+                ) {
                     cast_stack.push_back(new_ext);
                 }
             }
@@ -747,46 +1106,76 @@ fn insert_elem_closure<Pk: MiniscriptKey, Ctx: ScriptContext>(
 /// given that it may be not be necessary to dissatisfy. For these elements, we
 /// apply the wrappers around the element once and bring them into the same
 /// dissat probability map and get their closure.
-fn insert_best_wrapped<Pk: MiniscriptKey, Ctx: ScriptContext>(
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
-    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    data: AstElemExt<Pk, Ctx>,
+fn insert_best_wrapped<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
+    map: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    data: AstElemExt<Pk, Ctx, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
 ) -> Result<(), CompilerError> {
-    insert_elem_closure(map, data, sat_prob, dissat_prob);
+    let config = policy_cache.config;
+    let resource_aware = policy_cache.resource_aware;
+    let mut rejected = policy_cache.rejections.as_ref().map(|_| vec![]);
+    insert_elem_closure(
+        map,
+        data,
+        sat_prob,
+        dissat_prob,
+        &config,
+        resource_aware,
+        rejected.as_mut(),
+    );
 
     if dissat_prob.is_some() {
-        let casts: [Cast<Pk, Ctx>; 10] = all_casts::<Pk, Ctx>();
+        let casts: [Cast<Pk, Ctx, Ext>; 10] = all_casts::<Pk, Ctx, Ext>();
 
         for cast in &casts {
             for x in best_compilations(policy_cache, policy, sat_prob, None)?.values() {
                 if let Ok(new_ext) = cast.cast(x) {
-                    insert_elem_closure(map, new_ext, sat_prob, dissat_prob);
+                    insert_elem_closure(
+                        map,
+                        new_ext,
+                        sat_prob,
+                        dissat_prob,
+                        &config,
+                        resource_aware,
+                        rejected.as_mut(),
+                    );
                 }
             }
         }
     }
+    if let (Some(rejections), Some(rejected)) = (policy_cache.rejections.as_mut(), rejected) {
+        rejections
+            .entry((policy.clone(), OrdF64(sat_prob), dissat_prob.map(OrdF64)))
+            .or_insert_with(Vec::new)
+            .extend(rejected);
+    }
     Ok(())
 }
 
 /// Get the best compilations of a policy with a given sat and dissat
 /// probabilities. This functions caches the results into a global policy cache.
-fn best_compilations<Pk, Ctx>(
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
+fn best_compilations<Pk, Ctx, Ext>(
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
-) -> Result<BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>, CompilerError>
+) -> Result<BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>, CompilerError>
 where
     Pk: MiniscriptKey,
     Ctx: ScriptContext,
+    Ext: Extension,
 {
     //Check the cache for hits
     let ord_sat_prob = OrdF64(sat_prob);
     let ord_dissat_prob = dissat_prob.map(OrdF64);
-    if let Some(ret) = policy_cache.get(&(policy.clone(), ord_sat_prob, ord_dissat_prob)) {
+    if let Some(ret) =
+        policy_cache
+            .compilations
+            .get(&(policy.clone(), ord_sat_prob, ord_dissat_prob))
+    {
         return Ok(ret.clone());
     }
 
@@ -855,6 +1244,7 @@ where
         Concrete::Hash160(ref hash) => {
             insert_wrap!(AstElemExt::terminal(Terminal::Hash160(hash.clone())))
         }
+        Concrete::Ext(ref e) => insert_wrap!(AstElemExt::terminal(Terminal::Ext(e.clone()))),
         Concrete::And(ref subs) => {
             assert_eq!(subs.len(), 2, "and takes 2 args");
             let mut left = best_compilations(policy_cache, &subs[0], sat_prob, dissat_prob)?;
@@ -987,7 +1377,8 @@ where
                 let be = best(types::Base::B, policy_cache, ast, sp, dp)?;
                 let bw = best(types::Base::W, policy_cache, ast, sp, dp)?;
 
-                let diff = be.cost_1d(sp, dp) - bw.cost_1d(sp, dp);
+                let config = policy_cache.config;
+                let diff = be.cost_1d(&config, sp, dp) - bw.cost_1d(&config, sp, dp);
                 best_es.push((be.comp_ext_data, be));
                 best_ws.push((bw.comp_ext_data, bw));
 
@@ -1016,7 +1407,36 @@ where
             };
             insert_wrap!(ast_ext);
 
-            let key_vec: Vec<Pk> = subs
+            // The cheapest subs may together exceed the resource limits, also
+            // try the threshold of the smallest ones.
+            if policy_cache.resource_aware {
+                let mut sub_ast = Vec::with_capacity(n);
+                let mut sub_ext_data = Vec::with_capacity(n);
+                for (i, ast) in subs.iter().enumerate() {
+                    let sp = sat_prob * k_over_n;
+                    let dp = Some(dissat_prob.unwrap_or(0.0) + (1.0 - k_over_n) * sat_prob);
+                    let basic_type = if i == 0 {
+                        types::Base::B
+                    } else {
+                        types::Base::W
+                    };
+                    let sub = smallest(basic_type, policy_cache, ast, sp, dp)?;
+                    sub_ext_data.push(sub.comp_ext_data);
+                    sub_ast.push(sub.ms);
+                }
+                let ast = Terminal::Thresh(k, sub_ast);
+                let ast_ext = AstElemExt {
+                    ms: Arc::new(
+                        Miniscript::from_ast(ast)
+                            .expect("threshold subs, which we just compiled, typeck"),
+                    ),
+                    comp_ext_data: CompilerExtData::threshold(k, n, |i| Ok(sub_ext_data[i]))
+                        .expect("threshold subs, which we just compiled, typeck"),
+                };
+                insert_wrap!(ast_ext);
+            }
+
+            let mut key_vec: Vec<Pk> = subs
                 .iter()
                 .filter_map(|s| {
                     if let Concrete::Key(ref pk) = *s {
@@ -1026,6 +1446,9 @@ where
                     }
                 })
                 .collect();
+            if policy_cache.config.sort_multi_keys {
+                key_vec.sort();
+            }
 
             match Ctx::sig_type() {
                 SigType::Schnorr if key_vec.len() == subs.len() => {
@@ -1061,7 +1484,9 @@ where
         // before calling this compile function
         Err(CompilerError::LimitsExceeded)
     } else {
-        policy_cache.insert((policy.clone(), ord_sat_prob, ord_dissat_prob), ret.clone());
+        policy_cache
+            .compilations
+            .insert((policy.clone(), ord_sat_prob, ord_dissat_prob), ret.clone());
         Ok(ret)
     }
 }
@@ -1070,12 +1495,12 @@ where
 /// `sat_prob` and `dissat_prob` represent the sat and dissat probabilities of
 /// root or. `weights` represent the odds for taking each sub branch
 #[allow(clippy::too_many_arguments)]
-fn compile_binary<Pk, Ctx, F>(
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
-    ret: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    left_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    right_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
+fn compile_binary<Pk, Ctx, Ext, F>(
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
+    ret: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    left_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    right_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
     weights: [f64; 2],
     sat_prob: f64,
     dissat_prob: Option<f64>,
@@ -1084,7 +1509,8 @@ fn compile_binary<Pk, Ctx, F>(
 where
     Pk: MiniscriptKey,
     Ctx: ScriptContext,
-    F: Fn(Arc<Miniscript<Pk, Ctx>>, Arc<Miniscript<Pk, Ctx>>) -> Terminal<Pk, Ctx>,
+    Ext: Extension,
+    F: Fn(Arc<Miniscript<Pk, Ctx, Ext>>, Arc<Miniscript<Pk, Ctx, Ext>>) -> Terminal<Pk, Ctx, Ext>,
 {
     for l in left_comp.values_mut() {
         let lref = Arc::clone(&l.ms);
@@ -1105,13 +1531,13 @@ where
 /// `sat_prob` and `dissat_prob` represent the sat and dissat probabilities of
 /// root and_or node. `weights` represent the odds for taking each sub branch
 #[allow(clippy::too_many_arguments)]
-fn compile_tern<Pk: MiniscriptKey, Ctx: ScriptContext>(
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
-    ret: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    a_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    b_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
-    c_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx>>,
+fn compile_tern<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
+    ret: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    a_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    b_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
+    c_comp: &mut BTreeMap<CompilationKey, AstElemExt<Pk, Ctx, Ext>>,
     weights: [f64; 2],
     sat_prob: f64,
     dissat_prob: Option<f64>,
@@ -1136,11 +1562,167 @@ fn compile_tern<Pk: MiniscriptKey, Ctx: ScriptContext>(
 }
 
 /// Obtain the best compilation of for p=1.0 and q=0
-pub fn best_compilation<Pk: MiniscriptKey, Ctx: ScriptContext>(
-    policy: &Concrete<Pk>,
-) -> Result<Miniscript<Pk, Ctx>, CompilerError> {
-    let mut policy_cache = PolicyCache::<Pk, Ctx>::new();
-    let x = &*best_t(&mut policy_cache, policy, 1.0, None)?.ms;
+///
+/// Compilations exceeding the consensus and standardness limits of `Ctx` are
+/// discarded during the search. When this leaves no compilation of the policy,
+/// the search is retried keeping, for every (sub)policy, the compilations
+/// which use fewer resources than the cheaper ones, and the cheapest
+/// compilation within the limits is returned.
+pub fn best_compilation<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy: &Concrete<Pk, Ext>,
+) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+    best_compilation_with_config(policy, &CompilerConfig::default())
+}
+
+/// Obtain the best compilation of for p=1.0 and q=0 under the cost model
+/// `config`, see [`best_compilation`]
+pub fn best_compilation_with_config<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy: &Concrete<Pk, Ext>,
+    config: &CompilerConfig,
+) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+    CompilationCache::new(config)?.best_compilation(policy)
+}
+
+/// Compilations of sub-policies shared between the compilations of several
+/// policies under the same cost model, such as the leaves of a TapTree.
+///
+/// The compilations kept for a sub-policy only depend on the sub-policy, its
+/// sat and dissat probabilities and the cost model, so that compiling a policy
+/// with a shared cache gives the same result as [`best_compilation_with_config`].
+pub struct CompilationCache<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> {
+    cache: PolicyCache<Pk, Ctx, Ext>,
+    /// The cache of the searches retried within the resource limits of `Ctx`
+    resource_aware_cache: PolicyCache<Pk, Ctx, Ext>,
+}
+
+impl<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension> CompilationCache<Pk, Ctx, Ext> {
+    /// Create an empty cache for compilations under the cost model `config`
+    pub fn new(config: &CompilerConfig) -> Result<Self, CompilerError> {
+        config.sanity_check()?;
+        Ok(CompilationCache {
+            cache: PolicyCache::new(*config, false),
+            resource_aware_cache: PolicyCache::new(*config, true),
+        })
+    }
+
+    /// Obtain the best compilation of `policy`, see [`best_compilation`],
+    /// reusing the compilations of the sub-policies compiled before
+    pub fn best_compilation(
+        &mut self,
+        policy: &Concrete<Pk, Ext>,
+    ) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+        let mut best = best_t(&mut self.cache, policy, 1.0, None);
+        if let Err(CompilerError::LimitsExceeded) = best {
+            best = best_t(&mut self.resource_aware_cache, policy, 1.0, None);
+        }
+        best.and_then(top_level)
+    }
+}
+
+/// Explain the compilation of a policy under the cost model `config`.
+///
+/// Along with the result of [`best_compilation_with_config`], lists for every
+/// policy node and every sat and dissat probabilities it was compiled with,
+/// the `n` cheapest compilations kept by the compiler and the `n` cheapest
+/// ones it rejected, with the reason of their rejection. At the top level,
+/// the kept compilations which are not safe are listed as rejected.
+pub fn explain_compilation<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy: &Concrete<Pk, Ext>,
+    config: &CompilerConfig,
+    n: usize,
+) -> Explanation<Pk, Ctx, Ext> {
+    if let Err(e) = config.sanity_check() {
+        return Explanation {
+            result: Err(e),
+            nodes: vec![],
+        };
+    }
+    let (result, cache) = search(policy, config);
+    let rejections = cache.rejections.as_ref().expect("rejections are recorded");
+
+    let mut subs = vec![];
+    pre_order(policy, &mut subs);
+    let mut nodes = vec![];
+    for sub in subs {
+        let mut probs: Vec<(OrdF64, Option<OrdF64>)> = cache
+            .compilations
+            .keys()
+            .chain(rejections.keys())
+            .filter(|key| key.0 == *sub)
+            .map(|key| (key.1, key.2))
+            .collect();
+        probs.sort();
+        probs.dedup();
+
+        for (sat_prob, dissat_prob) in probs {
+            let key = (sub.clone(), sat_prob, dissat_prob);
+            let (sat_prob, dissat_prob) = (sat_prob.0, dissat_prob.map(|p| p.0));
+            let candidate = |elem: &AstElemExt<Pk, Ctx, Ext>, rejection| Candidate {
+                ms: Arc::clone(&elem.ms),
+                cost: elem.cost_1d(config, sat_prob, dissat_prob),
+                rejection,
+            };
+            let is_root = sub == policy && sat_prob == 1.0 && dissat_prob.is_none();
+
+            let mut candidates = vec![];
+            let mut rejected = vec![];
+            for elem in cache
+                .compilations
+                .get(&key)
+                .into_iter()
+                .flat_map(|map| map.values())
+            {
+                if is_root && elem.ms.ty.corr.base == types::Base::B && !elem.ms.ty.mall.safe {
+                    rejected.push(candidate(elem, Some(RejectionReason::NonSafe)));
+                } else {
+                    candidates.push(candidate(elem, None));
+                }
+            }
+            for (elem, reason) in rejections.get(&key).into_iter().flatten() {
+                rejected.push(candidate(elem, Some(reason.clone())));
+            }
+            nodes.push(NodeExplanation {
+                policy: sub.clone(),
+                sat_prob,
+                dissat_prob,
+                candidates: cheapest(candidates, n),
+                rejected: cheapest(rejected, n),
+            });
+        }
+    }
+    Explanation { result, nodes }
+}
+
+/// Run the search of [`best_compilation_with_config`] recording the rejected
+/// compilations, returning the cache of the search which found the best
+/// compilation or failed
+#[allow(clippy::type_complexity)]
+fn search<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    policy: &Concrete<Pk, Ext>,
+    config: &CompilerConfig,
+) -> (
+    Result<Miniscript<Pk, Ctx, Ext>, CompilerError>,
+    PolicyCache<Pk, Ctx, Ext>,
+) {
+    let new_cache = |resource_aware| {
+        let mut cache = PolicyCache::<Pk, Ctx, Ext>::new(*config, resource_aware);
+        cache.rejections = Some(BTreeMap::new());
+        cache
+    };
+    let mut cache = new_cache(false);
+    let mut best = best_t(&mut cache, policy, 1.0, None);
+    if let Err(CompilerError::LimitsExceeded) = best {
+        cache = new_cache(true);
+        best = best_t(&mut cache, policy, 1.0, None);
+    }
+    (best.and_then(top_level), cache)
+}
+
+/// Check that the best compilation of a policy can be used at the top level
+fn top_level<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    best: AstElemExt<Pk, Ctx, Ext>,
+) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+    let x = &*best.ms;
     if !x.ty.mall.safe {
         Err(CompilerError::TopLevelNonSafe)
     } else if !x.ty.mall.non_malleable {
@@ -1150,39 +1732,79 @@ pub fn best_compilation<Pk: MiniscriptKey, Ctx: ScriptContext>(
     }
 }
 
+/// Collect the distinct nodes of a policy in pre-order
+fn pre_order<'a, Pk: MiniscriptKey, Ext: Extension>(
+    policy: &'a Concrete<Pk, Ext>,
+    ret: &mut Vec<&'a Concrete<Pk, Ext>>,
+) {
+    if ret.contains(&policy) {
+        return;
+    }
+    ret.push(policy);
+    match *policy {
+        Concrete::And(ref subs) | Concrete::Threshold(_, ref subs) => {
+            subs.iter().for_each(|sub| pre_order(sub, ret))
+        }
+        Concrete::Or(ref subs) => subs.iter().for_each(|(_, sub)| pre_order(sub, ret)),
+        _ => {}
+    }
+}
+
+/// The `n` cheapest distinct candidates, by increasing cost
+fn cheapest<Pk: MiniscriptKey, Ctx: ScriptContext, Ext: Extension>(
+    mut candidates: Vec<Candidate<Pk, Ctx, Ext>>,
+    n: usize,
+) -> Vec<Candidate<Pk, Ctx, Ext>> {
+    candidates.sort_by_key(|c| OrdF64(c.cost));
+    let mut ret: Vec<Candidate<Pk, Ctx, Ext>> = Vec::with_capacity(n);
+    for c in candidates {
+        if ret.len() == n {
+            break;
+        }
+        if !ret.iter().any(|r| r.ms == c.ms) {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
 /// Obtain the best B expression with given sat and dissat
-fn best_t<Pk, Ctx>(
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
+fn best_t<Pk, Ctx, Ext>(
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
-) -> Result<AstElemExt<Pk, Ctx>, CompilerError>
+) -> Result<AstElemExt<Pk, Ctx, Ext>, CompilerError>
 where
     Pk: MiniscriptKey,
     Ctx: ScriptContext,
+    Ext: Extension,
 {
+    let config = policy_cache.config;
     best_compilations(policy_cache, policy, sat_prob, dissat_prob)?
         .into_iter()
         .filter(|&(key, _)| {
             key.ty.corr.base == types::Base::B && key.dissat_prob == dissat_prob.map(OrdF64)
         })
         .map(|(_, val)| val)
-        .min_by_key(|ext| OrdF64(ext.cost_1d(sat_prob, dissat_prob)))
+        .min_by_key(|ext| OrdF64(ext.cost_1d(&config, sat_prob, dissat_prob)))
         .ok_or(CompilerError::LimitsExceeded)
 }
 
 /// Obtain the <basic-type>.deu (e.g. W.deu, B.deu) expression with the given sat and dissat
-fn best<Pk, Ctx>(
+fn best<Pk, Ctx, Ext>(
     basic_type: types::Base,
-    policy_cache: &mut PolicyCache<Pk, Ctx>,
-    policy: &Concrete<Pk>,
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
     sat_prob: f64,
     dissat_prob: Option<f64>,
-) -> Result<AstElemExt<Pk, Ctx>, CompilerError>
+) -> Result<AstElemExt<Pk, Ctx, Ext>, CompilerError>
 where
     Pk: MiniscriptKey,
     Ctx: ScriptContext,
+    Ext: Extension,
 {
+    let config = policy_cache.config;
     best_compilations(policy_cache, policy, sat_prob, dissat_prob)?
         .into_iter()
         .filter(|(key, val)| {
@@ -1192,7 +1814,40 @@ where
                 && key.dissat_prob == dissat_prob.map(OrdF64)
         })
         .map(|(_, val)| val)
-        .min_by_key(|ext| OrdF64(ext.cost_1d(sat_prob, dissat_prob)))
+        .min_by_key(|ext| OrdF64(ext.cost_1d(&config, sat_prob, dissat_prob)))
+        .ok_or(CompilerError::LimitsExceeded)
+}
+
+/// Obtain the <basic-type>.deu expression with the smallest script among the
+/// compilations with given sat and dissat, preferring the cheapest ones
+fn smallest<Pk, Ctx, Ext>(
+    basic_type: types::Base,
+    policy_cache: &mut PolicyCache<Pk, Ctx, Ext>,
+    policy: &Concrete<Pk, Ext>,
+    sat_prob: f64,
+    dissat_prob: Option<f64>,
+) -> Result<AstElemExt<Pk, Ctx, Ext>, CompilerError>
+where
+    Pk: MiniscriptKey,
+    Ctx: ScriptContext,
+    Ext: Extension,
+{
+    let config = policy_cache.config;
+    best_compilations(policy_cache, policy, sat_prob, dissat_prob)?
+        .into_iter()
+        .filter(|(key, val)| {
+            key.ty.corr.base == basic_type
+                && key.ty.corr.unit
+                && val.ms.ty.mall.dissat == types::Dissat::Unique
+                && key.dissat_prob == dissat_prob.map(OrdF64)
+        })
+        .map(|(_, val)| val)
+        .min_by_key(|ext| {
+            (
+                ext.ms.ext.pk_cost,
+                OrdF64(ext.cost_1d(&config, sat_prob, dissat_prob)),
+            )
+        })
         .ok_or(CompilerError::LimitsExceeded)
 }
 
@@ -1206,13 +1861,15 @@ mod tests {
     use elements::{self, hashes, opcodes, script, secp256k1_zkp, Sequence};
 
     use super::*;
+    use crate::extensions::CovExtArgs;
     use crate::miniscript::{Legacy, Segwitv0, Tap};
     use crate::policy::Liftable;
-    use crate::{script_num_size, ElementsSig, ToPublicKey};
+    use crate::{script_num_size, CovenantExt, Descriptor, ElementsSig, NoExt, ToPublicKey};
 
     type SPolicy = Concrete<String>;
     type BPolicy = Concrete<bitcoin::PublicKey>;
-    type TapAstElemExt = policy::compiler::AstElemExt<String, Tap>;
+    type TapAstElemExt = policy::compiler::AstElemExt<String, Tap, NoExt>;
+    type SegwitAstElemExt = policy::compiler::AstElemExt<String, Segwitv0, NoExt>;
     type SegwitMiniScript = Miniscript<bitcoin::PublicKey, Segwitv0>;
 
     fn pubkeys_and_a_sig(n: usize) -> (Vec<bitcoin::PublicKey>, secp256k1_zkp::ecdsa::Signature) {
@@ -1302,9 +1959,18 @@ mod tests {
     #[test]
     fn compile_q() {
         let policy = SPolicy::from_str("or(1@and(pk(A),pk(B)),127@pk(C))").expect("parsing");
-        let compilation: TapAstElemExt = best_t(&mut BTreeMap::new(), &policy, 1.0, None).unwrap();
+        let compilation: TapAstElemExt = best_t(
+            &mut PolicyCache::new(CompilerConfig::default(), false),
+            &policy,
+            1.0,
+            None,
+        )
+        .unwrap();
 
-        assert_eq!(compilation.cost_1d(1.0, None), 87.0 + 67.0390625);
+        assert_eq!(
+            compilation.cost_1d(&CompilerConfig::default(), 1.0, None),
+            87.0 + 67.0390625
+        );
         assert_eq!(
             policy.lift().unwrap().sorted(),
             compilation.ms.lift().unwrap().sorted()
@@ -1314,9 +1980,18 @@ mod tests {
         let policy = SPolicy::from_str(
                 "and(and(and(or(127@thresh(2,pk(A),pk(B),thresh(2,or(127@pk(A),1@pk(B)),after(100),or(and(pk(C),after(200)),and(pk(D),sha256(66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925))),pk(E))),1@pk(F)),sha256(66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925)),or(127@pk(G),1@after(300))),or(127@after(400),pk(H)))"
             ).expect("parsing");
-        let compilation: TapAstElemExt = best_t(&mut BTreeMap::new(), &policy, 1.0, None).unwrap();
+        let compilation: TapAstElemExt = best_t(
+            &mut PolicyCache::new(CompilerConfig::default(), false),
+            &policy,
+            1.0,
+            None,
+        )
+        .unwrap();
 
-        assert_eq!(compilation.cost_1d(1.0, None), 433.0 + 275.7909749348958);
+        assert_eq!(
+            compilation.cost_1d(&CompilerConfig::default(), 1.0, None),
+            433.0 + 275.7909749348958
+        );
         assert_eq!(
             policy.lift().unwrap().sorted(),
             compilation.ms.lift().unwrap().sorted()
@@ -1598,6 +2273,224 @@ mod tests {
         );
     }
 
+    #[test]
+    fn compile_resource_aware() {
+        let small = Resources {
+            script_size: 35,
+            ops: 1,
+            sat_stack_elems: 1,
+            sat_size: 73,
+            exec_stack_elems: 2,
+        };
+        let big = Resources {
+            script_size: 70,
+            ..small
+        };
+        assert!(small.fits_in(big));
+        assert!(!big.fits_in(small));
+
+        // A more expensive compilation using fewer resources is not discarded
+        let key = CompilationKey::from_type(types::Type::from_false(), false, None);
+        let small_key = CompilationKey {
+            resources: Some(small),
+            ..key
+        };
+        let big_key = CompilationKey {
+            resources: Some(big),
+            ..key
+        };
+        assert!(small_key.is_subtype(big_key));
+        assert!(!big_key.is_subtype(small_key));
+        assert!(key.is_subtype(key));
+
+        // The resource-aware search finds compilations at least as cheap, which
+        // are within the limits
+        for s in &[
+            "or(1@and(pk(A),pk(B)),127@pk(C))",
+            "thresh(2,pk(A),or(pk(B),and(pk(C),older(144))),and(pk(D),sha256(66687aadf862bd776c8fc18b8e9f8e20089714856ee233b3902a591d0d5f2925)))",
+        ] {
+            let policy = SPolicy::from_str(s).expect("parsing");
+            let config = CompilerConfig::default();
+            let normal: SegwitAstElemExt =
+                best_t(&mut PolicyCache::new(config, false), &policy, 1.0, None).unwrap();
+            let aware: SegwitAstElemExt =
+                best_t(&mut PolicyCache::new(config, true), &policy, 1.0, None).unwrap();
+            assert!(aware.cost_1d(&config, 1.0, None) <= normal.cost_1d(&config, 1.0, None));
+            assert!(Segwitv0::check_local_validity(&aware.ms).is_ok());
+            assert_eq!(
+                policy.lift().unwrap().sorted(),
+                aware.ms.lift().unwrap().sorted()
+            );
+        }
+
+        // The cheapest compilation of the first branch, or_d, executes both
+        // multisigs when dissatisfying it, which exceeds the op count limit
+        // along with the timelocks, while or_i only executes one of them
+        let (keys, _) = pubkeys_and_a_sig(40);
+        let multi = |keys: &[bitcoin::PublicKey]| {
+            let pks: Vec<String> = keys.iter().map(|pk| format!("pk({})", pk)).collect();
+            format!("thresh(1,{})", pks.join(","))
+        };
+        let mut timelocks = "older(1)".to_owned();
+        for i in 2..=83 {
+            timelocks = format!("and(older({}),{})", i, timelocks);
+        }
+        let policy = BPolicy::from_str(&format!(
+            "and(or(127@{},1@{}),{})",
+            multi(&keys[..20]),
+            multi(&keys[20..]),
+            timelocks
+        ))
+        .expect("parsing");
+        let normal = best_t::<_, Segwitv0, NoExt>(
+            &mut PolicyCache::new(CompilerConfig::default(), false),
+            &policy,
+            1.0,
+            None,
+        );
+        assert!(matches!(normal, Err(CompilerError::LimitsExceeded)));
+        let ms: SegwitMiniScript = best_compilation(&policy).unwrap();
+        ms.sanity_check().unwrap();
+        assert!(Segwitv0::check_local_validity(&ms).is_ok());
+        assert_eq!(
+            policy.lift().unwrap().normalized().sorted(),
+            ms.lift().unwrap().normalized().sorted()
+        );
+
+        // The number of compilations of a type kept by the resource-aware
+        // search is bounded
+        let mut cache = PolicyCache::<_, Segwitv0, NoExt>::new(CompilerConfig::default(), true);
+        best_t(&mut cache, &policy, 1.0, None).unwrap();
+        for map in cache.compilations.values() {
+            for key in map.keys() {
+                let same_type = map.keys().filter(|k| {
+                    k.ty == key.ty
+                        && k.expensive_verify == key.expensive_verify
+                        && k.dissat_prob == key.dissat_prob
+                });
+                assert!(same_type.count() <= MAX_RESOURCE_AWARE_COMPILATIONS);
+            }
+        }
+    }
+
+    #[test]
+    fn compile_config() {
+        let policy = SPolicy::from_str("or(3@and(pk(A),pk(B)),1@thresh(2,pk(C),pk(D),pk(E)))")
+            .expect("parsing");
+        let default_ms: Miniscript<String, Segwitv0> = policy.compile().unwrap();
+
+        // Scaling the cost model does not change the compilation, only its cost
+        let config = CompilerConfig {
+            script_size_weight: 2.0,
+            sat_weight: 2.0,
+            dissat_weight: 2.0,
+            ..CompilerConfig::default()
+        };
+        let ms: Miniscript<String, Segwitv0> = policy.compile_with_config(&config).unwrap();
+        assert_eq!(ms, default_ms);
+        let default_cost = best_t::<_, Segwitv0, NoExt>(
+            &mut PolicyCache::new(CompilerConfig::default(), false),
+            &policy,
+            1.0,
+            None,
+        )
+        .unwrap()
+        .cost_1d(&CompilerConfig::default(), 1.0, None);
+        let cost =
+            best_t::<_, Segwitv0, NoExt>(&mut PolicyCache::new(config, false), &policy, 1.0, None)
+                .unwrap()
+                .cost_1d(&config, 1.0, None);
+        assert_eq!(cost, 2.0 * default_cost);
+
+        let config = CompilerConfig {
+            sat_weight: -1.0,
+            ..CompilerConfig::default()
+        };
+        assert_eq!(
+            policy.compile_with_config::<Segwitv0>(&config),
+            Err(CompilerError::InvalidConfig)
+        );
+
+        // A zero weight ignores infinite costs instead of making them NaN
+        assert_eq!(weighted(0.0, f64::INFINITY), 0.0);
+        assert_eq!(weighted(1.0, f64::INFINITY), f64::INFINITY);
+        let config = CompilerConfig {
+            sat_weight: 0.0,
+            dissat_weight: 0.0,
+            ..CompilerConfig::default()
+        };
+        assert!(policy.compile_with_config::<Segwitv0>(&config).is_ok());
+
+        // Without key path preference, the unspendable key is the internal key
+        let policy = SPolicy::from_str("or(pk(A),pk(B))").expect("parsing");
+        let config = CompilerConfig {
+            prefer_key_path: false,
+            ..CompilerConfig::default()
+        };
+        for (config, expected_key) in &[(CompilerConfig::default(), "A"), (config, "UNSPENDABLE")] {
+            let desc = policy
+                .compile_tr_with_config(Some("UNSPENDABLE".to_owned()), config)
+                .unwrap();
+            match desc {
+                Descriptor::Tr(ref tr) => assert_eq!(tr.internal_key(), expected_key),
+                _ => panic!("compile_tr_with_config must produce a Tr descriptor"),
+            }
+        }
+    }
+
+    #[test]
+    fn compile_explain() {
+        let policy = SPolicy::from_str("or(3@pk(A),1@and(pk(B),older(144)))").expect("parsing");
+        let config = CompilerConfig::default();
+        let explanation = policy.explain_compile::<Segwitv0>(&config, 3);
+        let ms = policy.compile::<Segwitv0>();
+        assert_eq!(explanation.result, ms);
+
+        // Every policy node is explained, starting with the root
+        let root = &explanation.nodes[0];
+        assert_eq!(root.policy, policy);
+        assert_eq!((root.sat_prob, root.dissat_prob), (1.0, None));
+        assert!(root.candidates.iter().any(|c| *c.ms == ms.clone().unwrap()));
+        for sub in &["pk(A)", "pk(B)", "older(144)", "and(pk(B),older(144))"] {
+            let sub = SPolicy::from_str(sub).unwrap();
+            assert!(explanation.nodes.iter().any(|node| node.policy == sub));
+        }
+        for node in &explanation.nodes {
+            assert!(node.candidates.len() <= 3 && node.rejected.len() <= 3);
+            assert!(node.candidates.iter().all(|c| c.rejection.is_none()));
+            assert!(node.rejected.iter().all(|c| c.rejection.is_some()));
+            assert!(node.candidates.windows(2).all(|w| w[0].cost <= w[1].cost));
+            assert!(node.rejected.windows(2).all(|w| w[0].cost <= w[1].cost));
+        }
+        assert!(explanation
+            .nodes
+            .iter()
+            .any(|node| !node.rejected.is_empty()));
+
+        // Unsafe top level compilations are rejected
+        let policy = SPolicy::from_str("and(older(144),after(1000))").expect("parsing");
+        let explanation = explain_compilation::<_, Segwitv0, NoExt>(&policy, &config, 10);
+        assert_eq!(explanation.result, Err(CompilerError::TopLevelNonSafe));
+        assert!(explanation.nodes[0]
+            .rejected
+            .iter()
+            .any(|c| c.rejection == Some(RejectionReason::NonSafe)));
+        assert_eq!(
+            policy.explain_compile::<Segwitv0>(&config, 10).result,
+            Err(CompilerError::TopLevelNonSafe)
+        );
+
+        let config = CompilerConfig {
+            sat_weight: -1.0,
+            ..CompilerConfig::default()
+        };
+        let explanation = policy.explain_compile::<Segwitv0>(&config, 10);
+        assert_eq!(explanation.result, Err(CompilerError::TopLevelNonSafe));
+        let explanation = explain_compilation::<_, Segwitv0, NoExt>(&policy, &config, 10);
+        assert_eq!(explanation.result, Err(CompilerError::InvalidConfig));
+        assert!(explanation.nodes.is_empty());
+    }
+
     #[test]
     fn compile_tr_thresh() {
         for k in 1..4 {
@@ -1607,6 +2500,116 @@ mod tests {
             let small_thresh_ms_expected: Miniscript<String, Tap> = ms_str!("multi_a({},B,C,D)", k);
             assert_eq!(small_thresh_ms, small_thresh_ms_expected);
         }
+
+        // Keys are emitted in policy order unless sorting is requested
+        let policy: Concrete<String> = policy_str!("thresh(2,pk(D),pk(B),pk(C))");
+        let ms: Miniscript<String, Tap> = policy.compile().unwrap();
+        assert_eq!(ms, ms_str!("multi_a(2,D,B,C)"));
+        let config = CompilerConfig {
+            sort_multi_keys: true,
+            ..CompilerConfig::default()
+        };
+        let ms: Miniscript<String, Tap> = policy.compile_with_config(&config).unwrap();
+        assert_eq!(ms, ms_str!("multi_a(2,B,C,D)"));
+    }
+
+    #[test]
+    fn compile_ext() {
+        type ExtPolicy = Concrete<String, CovenantExt<CovExtArgs>>;
+
+        let policy = ExtPolicy::from_str("and(pk(A),ver_eq(2))").unwrap();
+        let ms: Miniscript<String, Segwitv0, CovenantExt<CovExtArgs>> = policy.compile().unwrap();
+        assert_eq!(policy.lift().unwrap().sorted(), ms.lift().unwrap().sorted());
+
+        // The extension on its own does not require a signature
+        let policy = ExtPolicy::from_str("ver_eq(2)").unwrap();
+        assert_eq!(
+            policy.compile::<Segwitv0>(),
+            Err(CompilerError::TopLevelNonSafe)
+        );
+
+        let policy = ExtPolicy::from_str("or(pk(A),and(pk(B),ver_eq(2)))").unwrap();
+        let desc = policy.compile_tr_ext(None).unwrap();
+        match desc {
+            Descriptor::TrExt(ref tr) => assert_eq!(tr.internal_key(), "A"),
+            _ => panic!("Expected a taproot descriptor with extensions"),
+        }
+        assert_eq!(
+            policy.lift().unwrap().sorted(),
+            desc.lift().unwrap().sorted()
+        );
+    }
+
+    #[test]
+    fn compile_cov() {
+        type ExtPolicy = Concrete<String, CovenantExt<CovExtArgs>>;
+
+        let policy = ExtPolicy::from_str("or(pk(B),and(pk(C),ver_eq(2)))").unwrap();
+        let desc = policy.compile_cov("A".to_owned()).unwrap();
+        desc.sanity_check().unwrap();
+        let cov = desc.as_cov().unwrap();
+        assert_eq!(cov.pk(), "A");
+        assert_eq!(*cov.to_ms(), policy.compile::<Segwitv0>().unwrap());
+
+        // The covenant key does not make an unsafe policy compilable
+        let policy = ExtPolicy::from_str("ver_eq(2)").unwrap();
+        assert_eq!(
+            policy.compile_cov("A".to_owned()),
+            Err(crate::Error::CompilerError(CompilerError::TopLevelNonSafe))
+        );
+
+        // Policies without extensions can also target covenant descriptors
+        let policy = SPolicy::from_str("or(pk(B),pk(C))").unwrap();
+        let desc = policy
+            .compile_to_descriptor::<Segwitv0>(policy::concrete::DescriptorCtx::Cov("A".to_owned()))
+            .unwrap();
+        match desc {
+            Descriptor::LegacyCSFSCov(ref cov) => {
+                assert_eq!(cov.pk(), "A");
+                assert_eq!(*cov.to_ms(), policy.compile::<Segwitv0>().unwrap());
+            }
+            _ => panic!("Expected a covenant descriptor"),
+        }
+    }
+
+    #[test]
+    fn compilation_cache() {
+        let config = CompilerConfig::default();
+        let mut cache = CompilationCache::<String, Tap, NoExt>::new(&config).unwrap();
+        // Policies sharing sub-policies compile as with a fresh cache, in any order
+        let policies = [
+            "and(pk(A),or(pk(B),and(pk(C),older(144))))",
+            "or(pk(B),and(pk(C),older(144)))",
+            "thresh(2,pk(A),or(pk(B),and(pk(C),older(144))),pk(D))",
+            "and(pk(A),or(pk(B),and(pk(C),older(144))))",
+            "thresh(3,pk(A),pk(B),pk(C),pk(D),pk(E))",
+            "and(after(100),older(144))",
+        ];
+        for s in policies.iter().chain(policies.iter().rev()) {
+            let policy = SPolicy::from_str(s).expect("parsing");
+            assert_eq!(
+                cache.best_compilation(&policy),
+                best_compilation_with_config(&policy, &config)
+            );
+        }
+        assert!(!cache.cache.compilations.is_empty());
+
+        let policy = SPolicy::from_str(policies[2]).expect("parsing");
+        assert_eq!(
+            policy.compile_with_cache(&mut cache),
+            policy.compile::<Tap>()
+        );
+        let policy = SPolicy::from_str("after(100)").expect("parsing");
+        assert_eq!(
+            policy.compile_with_cache(&mut cache),
+            Err(CompilerError::TopLevelNonSafe)
+        );
+
+        let config = CompilerConfig {
+            sat_weight: -1.0,
+            ..config
+        };
+        assert!(CompilationCache::<String, Tap, NoExt>::new(&config).is_err());
     }
 }
 
@@ -1616,9 +2619,20 @@ mod benches {
 
     use test::{black_box, Bencher};
 
-    use super::{CompilerError, Concrete};
-    use crate::{Miniscript, Tap};
+    use super::{CompilationCache, CompilerConfig, CompilerError, Concrete};
+    use crate::{Miniscript, NoExt, Tap};
     type TapMsRes = Result<Miniscript<String, Tap>, CompilerError>;
+    const XLARGE_INNER: &str = "thresh(4,pk(B),older(100),pk(C),and(after(100),or(pk(D),or(pk(E),and(pk(F),thresh(2,pk(G),or(pk(H),and(thresh(5,pk(I),or(pk(J),pk(K)),pk(L),pk(M),pk(N),pk(O),pk(P),pk(Q),pk(R),pk(S),pk(T)),pk(U))),pk(V),or(and(pk(W),pk(X)),pk(Y)),after(100)))))),pk(Z))";
+
+    /// Variants of the policy of `compile_xlarge` sharing its threshold
+    fn xlarge_variants() -> Vec<Concrete<String>> {
+        (0..4)
+            .map(|i| {
+                Concrete::<String>::from_str(&format!("or(pk(A{}),{})", i, XLARGE_INNER))
+                    .expect("parsing")
+            })
+            .collect()
+    }
     #[bench]
     pub fn compile_basic(bh: &mut Bencher) {
         let h = (0..64).map(|_| "a").collect::<String>();
@@ -1655,4 +2669,28 @@ mod benches {
             black_box(pt).unwrap();
         });
     }
+
+    #[bench]
+    pub fn compile_xlarge_variants(bh: &mut Bencher) {
+        let pols = xlarge_variants();
+        bh.iter(|| {
+            for pol in &pols {
+                let pt: TapMsRes = pol.compile();
+                black_box(pt).unwrap();
+            }
+        });
+    }
+
+    #[bench]
+    pub fn compile_xlarge_variants_cached(bh: &mut Bencher) {
+        let pols = xlarge_variants();
+        bh.iter(|| {
+            let config = CompilerConfig::default();
+            let mut cache = CompilationCache::<String, Tap, NoExt>::new(&config).unwrap();
+            for pol in &pols {
+                let pt: TapMsRes = pol.compile_with_cache(&mut cache);
+                black_box(pt).unwrap();
+            }
+        });
+    }
 }
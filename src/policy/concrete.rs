@@ -10,26 +10,29 @@ use std::{error, fmt, str};
 use elements::{LockTime, Sequence};
 #[cfg(feature = "compiler")]
 use {
-    crate::descriptor::TapTree,
+    crate::descriptor::{LegacyCSFSCov, TapLeafScript, TapTree},
     crate::miniscript::ScriptContext,
+    crate::policy::compiler::CompilationCache,
+    crate::policy::compiler::CompilerConfig,
     crate::policy::compiler::CompilerError,
     crate::policy::compiler::OrdF64,
     crate::policy::{compiler, Concrete, Liftable, Semantic},
     crate::Descriptor,
     crate::Miniscript,
-    crate::NoExt,
-    crate::Tap,
+    crate::{Segwitv0, Tap},
+    elements::taproot::TAPROOT_CONTROL_MAX_NODE_COUNT,
     std::cmp::Reverse,
     std::collections::{BTreeSet, BinaryHeap, HashMap},
     std::sync::Arc,
+    std::{panic, thread},
 };
 
-use super::ENTAILMENT_MAX_TERMINALS;
+use super::{ENTAILMENT_MAX_TERMINALS, SATISFYING_SETS_MAX};
 use crate::expression::{self, FromTree};
 use crate::miniscript::types::extra_props::TimelockInfo;
 #[cfg(all(doc, not(feature = "compiler")))]
 use crate::Descriptor;
-use crate::{errstr, AbsLockTime, Error, ForEachKey, MiniscriptKey, Translator};
+use crate::{errstr, AbsLockTime, Error, Extension, ForEachKey, MiniscriptKey, NoExt, Translator};
 
 /// Maximum TapLeafs allowed in a compiled TapTree
 #[cfg(feature = "compiler")]
@@ -39,7 +42,7 @@ const MAX_COMPILATION_LEAVES: usize = 1024;
 /// and whose disjunctions are annotated with satisfaction probabilities
 /// to assist the compiler
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub enum Policy<Pk: MiniscriptKey> {
+pub enum Policy<Pk: MiniscriptKey, Ext: Extension = NoExt> {
     /// Unsatisfiable
     Unsatisfiable,
     /// Trivially satisfiable
@@ -58,28 +61,33 @@ pub enum Policy<Pk: MiniscriptKey> {
     Ripemd160(Pk::Ripemd160),
     /// A HASH160 whose preimage must be provided to satisfy the descriptor
     Hash160(Pk::Hash160),
+    /// An extension fragment, compiled directly into a [`Terminal::Ext`]
+    ///
+    /// [`Terminal::Ext`]: crate::Terminal::Ext
+    Ext(Ext),
     /// A list of sub-policies, all of which must be satisfied
-    And(Vec<Policy<Pk>>),
+    And(Vec<Policy<Pk, Ext>>),
     /// A list of sub-policies, one of which must be satisfied, along with
     /// relative probabilities for each one
-    Or(Vec<(usize, Policy<Pk>)>),
+    Or(Vec<(usize, Policy<Pk, Ext>)>),
     /// A set of descriptors, satisfactions must be provided for `k` of them
-    Threshold(usize, Vec<Policy<Pk>>),
+    Threshold(usize, Vec<Policy<Pk, Ext>>),
 }
 
-impl<Pk> Policy<Pk>
+impl<Pk, Ext> Policy<Pk, Ext>
 where
     Pk: MiniscriptKey,
+    Ext: Extension,
 {
     /// Construct a `Policy::After` from `n`. Helper function equivalent to
     /// `Policy::After(LockTime::from(LockTime::from_consensus(n)))`.
-    pub fn after(n: u32) -> Policy<Pk> {
+    pub fn after(n: u32) -> Policy<Pk, Ext> {
         Policy::After(AbsLockTime::from(LockTime::from_consensus(n)))
     }
 
     /// Construct a `Policy::Older` from `n`. Helper function equivalent to
     /// `Policy::Older(Sequence::from_consensus(n))`.
-    pub fn older(n: u32) -> Policy<Pk> {
+    pub fn older(n: u32) -> Policy<Pk, Ext> {
         Policy::Older(Sequence::from_consensus(n))
     }
 }
@@ -163,6 +171,7 @@ impl<Pk: MiniscriptKey> From<Policy<Pk>> for PolicyArc<Pk> {
             Policy::Hash256(hash) => PolicyArc::Hash256(hash),
             Policy::Ripemd160(hash) => PolicyArc::Ripemd160(hash),
             Policy::Hash160(hash) => PolicyArc::Hash160(hash),
+            Policy::Ext(e) => match e {},
             Policy::And(subs) => PolicyArc::And(
                 subs.iter()
                     .map(|sub| Arc::new(Self::from(sub.clone())))
@@ -202,6 +211,8 @@ pub enum PolicyError {
     InsufficientArgsforOr,
     /// Entailment max terminals exceeded
     EntailmentMaxTerminals,
+    /// Too many satisfying sets to enumerate
+    SatisfyingSetsMax,
     /// lifting error: Cannot lift policies that have
     /// a combination of height and timelocks.
     HeightTimelockCombination,
@@ -222,6 +233,9 @@ pub enum DescriptorCtx<Pk> {
     /// [`Descriptor::Tr`] where the `Option<Pk>` corresponds to the internal key if no
     /// internal key can be inferred from the given policy.
     Tr(Option<Pk>),
+    /// [`Descriptor::LegacyCSFSCov`] where `Pk` is the key of the covenant. Policies
+    /// with extension fragments are compiled with [`Policy::compile_cov`].
+    Cov(Pk),
 }
 
 impl fmt::Display for PolicyError {
@@ -249,6 +263,11 @@ impl fmt::Display for PolicyError {
                 "Policy entailment only supports {} terminals",
                 ENTAILMENT_MAX_TERMINALS
             ),
+            PolicyError::SatisfyingSetsMax => write!(
+                f,
+                "Policy has more than {} satisfying sets",
+                SATISFYING_SETS_MAX
+            ),
             PolicyError::HeightTimelockCombination => {
                 f.write_str("Cannot lift policies that have a heightlock and timelock combination")
             }
@@ -270,13 +289,14 @@ impl error::Error for PolicyError {
             | InsufficientArgsforAnd
             | InsufficientArgsforOr
             | EntailmentMaxTerminals
+            | SatisfyingSetsMax
             | HeightTimelockCombination
             | DuplicatePubKeys => None,
         }
     }
 }
 
-impl<Pk: MiniscriptKey> Policy<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> Policy<Pk, Ext> {
     /// Flatten the [`Policy`] tree structure into a Vector of tuple `(leaf script, leaf probability)`
     /// with leaf probabilities corresponding to odds for sub-branch in the policy.
     /// We calculate the probability of selecting the sub-branch at every level and calculate the
@@ -300,7 +320,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     /// Since this splitting might lead to exponential blow-up, we constraint the number of
     /// leaf-nodes to [`MAX_COMPILATION_LEAVES`].
     #[cfg(feature = "compiler")]
-    fn to_tapleaf_prob_vec(&self, prob: f64) -> Vec<(f64, Policy<Pk>)> {
+    fn to_tapleaf_prob_vec(&self, prob: f64) -> Vec<(f64, Policy<Pk, Ext>)> {
         match self {
             Policy::Or(ref subs) => {
                 let total_odds: usize = subs.iter().map(|(ref k, _)| k).sum();
@@ -322,7 +342,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
 
     /// Extract the internal_key from policy tree.
     #[cfg(feature = "compiler")]
-    fn extract_key(self, unspendable_key: Option<Pk>) -> Result<(Pk, Policy<Pk>), Error> {
+    fn extract_key(self, unspendable_key: Option<Pk>) -> Result<(Pk, Policy<Pk, Ext>), Error> {
         let mut internal_key: Option<Pk> = None;
         {
             let mut prob = 0.;
@@ -360,6 +380,264 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
         }
     }
 
+    /// Compile the [`Policy`] into a [`Descriptor::TrExt`], keeping any extension
+    /// fragments of the policy as [`Terminal::Ext`] leaves of the compiled TapTree.
+    ///
+    /// The TapTree is constructed exactly as in [`Policy::compile_tr`].
+    ///
+    /// [`Terminal::Ext`]: crate::Terminal::Ext
+    #[cfg(feature = "compiler")]
+    pub fn compile_tr_ext(
+        &self,
+        unspendable_key: Option<Pk>,
+    ) -> Result<Descriptor<Pk, Ext>, Error> {
+        let config = CompilerConfig::default();
+        let (internal_key, tree) =
+            self.compile_tap_tree(unspendable_key, &config, None, &|leaves| {
+                compile_leaves(leaves, &config)
+            })?;
+        Descriptor::new_tr_ext(internal_key, tree)
+    }
+
+    /// Check that the policy can be compiled, and extract the internal key
+    /// from the policy
+    ///
+    /// The internal key is only extracted from the policy if
+    /// [`CompilerConfig::prefer_key_path`] is set or if no `unspendable_key`
+    /// is provided. When `aggregate` is provided, it is first tried on the
+    /// conjunctions of keys of the policy, see
+    /// [`Policy::compile_tr_with_aggregation`].
+    #[cfg(feature = "compiler")]
+    fn tr_internal_key(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+        aggregate: Option<&mut dyn FnMut(&[Pk]) -> Option<Pk>>,
+    ) -> Result<(Pk, Policy<Pk, Ext>), Error> {
+        self.is_valid()?; // Check for validity
+        match self.is_safe_nonmalleable() {
+            (false, _) => Err(Error::from(CompilerError::TopLevelNonSafe)),
+            (_, false) => Err(Error::from(
+                CompilerError::ImpossibleNonMalleableCompilation,
+            )),
+            _ => match unspendable_key {
+                Some(key) if !config.prefer_key_path => Ok((key, self.clone())),
+                unspendable_key => {
+                    let aggregated = aggregate.and_then(|agg| self.extract_aggregate_key(agg));
+                    match aggregated {
+                        Some(extracted) => Ok(extracted),
+                        None => self.clone().extract_key(unspendable_key),
+                    }
+                }
+            },
+        }
+    }
+
+    /// Extract an internal key from the most probable root-level branch of
+    /// the policy which is a conjunction of keys, aggregating its keys with
+    /// `aggregate` when there are several
+    #[cfg(feature = "compiler")]
+    fn extract_aggregate_key(
+        &self,
+        aggregate: &mut dyn FnMut(&[Pk]) -> Option<Pk>,
+    ) -> Option<(Pk, Policy<Pk, Ext>)> {
+        let mut branches: Vec<_> = self
+            .to_tapleaf_prob_vec(1.0)
+            .into_iter()
+            .filter_map(|(prob, branch)| branch.key_conjunction().map(|keys| (prob, keys, branch)))
+            .collect();
+        // Stable sort, so that the first of equally probable branches is preferred
+        branches.sort_by_key(|(prob, ..)| Reverse(OrdF64(*prob)));
+
+        for (_, keys, branch) in branches {
+            if keys.len() == 1 {
+                let key = keys[0].clone();
+                let policy = self.clone().translate_unsatisfiable_pk(&key);
+                return Some((key, policy));
+            }
+            if let Some(key) = aggregate(&keys) {
+                return Some((key, self.clone().translate_unsatisfiable_branch(&branch)));
+            }
+        }
+        None
+    }
+
+    /// The keys of the policy if it is a conjunction of keys, i.e. a key, or
+    /// an [`And`][`Policy::And`] or n-of-n [`Thresh`][`Policy::Threshold`] of
+    /// conjunctions of keys.
+    #[cfg(feature = "compiler")]
+    fn key_conjunction(&self) -> Option<Vec<Pk>> {
+        let subs = match *self {
+            Policy::Key(ref pk) => return Some(vec![pk.clone()]),
+            Policy::And(ref subs) => subs,
+            Policy::Threshold(k, ref subs) if k == subs.len() => subs,
+            _ => return None,
+        };
+        subs.iter()
+            .map(Policy::key_conjunction)
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| keys.into_iter().flatten().collect())
+    }
+
+    /// Translate the root-level disjunctive branches equal to `branch` to
+    /// `Concrete::Unsatisfiable` when extracting an aggregate TapKey
+    #[cfg(feature = "compiler")]
+    fn translate_unsatisfiable_branch(self, branch: &Policy<Pk, Ext>) -> Policy<Pk, Ext> {
+        if self == *branch {
+            return Policy::Unsatisfiable;
+        }
+        match self {
+            Policy::Or(subs) => Policy::Or(
+                subs.into_iter()
+                    .map(|(k, sub)| (k, sub.translate_unsatisfiable_branch(branch)))
+                    .collect(),
+            ),
+            Policy::Threshold(1, subs) => Policy::Threshold(
+                1,
+                subs.into_iter()
+                    .map(|sub| sub.translate_unsatisfiable_branch(branch))
+                    .collect(),
+            ),
+            x => x,
+        }
+    }
+
+    /// Extract the internal key and compile the remaining policy into a TapTree
+    #[cfg(feature = "compiler")]
+    fn compile_tap_tree(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+        aggregate: Option<&mut dyn FnMut(&[Pk]) -> Option<Pk>>,
+        compile_leaves: LeafCompiler<'_, Pk, Ext>,
+    ) -> Result<(Pk, Option<TapTree<Pk, Ext>>), Error> {
+        let (internal_key, policy) = self.tr_internal_key(unspendable_key, config, aggregate)?;
+        policy.check_num_tapleaves()?;
+        let tree = match policy {
+            Policy::Trivial => None,
+            policy => {
+                let mut vec_policies: Vec<_> = policy.to_tapleaf_prob_vec(1.0);
+                if config.split_key_thresholds {
+                    vec_policies = split_key_thresholds(vec_policies, config)?;
+                }
+                let leaf_compilations: Vec<_> =
+                    compile_leaf_policies(vec_policies, config, compile_leaves)?
+                        .into_iter()
+                        .map(|(prob, ms)| (OrdF64(prob), ms))
+                        .collect();
+                // The whole policy may be spent through the internal key
+                if leaf_compilations.is_empty() {
+                    None
+                } else {
+                    Some(with_huffman_tree(leaf_compilations)?)
+                }
+            }
+        };
+        Ok((internal_key, tree))
+    }
+
+    /// Compile the descriptor into an optimized `Miniscript` representation
+    ///
+    /// # NOTE:
+    ///
+    /// It is **not recommended** to use policy as a stable identifier for a miniscript.
+    /// You should use the policy compiler once, and then use the miniscript output as a stable identifier.
+    /// See the compiler document in doc/compiler.md for more details.
+    #[cfg(feature = "compiler")]
+    pub fn compile<Ctx: ScriptContext>(&self) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+        self.compile_with_config(&CompilerConfig::default())
+    }
+
+    /// Compile the descriptor into the `Miniscript` representation which is
+    /// optimal under the cost model `config`, see [`Policy::compile`]
+    #[cfg(feature = "compiler")]
+    pub fn compile_with_config<Ctx: ScriptContext>(
+        &self,
+        config: &CompilerConfig,
+    ) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+        self.is_valid()?;
+        match self.is_safe_nonmalleable() {
+            (false, _) => Err(CompilerError::TopLevelNonSafe),
+            (_, false) => Err(CompilerError::ImpossibleNonMalleableCompilation),
+            _ => compiler::best_compilation_with_config(self, config),
+        }
+    }
+
+    /// Compile the descriptor into the `Miniscript` representation which is
+    /// optimal under the cost model of `cache`, reusing the compilations of
+    /// the sub-policies of the policies compiled before with it.
+    ///
+    /// A single compilation already shares the compilations of its repeated
+    /// sub-policies, so the cache only saves work when compiling several
+    /// policies with common sub-policies. The result is the one returned by
+    /// [`Policy::compile_with_config`].
+    #[cfg(feature = "compiler")]
+    pub fn compile_with_cache<Ctx: ScriptContext>(
+        &self,
+        cache: &mut CompilationCache<Pk, Ctx, Ext>,
+    ) -> Result<Miniscript<Pk, Ctx, Ext>, CompilerError> {
+        self.is_valid()?;
+        match self.is_safe_nonmalleable() {
+            (false, _) => Err(CompilerError::TopLevelNonSafe),
+            (_, false) => Err(CompilerError::ImpossibleNonMalleableCompilation),
+            _ => cache.best_compilation(self),
+        }
+    }
+
+    /// Explain the compilation of the policy under the cost model `config`,
+    /// listing for every policy node the `n` cheapest compilations kept by
+    /// the compiler and the `n` cheapest ones it rejected, see
+    /// [`compiler::explain_compilation`]
+    #[cfg(feature = "compiler")]
+    pub fn explain_compile<Ctx: ScriptContext>(
+        &self,
+        config: &CompilerConfig,
+        n: usize,
+    ) -> compiler::Explanation<Pk, Ctx, Ext> {
+        let checked = match self.is_valid() {
+            Err(e) => Err(CompilerError::from(e)),
+            Ok(()) => match self.is_safe_nonmalleable() {
+                (false, _) => Err(CompilerError::TopLevelNonSafe),
+                (_, false) => Err(CompilerError::ImpossibleNonMalleableCompilation),
+                _ => Ok(()),
+            },
+        };
+        match checked {
+            Err(e) => compiler::Explanation {
+                result: Err(e),
+                nodes: vec![],
+            },
+            Ok(()) => compiler::explain_compilation(self, config, n),
+        }
+    }
+
+    /// Compile the [`Policy`] into a [`Descriptor::LegacyCSFSCov`] covenant
+    /// on the key `pk`.
+    ///
+    /// The policy, which may contain extension fragments such as `ver_eq` or
+    /// `outputs_pref`, is compiled under the Segwitv0 context and the resulting
+    /// descriptor is checked with [`LegacyCSFSCov::sanity_check`].
+    #[cfg(feature = "compiler")]
+    pub fn compile_cov(&self, pk: Pk) -> Result<Descriptor<Pk, Ext>, Error> {
+        self.compile_cov_with_config(pk, &CompilerConfig::default())
+    }
+
+    /// Compile the [`Policy`] into a [`Descriptor::LegacyCSFSCov`] under the
+    /// cost model `config`, see [`Policy::compile_cov`]
+    #[cfg(feature = "compiler")]
+    pub fn compile_cov_with_config(
+        &self,
+        pk: Pk,
+        config: &CompilerConfig,
+    ) -> Result<Descriptor<Pk, Ext>, Error> {
+        let ms = self.compile_with_config::<Segwitv0>(config)?;
+        let cov = LegacyCSFSCov::new(pk, ms)?;
+        cov.sanity_check()?;
+        Ok(Descriptor::LegacyCSFSCov(cov))
+    }
+}
+
+impl<Pk: MiniscriptKey> Policy<Pk> {
     /// Compile the [`Policy`] into a [`Descriptor::Tr`].
     ///
     /// ### TapTree compilation
@@ -378,39 +656,105 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     // TODO: We might require other compile errors for Taproot.
     #[cfg(feature = "compiler")]
     pub fn compile_tr(&self, unspendable_key: Option<Pk>) -> Result<Descriptor<Pk, NoExt>, Error> {
-        self.is_valid()?; // Check for validity
-        match self.is_safe_nonmalleable() {
-            (false, _) => Err(Error::from(CompilerError::TopLevelNonSafe)),
-            (_, false) => Err(Error::from(
-                CompilerError::ImpossibleNonMalleableCompilation,
-            )),
-            _ => {
-                let (internal_key, policy) = self.clone().extract_key(unspendable_key)?;
-                policy.check_num_tapleaves()?;
-                let tree = Descriptor::new_tr(
-                    internal_key,
-                    match policy {
-                        Policy::Trivial => None,
-                        policy => {
-                            let vec_policies: Vec<_> = policy.to_tapleaf_prob_vec(1.0);
-                            let mut leaf_compilations: Vec<(OrdF64, Miniscript<Pk, Tap>)> = vec![];
-                            for (prob, pol) in vec_policies {
-                                // policy corresponding to the key (replaced by unsatisfiable) is skipped
-                                if pol == Policy::Unsatisfiable {
-                                    continue;
-                                }
-                                let compilation = compiler::best_compilation::<Pk, Tap>(&pol)?;
-                                compilation.sanity_check()?;
-                                leaf_compilations.push((OrdF64(prob), compilation));
-                            }
-                            let taptree = with_huffman_tree::<Pk>(leaf_compilations)?;
-                            Some(taptree)
-                        }
-                    },
-                )?;
-                Ok(tree)
-            }
-        }
+        self.compile_tr_with_config(unspendable_key, &CompilerConfig::default())
+    }
+
+    /// Compile the [`Policy`] into a [`Descriptor::Tr`] under the cost model
+    /// `config`, see [`Policy::compile_tr`].
+    ///
+    /// Unless [`CompilerConfig::prefer_key_path`] is set, the whole policy is
+    /// compiled into script leaves when an `unspendable_key` is provided.
+    #[cfg(feature = "compiler")]
+    pub fn compile_tr_with_config(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+    ) -> Result<Descriptor<Pk, NoExt>, Error> {
+        let (internal_key, tree) =
+            self.compile_tap_tree(unspendable_key, config, None, &|leaves| {
+                compile_leaves(leaves, config)
+            })?;
+        Descriptor::new_tr(internal_key, tree)
+    }
+
+    /// Compile the [`Policy`] into a [`Descriptor::Tr`] under the cost model
+    /// `config` like [`Policy::compile_tr_with_config`], compiling the leaves of
+    /// the TapTree on up to `threads` threads.
+    ///
+    /// Every thread compiles a contiguous range of the leaves, reusing the
+    /// compilations of the sub-policies they share, and the descriptor is
+    /// identical to the one compiled on a single thread. The leaves are
+    /// compiled on the calling thread when `threads` is at most 1.
+    ///
+    /// The keys must be `'static` as the leaves are moved to spawned threads:
+    /// scoped threads, which would allow borrowed keys, need Rust 1.63, above
+    /// the MSRV of this crate. Use [`Policy::compile_tr_with_config`] for
+    /// policies over borrowed keys.
+    #[cfg(feature = "compiler")]
+    pub fn compile_tr_parallel(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+        threads: usize,
+    ) -> Result<Descriptor<Pk, NoExt>, Error>
+    where
+        Pk: 'static,
+        Policy<Pk>: Send,
+        Miniscript<Pk, Tap>: Send,
+    {
+        let (internal_key, tree) =
+            self.compile_tap_tree(unspendable_key, config, None, &|leaves| {
+                compile_leaves_parallel(leaves, config, threads)
+            })?;
+        Descriptor::new_tr(internal_key, tree)
+    }
+
+    /// Compile the [`Policy`] into a [`Descriptor::Tr`] under the cost model
+    /// `config`, using an aggregate of the keys of a conjunction as internal key.
+    ///
+    /// The most probable root-level branch of the policy which is a conjunction of
+    /// keys, e.g. `and(pk(A),pk(B))` or `thresh(3,pk(A),pk(B),pk(C))`, is spent
+    /// through the key path: its keys are passed to `aggregate`, which returns
+    /// their aggregate key (for instance a MuSig2 key), and the branch is left
+    /// out of the TapTree. A branch which is a single key needs no aggregation.
+    /// When `aggregate` returns `None`, the next most probable conjunction is
+    /// tried, and the internal key is selected as by [`Policy::compile_tr`] when
+    /// no conjunction remains.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use elements_miniscript::policy::compiler::CompilerConfig;
+    /// use elements_miniscript::policy::Concrete;
+    /// use elements_miniscript::Descriptor;
+    /// use std::str::FromStr;
+    ///
+    /// let policy = Concrete::<String>::from_str("or(9@and(pk(A),pk(B)),1@and(pk(A),older(144)))").unwrap();
+    /// let desc = policy
+    ///     .compile_tr_with_aggregation(None, &CompilerConfig::default(), |keys| {
+    ///         Some(format!("musig({})", keys.join(",")))
+    ///     })
+    ///     .unwrap();
+    /// match desc {
+    ///     Descriptor::Tr(tr) => assert_eq!(tr.internal_key(), "musig(A,B)"),
+    ///     _ => unreachable!(),
+    /// }
+    /// ```
+    #[cfg(feature = "compiler")]
+    pub fn compile_tr_with_aggregation<F>(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+        mut aggregate: F,
+    ) -> Result<Descriptor<Pk, NoExt>, Error>
+    where
+        F: FnMut(&[Pk]) -> Option<Pk>,
+    {
+        let (internal_key, tree) =
+            self.compile_tap_tree(unspendable_key, config, Some(&mut aggregate), &|leaves| {
+                compile_leaves(leaves, config)
+            })?;
+        Descriptor::new_tr(internal_key, tree)
     }
 
     /// Compiles the [`Policy`] into a [`Descriptor::Tr`].
@@ -438,46 +782,106 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
         &self,
         unspendable_key: Option<Pk>,
     ) -> Result<Descriptor<Pk>, Error> {
-        self.is_valid()?; // Check for validity
-        match self.is_safe_nonmalleable() {
-            (false, _) => Err(Error::from(CompilerError::TopLevelNonSafe)),
-            (_, false) => Err(Error::from(
-                CompilerError::ImpossibleNonMalleableCompilation,
-            )),
-            _ => {
-                let (internal_key, policy) = self.clone().extract_key(unspendable_key)?;
-                let tree = Descriptor::new_tr(
-                    internal_key,
-                    match policy {
-                        Policy::Trivial => None,
-                        policy => {
-                            let pol = PolicyArc::from(policy);
-                            let leaf_compilations: Vec<_> = pol
-                                .enumerate_policy_tree(1.0)
-                                .into_iter()
-                                .filter(|x| x.1 != Arc::new(PolicyArc::Unsatisfiable))
-                                .map(|(prob, ref pol)| {
-                                    let converted_pol = Policy::<Pk>::from((**pol).clone());
-                                    (
-                                        OrdF64(prob),
-                                        compiler::best_compilation(&converted_pol).unwrap(),
-                                    )
-                                })
-                                .collect();
-                            let taptree = with_huffman_tree::<Pk>(leaf_compilations).unwrap();
-                            Some(taptree)
-                        }
-                    },
-                )?;
-                Ok(tree)
+        let config = CompilerConfig::default();
+        let (internal_key, policy) = self.tr_internal_key(unspendable_key, &config, None)?;
+        let tree = match policy {
+            Policy::Trivial => None,
+            policy => {
+                let pol = PolicyArc::from(policy);
+                let leaves = pol
+                    .enumerate_policy_tree(1.0)
+                    .into_iter()
+                    .filter(|x| x.1 != Arc::new(PolicyArc::Unsatisfiable))
+                    .map(|(prob, ref pol)| (prob, Policy::<Pk>::from((**pol).clone())))
+                    .collect();
+                let leaf_compilations = compile_leaf_policies(leaves, &config, &|leaves| {
+                    compile_leaves(leaves, &config)
+                })?
+                .into_iter()
+                .map(|(prob, ms)| (OrdF64(prob), ms))
+                .collect();
+                let taptree = with_huffman_tree(leaf_compilations)?;
+                Some(taptree)
+            }
+        };
+        Descriptor::new_tr(internal_key, tree)
+    }
+
+    /// Compiles the [`Policy`] into a [`Descriptor::Tr`], choosing the TapTree of
+    /// minimal expected spending weight, and reports the compiled leaves.
+    ///
+    /// Both the root-level disjunctions of [`Policy::compile_tr`] and the
+    /// enumeration of [`Policy::compile_tr_private_experimental`] are compiled into
+    /// leaves, whose depths are chosen to minimize the expected weight of a script
+    /// path spend while keeping every leaf within the consensus depth limit of 128.
+    /// When [`CompilerConfig::split_key_thresholds`] is set, the root-level
+    /// disjunctions with their key-only thresholds split into subsets are also
+    /// compiled. The set of leaves with the lowest expected spending weight is kept. Leaves
+    /// whose script exceeds [`CompilerConfig::max_tap_leaf_size`] rule out their
+    /// set of leaves.
+    ///
+    /// The reported leaves are ordered by depth, and by decreasing probability at
+    /// the same depth. Their probabilities are the ones derived from the odds of the
+    /// policy, the probability of the key path spend being left out.
+    #[cfg(feature = "compiler")]
+    pub fn compile_tr_optimized(
+        &self,
+        unspendable_key: Option<Pk>,
+        config: &CompilerConfig,
+    ) -> Result<(Descriptor<Pk>, Vec<TapLeafCompilation<Pk>>), Error> {
+        let (internal_key, policy) = self.tr_internal_key(unspendable_key, config, None)?;
+        if policy == Policy::Trivial {
+            return Ok((Descriptor::new_tr(internal_key, None)?, vec![]));
+        }
+
+        let mut candidates = Vec::with_capacity(3);
+        if policy.num_tap_leaves() <= MAX_COMPILATION_LEAVES {
+            let leaves = policy.to_tapleaf_prob_vec(1.0);
+            if config.split_key_thresholds {
+                candidates.push(split_key_thresholds(leaves.clone(), config)?);
             }
+            candidates.push(leaves);
+        }
+        candidates.push(
+            PolicyArc::from(policy)
+                .enumerate_policy_tree(1.0)
+                .into_iter()
+                .map(|(prob, pol)| (prob, Policy::from((*pol).clone())))
+                .collect(),
+        );
+
+        let mut best: Option<(f64, Vec<TapLeafCompilation<Pk>>)> = None;
+        let mut first_error = None;
+        for candidate in candidates {
+            match compile_tap_leaves(candidate, config) {
+                Ok(leaves) => {
+                    let weight = expected_spend_weight(&leaves);
+                    if best.as_ref().map_or(true, |(w, _)| weight < *w) {
+                        best = Some((weight, leaves));
+                    }
+                }
+                Err(e) => {
+                    if first_error.is_none() {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+        match best {
+            Some((_, leaves)) => {
+                let tree = tree_from_leaves(&leaves)?;
+                Ok((Descriptor::new_tr(internal_key, tree)?, leaves))
+            }
+            None => Err(first_error.unwrap_or_else(|| errstr("Empty Miniscript compilation"))),
         }
     }
 
     /// Compile the [`Policy`] into desc_ctx [`Descriptor`]
     ///
     /// In case of [Tr][`DescriptorCtx::Tr`], `internal_key` is used for the Taproot comilation when
-    /// no public key can be inferred from the given policy.
+    /// no public key can be inferred from the given policy. In case of
+    /// [Cov][`DescriptorCtx::Cov`], the policy is compiled under the Segwitv0 context
+    /// regardless of `Ctx`.
     ///
     /// # NOTE:
     ///
@@ -488,6 +892,17 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     pub fn compile_to_descriptor<Ctx: ScriptContext>(
         &self,
         desc_ctx: DescriptorCtx<Pk>,
+    ) -> Result<Descriptor<Pk, NoExt>, Error> {
+        self.compile_to_descriptor_with_config::<Ctx>(desc_ctx, &CompilerConfig::default())
+    }
+
+    /// Compile the [`Policy`] into desc_ctx [`Descriptor`] under the cost
+    /// model `config`, see [`Policy::compile_to_descriptor`]
+    #[cfg(feature = "compiler")]
+    pub fn compile_to_descriptor_with_config<Ctx: ScriptContext>(
+        &self,
+        desc_ctx: DescriptorCtx<Pk>,
+        config: &CompilerConfig,
     ) -> Result<Descriptor<Pk, NoExt>, Error> {
         self.is_valid()?;
         match self.is_safe_nonmalleable() {
@@ -496,31 +911,25 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
                 CompilerError::ImpossibleNonMalleableCompilation,
             )),
             _ => match desc_ctx {
-                DescriptorCtx::Bare => Descriptor::new_bare(compiler::best_compilation(self)?),
-                DescriptorCtx::Sh => Descriptor::new_sh(compiler::best_compilation(self)?),
-                DescriptorCtx::Wsh => Descriptor::new_wsh(compiler::best_compilation(self)?),
-                DescriptorCtx::ShWsh => Descriptor::new_sh_wsh(compiler::best_compilation(self)?),
-                DescriptorCtx::Tr(unspendable_key) => self.compile_tr(unspendable_key),
+                DescriptorCtx::Bare => {
+                    Descriptor::new_bare(compiler::best_compilation_with_config(self, config)?)
+                }
+                DescriptorCtx::Sh => {
+                    Descriptor::new_sh(compiler::best_compilation_with_config(self, config)?)
+                }
+                DescriptorCtx::Wsh => {
+                    Descriptor::new_wsh(compiler::best_compilation_with_config(self, config)?)
+                }
+                DescriptorCtx::ShWsh => {
+                    Descriptor::new_sh_wsh(compiler::best_compilation_with_config(self, config)?)
+                }
+                DescriptorCtx::Tr(unspendable_key) => {
+                    self.compile_tr_with_config(unspendable_key, config)
+                }
+                DescriptorCtx::Cov(pk) => self.compile_cov_with_config(pk, config),
             },
         }
     }
-
-    /// Compile the descriptor into an optimized `Miniscript` representation
-    ///
-    /// # NOTE:
-    ///
-    /// It is **not recommended** to use policy as a stable identifier for a miniscript.
-    /// You should use the policy compiler once, and then use the miniscript output as a stable identifier.
-    /// See the compiler document in doc/compiler.md for more details.
-    #[cfg(feature = "compiler")]
-    pub fn compile<Ctx: ScriptContext>(&self) -> Result<Miniscript<Pk, Ctx>, CompilerError> {
-        self.is_valid()?;
-        match self.is_safe_nonmalleable() {
-            (false, _) => Err(CompilerError::TopLevelNonSafe),
-            (_, false) => Err(CompilerError::ImpossibleNonMalleableCompilation),
-            _ => compiler::best_compilation(self),
-        }
-    }
 }
 
 #[cfg(feature = "compiler")]
@@ -657,7 +1066,7 @@ impl<Pk: MiniscriptKey> PolicyArc<Pk> {
     }
 }
 
-impl<Pk: MiniscriptKey> ForEachKey<Pk> for Policy<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> ForEachKey<Pk> for Policy<Pk, Ext> {
     fn for_each_key<'a, F: FnMut(&'a Pk) -> bool>(&'a self, mut pred: F) -> bool
     where
         Pk: 'a,
@@ -671,7 +1080,8 @@ impl<Pk: MiniscriptKey> ForEachKey<Pk> for Policy<Pk> {
             | Policy::Ripemd160(..)
             | Policy::Hash160(..)
             | Policy::After(..)
-            | Policy::Older(..) => true,
+            | Policy::Older(..)
+            | Policy::Ext(..) => true,
             Policy::Threshold(_, ref subs) | Policy::And(ref subs) => {
                 subs.iter().all(|sub| sub.for_each_key(&mut pred))
             }
@@ -680,7 +1090,7 @@ impl<Pk: MiniscriptKey> ForEachKey<Pk> for Policy<Pk> {
     }
 }
 
-impl<Pk: MiniscriptKey> Policy<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> Policy<Pk, Ext> {
     /// Convert a policy using one kind of public key to another
     /// type of public key
     ///
@@ -724,7 +1134,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     /// let expected_policy = Policy::from_str(&format!("and(pk({}),pk({}))", alice_key, bob_key)).unwrap();
     /// assert_eq!(real_policy, expected_policy);
     /// ```
-    pub fn translate_pk<Q, E, T>(&self, t: &mut T) -> Result<Policy<Q>, E>
+    pub fn translate_pk<Q, E, T>(&self, t: &mut T) -> Result<Policy<Q, Ext>, E>
     where
         T: Translator<Pk, Q, E>,
         Q: MiniscriptKey,
@@ -732,7 +1142,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
         self._translate_pk(t)
     }
 
-    fn _translate_pk<Q, E, T>(&self, t: &mut T) -> Result<Policy<Q>, E>
+    fn _translate_pk<Q, E, T>(&self, t: &mut T) -> Result<Policy<Q, Ext>, E>
     where
         T: Translator<Pk, Q, E>,
         Q: MiniscriptKey,
@@ -747,26 +1157,27 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             Policy::Hash160(ref h) => t.hash160(h).map(Policy::Hash160),
             Policy::Older(n) => Ok(Policy::Older(n)),
             Policy::After(n) => Ok(Policy::After(n)),
+            Policy::Ext(ref e) => Ok(Policy::Ext(e.clone())),
             Policy::Threshold(k, ref subs) => {
-                let new_subs: Result<Vec<Policy<Q>>, _> =
+                let new_subs: Result<Vec<Policy<Q, Ext>>, _> =
                     subs.iter().map(|sub| sub._translate_pk(t)).collect();
                 new_subs.map(|ok| Policy::Threshold(k, ok))
             }
             Policy::And(ref subs) => Ok(Policy::And(
                 subs.iter()
                     .map(|sub| sub._translate_pk(t))
-                    .collect::<Result<Vec<Policy<Q>>, E>>()?,
+                    .collect::<Result<Vec<Policy<Q, Ext>>, E>>()?,
             )),
             Policy::Or(ref subs) => Ok(Policy::Or(
                 subs.iter()
                     .map(|(prob, sub)| Ok((*prob, sub._translate_pk(t)?)))
-                    .collect::<Result<Vec<(usize, Policy<Q>)>, E>>()?,
+                    .collect::<Result<Vec<(usize, Policy<Q, Ext>)>, E>>()?,
             )),
         }
     }
 
     /// Translate `Concrete::Key(key)` to `Concrete::Unsatisfiable` when extracting TapKey
-    pub fn translate_unsatisfiable_pk(self, key: &Pk) -> Policy<Pk> {
+    pub fn translate_unsatisfiable_pk(self, key: &Pk) -> Policy<Pk, Ext> {
         match self {
             Policy::Key(ref k) if k.clone() == *key => Policy::Unsatisfiable,
             Policy::And(subs) => Policy::And(
@@ -866,6 +1277,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             | Policy::Hash256(_)
             | Policy::Ripemd160(_)
             | Policy::Hash160(_) => TimelockInfo::default(),
+            Policy::Ext(ref e) => e.extra_prop().timelock_info,
             Policy::After(t) => TimelockInfo {
                 csv_with_height: false,
                 csv_with_time: false,
@@ -969,6 +1381,10 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             | Policy::Hash160(_)
             | Policy::After(_)
             | Policy::Older(_) => (false, true),
+            Policy::Ext(ref e) => {
+                let mall = e.mall_prop();
+                (mall.safe, mall.non_malleable)
+            }
             Policy::Threshold(k, ref subs) => {
                 let (safe_count, non_mall_count) = subs
                     .iter()
@@ -1005,7 +1421,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     }
 }
 
-impl<Pk: MiniscriptKey> fmt::Debug for Policy<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> fmt::Debug for Policy<Pk, Ext> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Policy::Unsatisfiable => f.write_str("UNSATISFIABLE()"),
@@ -1017,6 +1433,7 @@ impl<Pk: MiniscriptKey> fmt::Debug for Policy<Pk> {
             Policy::Hash256(ref h) => write!(f, "hash256({})", h),
             Policy::Ripemd160(ref h) => write!(f, "ripemd160({})", h),
             Policy::Hash160(ref h) => write!(f, "hash160({})", h),
+            Policy::Ext(ref e) => write!(f, "{:?}", e),
             Policy::And(ref subs) => {
                 f.write_str("and(")?;
                 if !subs.is_empty() {
@@ -1048,7 +1465,7 @@ impl<Pk: MiniscriptKey> fmt::Debug for Policy<Pk> {
     }
 }
 
-impl<Pk: MiniscriptKey> fmt::Display for Policy<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> fmt::Display for Policy<Pk, Ext> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match *self {
             Policy::Unsatisfiable => f.write_str("UNSATISFIABLE"),
@@ -1060,6 +1477,7 @@ impl<Pk: MiniscriptKey> fmt::Display for Policy<Pk> {
             Policy::Hash256(ref h) => write!(f, "hash256({})", h),
             Policy::Ripemd160(ref h) => write!(f, "ripemd160({})", h),
             Policy::Hash160(ref h) => write!(f, "hash160({})", h),
+            Policy::Ext(ref e) => write!(f, "{}", e),
             Policy::And(ref subs) => {
                 f.write_str("and(")?;
                 if !subs.is_empty() {
@@ -1092,9 +1510,10 @@ impl<Pk: MiniscriptKey> fmt::Display for Policy<Pk> {
 }
 
 impl_from_str!(
-    Policy<Pk>,
+    Policy<Pk, Ext>,
+    => Ext; Extension,
     type Err = Error;,
-    fn from_str(s: &str) -> Result<Policy<Pk>, Error> {
+    fn from_str(s: &str) -> Result<Policy<Pk, Ext>, Error> {
         for ch in s.as_bytes() {
             if *ch < 20 || *ch > 127 {
                 return Err(Error::Unprintable(*ch));
@@ -1102,21 +1521,22 @@ impl_from_str!(
         }
 
         let tree = expression::Tree::from_str(s)?;
-        let policy: Policy<Pk> = FromTree::from_tree(&tree)?;
+        let policy: Policy<Pk, Ext> = FromTree::from_tree(&tree)?;
         policy.check_timelocks()?;
         Ok(policy)
     }
 );
 
-serde_string_impl_pk!(Policy, "a miniscript concrete policy");
+serde_string_impl_pk!(Policy, "a miniscript concrete policy" => Ext ; Extension);
 
 #[rustfmt::skip]
 impl_block_str!(
-    Policy<Pk>,
+    Policy<Pk, Ext>,
+    => Ext; Extension,
     /// Helper function for `from_tree` to parse subexpressions with
     /// names of the form x@y
     fn from_tree_prob(top: &expression::Tree, allow_prob: bool,)
-        -> Result<(usize, Policy<Pk>), Error>
+        -> Result<(usize, Policy<Pk, Ext>), Error>
     {
         let frag_prob;
         let frag_name;
@@ -1211,25 +1631,29 @@ impl_block_str!(
                 }
                 Ok(Policy::Threshold(thresh as usize, subs))
             }
-            _ => Err(errstr(top.name)),
+            (name, _) => match Ext::from_name_tree(name, &top.args) {
+                Ok(e) => Ok(Policy::Ext(e)),
+                Err(..) => Err(errstr(top.name)),
+            },
         }
         .map(|res| (frag_prob, res))
     }
 );
 
 impl_from_tree!(
-    Policy<Pk>,
-    fn from_tree(top: &expression::Tree) -> Result<Policy<Pk>, Error> {
+    Policy<Pk, Ext>,
+    => Ext; Extension,
+    fn from_tree(top: &expression::Tree) -> Result<Policy<Pk, Ext>, Error> {
         Policy::from_tree_prob(top, false).map(|(_, result)| result)
     }
 );
 
 /// Create a Huffman Tree from compiled [Miniscript] nodes
 #[cfg(feature = "compiler")]
-fn with_huffman_tree<Pk: MiniscriptKey>(
-    ms: Vec<(OrdF64, Miniscript<Pk, Tap>)>,
-) -> Result<TapTree<Pk, NoExt>, Error> {
-    let mut node_weights = BinaryHeap::<(Reverse<OrdF64>, TapTree<Pk>)>::new();
+fn with_huffman_tree<Pk: MiniscriptKey, Ext: Extension>(
+    ms: Vec<(OrdF64, Miniscript<Pk, Tap, Ext>)>,
+) -> Result<TapTree<Pk, Ext>, Error> {
+    let mut node_weights = BinaryHeap::<(Reverse<OrdF64>, TapTree<Pk, Ext>)>::new();
     for (prob, script) in ms {
         node_weights.push((Reverse(prob), TapTree::Leaf(Arc::new(script))));
     }
@@ -1255,6 +1679,395 @@ fn with_huffman_tree<Pk: MiniscriptKey>(
     Ok(node)
 }
 
+/// A leaf of a Taproot compilation, see [`Policy::compile_tr_optimized`]
+#[cfg(feature = "compiler")]
+#[derive(Clone, PartialEq, Debug)]
+pub struct TapLeafCompilation<Pk: MiniscriptKey> {
+    /// The compiled leaf script
+    pub ms: Arc<Miniscript<Pk, Tap>>,
+    /// The probability of spending through the leaf
+    pub prob: f64,
+    /// The depth of the leaf in the TapTree
+    pub depth: usize,
+    /// The maximum weight of the witness spending through the leaf, including
+    /// the leaf script and the control block
+    pub spend_weight: usize,
+}
+
+/// Compile a policy into a TapLeaf, checking the leaf size limit of `config`
+#[cfg(feature = "compiler")]
+fn compile_tap_leaf<Pk: MiniscriptKey, Ext: Extension>(
+    policy: &Policy<Pk, Ext>,
+    config: &CompilerConfig,
+) -> Result<Miniscript<Pk, Tap, Ext>, Error> {
+    let ms = compiler::best_compilation_with_config::<Pk, Tap, Ext>(policy, config)?;
+    check_tap_leaf(ms, config)
+}
+
+/// Check a compiled leaf of a Taproot compilation against the limits of `config`
+#[cfg(feature = "compiler")]
+fn check_tap_leaf<Pk: MiniscriptKey, Ext: Extension>(
+    ms: Miniscript<Pk, Tap, Ext>,
+    config: &CompilerConfig,
+) -> Result<Miniscript<Pk, Tap, Ext>, Error> {
+    ms.sanity_check()?;
+    match config.max_tap_leaf_size {
+        Some(max) if ms.script_size() > max => Err(Error::from(CompilerError::LimitsExceeded)),
+        _ => Ok(ms),
+    }
+}
+
+/// Compiles the policies of the leaves of a TapTree, returning their
+/// compilations in order. The leaves following a failed compilation may be
+/// left out.
+#[cfg(feature = "compiler")]
+type LeafCompiler<'a, Pk, Ext> =
+    &'a dyn Fn(Vec<Policy<Pk, Ext>>) -> Vec<Result<Miniscript<Pk, Tap, Ext>, CompilerError>>;
+
+/// Compile the leaves of a Taproot compilation with `compile_leaves`, checking
+/// them against the limits of `config`
+#[cfg(feature = "compiler")]
+fn compile_leaf_policies<Pk: MiniscriptKey, Ext: Extension>(
+    leaves: Vec<(f64, Policy<Pk, Ext>)>,
+    config: &CompilerConfig,
+    compile_leaves: LeafCompiler<'_, Pk, Ext>,
+) -> Result<Vec<(f64, Miniscript<Pk, Tap, Ext>)>, Error> {
+    // policy corresponding to the key (replaced by unsatisfiable) is skipped
+    let (probs, policies): (Vec<_>, Vec<_>) = leaves
+        .into_iter()
+        .filter(|(_, pol)| *pol != Policy::Unsatisfiable)
+        .unzip();
+    probs
+        .into_iter()
+        .zip(compile_leaves(policies))
+        .map(|(prob, ms)| Ok((prob, check_tap_leaf(ms?, config)?)))
+        .collect()
+}
+
+/// Compile the policies of the leaves of a TapTree in order, sharing the
+/// compilations of their sub-policies, see [`LeafCompiler`]
+#[cfg(feature = "compiler")]
+fn compile_leaves<Pk: MiniscriptKey, Ext: Extension>(
+    policies: Vec<Policy<Pk, Ext>>,
+    config: &CompilerConfig,
+) -> Vec<Result<Miniscript<Pk, Tap, Ext>, CompilerError>> {
+    let mut cache = match CompilationCache::new(config) {
+        Ok(cache) => cache,
+        Err(e) => return vec![Err(e)],
+    };
+    let mut ret = Vec::with_capacity(policies.len());
+    for pol in &policies {
+        let compilation = cache.best_compilation(pol);
+        let failed = compilation.is_err();
+        ret.push(compilation);
+        if failed {
+            break;
+        }
+    }
+    ret
+}
+
+/// Compile the policies of the leaves of a TapTree like [`compile_leaves`],
+/// splitting them into contiguous ranges compiled on up to `threads` threads
+#[cfg(feature = "compiler")]
+fn compile_leaves_parallel<Pk, Ext>(
+    policies: Vec<Policy<Pk, Ext>>,
+    config: &CompilerConfig,
+    threads: usize,
+) -> Vec<Result<Miniscript<Pk, Tap, Ext>, CompilerError>>
+where
+    Pk: MiniscriptKey + 'static,
+    Ext: Extension + 'static,
+    Policy<Pk, Ext>: Send,
+    Miniscript<Pk, Tap, Ext>: Send,
+{
+    if threads <= 1 || policies.len() <= 1 {
+        return compile_leaves(policies, config);
+    }
+    if let Err(e) = config.sanity_check() {
+        return vec![Err(e)];
+    }
+    let threads = threads.min(policies.len());
+    let chunk_size = (policies.len() + threads - 1) / threads;
+    let mut handles = Vec::with_capacity(threads);
+    let mut policies = policies.into_iter().peekable();
+    while policies.peek().is_some() {
+        let chunk: Vec<_> = policies.by_ref().take(chunk_size).collect();
+        let config = *config;
+        // `std::thread::scope` would not need the `'static` bounds but is
+        // only stable since Rust 1.63, above our MSRV of 1.58.
+        handles.push(thread::spawn(move || compile_leaves(chunk, &config)));
+    }
+    let mut ret = vec![];
+    for handle in handles {
+        match handle.join() {
+            Ok(compilations) => ret.extend(compilations),
+            Err(e) => panic::resume_unwind(e),
+        }
+    }
+    ret
+}
+
+/// Split the key-only thresholds `thresh(k,pk(..),..)` among the leaves of a
+/// Taproot compilation into one leaf per subset of `k` keys, when the weight
+/// of a spend through such a leaf, deeper in the TapTree, is lower than through
+/// the compiled threshold. Thresholds are not split beyond
+/// [`MAX_COMPILATION_LEAVES`] leaves in total.
+#[cfg(feature = "compiler")]
+fn split_key_thresholds<Pk: MiniscriptKey, Ext: Extension>(
+    leaves: Vec<(f64, Policy<Pk, Ext>)>,
+    config: &CompilerConfig,
+) -> Result<Vec<(f64, Policy<Pk, Ext>)>, Error> {
+    let mut num_leaves = leaves.len();
+    let mut ret = Vec::with_capacity(num_leaves);
+    for (prob, pol) in leaves {
+        let (k, keys) = match key_threshold(&pol) {
+            Some(threshold) => threshold,
+            None => {
+                ret.push((prob, pol));
+                continue;
+            }
+        };
+        let num_subsets = match binomial(keys.len(), k) {
+            Some(n) if num_leaves - 1 + n <= MAX_COMPILATION_LEAVES => n,
+            _ => {
+                ret.push((prob, pol));
+                continue;
+            }
+        };
+
+        // Every subset leaf has the same weight, at least ceil(log2(n)) deeper
+        let subset =
+            |keys: &[Pk]| Policy::Threshold(k, keys.iter().cloned().map(Policy::Key).collect());
+        let depth = (usize::BITS - (num_subsets - 1).leading_zeros()) as usize;
+        let thresh_ms = compile_tap_leaf(&pol, config)?;
+        let subset_ms = compile_tap_leaf(&subset(&keys[..k]), config)?;
+        let thresh_weight = TapLeafScript::Miniscript(&thresh_ms).max_weight_to_satisfy(0)?;
+        let subset_weight = TapLeafScript::Miniscript(&subset_ms).max_weight_to_satisfy(depth)?;
+        if subset_weight < thresh_weight {
+            let subset_prob = prob / num_subsets as f64;
+            ret.extend(
+                key_subsets(&keys, k)
+                    .iter()
+                    .map(|keys| (subset_prob, subset(&keys[..]))),
+            );
+            num_leaves += num_subsets - 1;
+        } else {
+            ret.push((prob, pol));
+        }
+    }
+    Ok(ret)
+}
+
+/// The `k` and the keys of a key-only threshold `thresh(k,pk(..),..)` which
+/// is neither a disjunction nor a conjunction
+#[cfg(feature = "compiler")]
+fn key_threshold<Pk: MiniscriptKey, Ext: Extension>(
+    policy: &Policy<Pk, Ext>,
+) -> Option<(usize, Vec<Pk>)> {
+    match *policy {
+        Policy::Threshold(k, ref subs) if 1 < k && k < subs.len() => {
+            let keys = subs
+                .iter()
+                .map(|sub| match *sub {
+                    Policy::Key(ref pk) => Some(pk.clone()),
+                    _ => None,
+                })
+                .collect::<Option<Vec<Pk>>>()?;
+            Some((k, keys))
+        }
+        _ => None,
+    }
+}
+
+/// The number of subsets of `k` elements of a set of `n` elements, `None` on
+/// overflow
+#[cfg(feature = "compiler")]
+fn binomial(n: usize, k: usize) -> Option<usize> {
+    let mut ret: usize = 1;
+    for i in 0..k {
+        ret = ret.checked_mul(n - i)? / (i + 1);
+    }
+    Some(ret)
+}
+
+/// The subsets of `k` keys, in lexicographic order of their indices
+#[cfg(feature = "compiler")]
+fn key_subsets<Pk: Clone>(keys: &[Pk], k: usize) -> Vec<Vec<Pk>> {
+    let n = keys.len();
+    let mut ret = vec![];
+    let mut idx: Vec<usize> = (0..k).collect();
+    loop {
+        ret.push(idx.iter().map(|&i| keys[i].clone()).collect());
+        // Find the last index which can be incremented
+        let mut i = k;
+        while i > 0 && idx[i - 1] == n - k + i - 1 {
+            i -= 1;
+        }
+        if i == 0 {
+            return ret;
+        }
+        idx[i - 1] += 1;
+        let start = idx[i - 1];
+        for (offset, x) in idx[i..].iter_mut().enumerate() {
+            *x = start + 1 + offset;
+        }
+    }
+}
+
+/// Compile the leaves of a Taproot compilation and assign them the depths of
+/// minimal expected spending weight
+#[cfg(feature = "compiler")]
+fn compile_tap_leaves<Pk: MiniscriptKey>(
+    policies: Vec<(f64, Policy<Pk>)>,
+    config: &CompilerConfig,
+) -> Result<Vec<TapLeafCompilation<Pk>>, Error> {
+    let compilations: Vec<_> =
+        compile_leaf_policies(policies, config, &|leaves| compile_leaves(leaves, config))?
+            .into_iter()
+            .map(|(prob, ms)| (prob, Arc::new(ms)))
+            .collect();
+    let probs: Vec<f64> = compilations.iter().map(|(prob, _)| *prob).collect();
+    let depths = limited_depths(&probs, TAPROOT_CONTROL_MAX_NODE_COUNT)?;
+
+    let mut leaves = compilations
+        .into_iter()
+        .zip(depths)
+        .map(|((prob, ms), depth)| {
+            let spend_weight = TapLeafScript::Miniscript(&*ms).max_weight_to_satisfy(depth)?;
+            Ok(TapLeafCompilation {
+                ms,
+                prob,
+                depth,
+                spend_weight,
+            })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    leaves.sort_by_key(|leaf| (leaf.depth, Reverse(OrdF64(leaf.prob))));
+    Ok(leaves)
+}
+
+/// Expected weight of a script path spend through the given leaves
+#[cfg(feature = "compiler")]
+fn expected_spend_weight<Pk: MiniscriptKey>(leaves: &[TapLeafCompilation<Pk>]) -> f64 {
+    let total_prob: f64 = leaves.iter().map(|leaf| leaf.prob).sum();
+    if total_prob == 0.0 {
+        return 0.0;
+    }
+    leaves
+        .iter()
+        .map(|leaf| leaf.prob * leaf.spend_weight as f64)
+        .sum::<f64>()
+        / total_prob
+}
+
+/// Computes the depths of the leaves of a binary tree minimizing the expected
+/// depth of the leaves, given their probabilities, with no leaf deeper than
+/// `max_depth`.
+///
+/// Uses the package-merge algorithm for length-limited Huffman codes: the
+/// depth of a leaf is the number of times it appears among the `2n - 2`
+/// lightest items of the list obtained by repeatedly pairing the items of the
+/// previous list and merging the pairs with the leaves.
+#[cfg(feature = "compiler")]
+fn limited_depths(probs: &[f64], max_depth: usize) -> Result<Vec<usize>, Error> {
+    enum Item {
+        Leaf(usize),
+        Package(usize, usize),
+    }
+
+    let n = probs.len();
+    if n <= 1 {
+        return Ok(vec![0; n]);
+    }
+    // A leaf is never deeper than n - 1 in an optimal tree
+    let max_depth = std::cmp::min(max_depth, n - 1);
+    if 1usize
+        .checked_shl(max_depth as u32)
+        .map_or(false, |max_leaves| n > max_leaves)
+    {
+        return Err(Error::MaxRecursiveDepthExceeded);
+    }
+
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|&i| OrdF64(probs[i]));
+    let mut items: Vec<Item> = order.iter().map(|&i| Item::Leaf(i)).collect();
+    let leaves: Vec<(f64, usize)> = order
+        .iter()
+        .enumerate()
+        .map(|(idx, &i)| (probs[i], idx))
+        .collect();
+
+    let mut list = leaves.clone();
+    for _ in 1..max_depth {
+        let mut packages = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks_exact(2) {
+            items.push(Item::Package(pair[0].1, pair[1].1));
+            packages.push((pair[0].0 + pair[1].0, items.len() - 1));
+        }
+        // Merge the leaves and the packages, both sorted by probability
+        list = Vec::with_capacity(leaves.len() + packages.len());
+        let (mut l, mut p) = (0, 0);
+        while l < leaves.len() || p < packages.len() {
+            if p == packages.len() || (l < leaves.len() && leaves[l].0 <= packages[p].0) {
+                list.push(leaves[l]);
+                l += 1;
+            } else {
+                list.push(packages[p]);
+                p += 1;
+            }
+        }
+    }
+
+    let mut depths = vec![0; n];
+    let mut stack: Vec<usize> = list.iter().take(2 * n - 2).map(|&(_, idx)| idx).collect();
+    while let Some(idx) = stack.pop() {
+        match items[idx] {
+            Item::Leaf(i) => depths[i] += 1,
+            Item::Package(a, b) => {
+                stack.push(a);
+                stack.push(b);
+            }
+        }
+    }
+    Ok(depths)
+}
+
+/// Builds the TapTree with the given leaves at their depths
+#[cfg(feature = "compiler")]
+fn tree_from_leaves<Pk: MiniscriptKey>(
+    leaves: &[TapLeafCompilation<Pk>],
+) -> Result<Option<TapTree<Pk>>, Error> {
+    let max_depth = match leaves.iter().map(|leaf| leaf.depth).max() {
+        Some(depth) => depth,
+        None => return Ok(None),
+    };
+    let mut level: Vec<TapTree<Pk>> = vec![];
+    for depth in (0..=max_depth).rev() {
+        let mut nodes: Vec<TapTree<Pk>> = leaves
+            .iter()
+            .filter(|leaf| leaf.depth == depth)
+            .map(|leaf| TapTree::Leaf(Arc::clone(&leaf.ms)))
+            .collect();
+        nodes.append(&mut level);
+        if depth == 0 {
+            level = nodes;
+            break;
+        }
+        let mut nodes = nodes.into_iter();
+        while let Some(left) = nodes.next() {
+            let right = nodes
+                .next()
+                .ok_or_else(|| errstr("TapLeaf depths do not form a full binary tree"))?;
+            level.push(TapTree::Tree(Arc::new(left), Arc::new(right)));
+        }
+    }
+    match level.pop() {
+        Some(tree) if level.is_empty() => Ok(Some(tree)),
+        _ => Err(errstr("TapLeaf depths do not form a full binary tree")),
+    }
+}
+
 /// Enumerate a [Thresh][`Policy::Threshold`](k, ..n..) into `n` different thresh.
 ///
 /// ## Strategy
@@ -1291,7 +2104,12 @@ mod tests {
     use std::sync::Arc;
 
     use super::Concrete;
-    use crate::policy::concrete::{generate_combination, PolicyArc};
+    use crate::policy::compiler::CompilerConfig;
+    use crate::policy::concrete::{
+        binomial, generate_combination, key_subsets, limited_depths, PolicyArc,
+    };
+    use crate::policy::{Liftable, Semantic};
+    use crate::{Descriptor, Error};
 
     #[test]
     fn test_gen_comb() {
@@ -1344,4 +2162,228 @@ mod tests {
             .collect::<Vec<_>>();
         assert_eq!(combinations, expected_comb);
     }
+
+    #[test]
+    fn test_limited_depths() {
+        let probs = [0.125, 0.5, 0.125, 0.25];
+        assert_eq!(limited_depths(&probs, 128).unwrap(), vec![3, 1, 3, 2]);
+        assert_eq!(limited_depths(&probs, 2).unwrap(), vec![2, 2, 2, 2]);
+        assert_eq!(limited_depths(&[1.0], 128).unwrap(), vec![0]);
+
+        // Exponentially decreasing probabilities give a degenerate tree unless
+        // bounded
+        let probs: Vec<f64> = (0..10).map(|i| 0.5f64.powi(i)).collect();
+        let depths = limited_depths(&probs, 128).unwrap();
+        assert_eq!(depths, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 9]);
+        let depths = limited_depths(&probs, 4).unwrap();
+        assert!(depths.iter().all(|&d| d <= 4));
+        let kraft: f64 = depths.iter().map(|&d| 0.5f64.powi(d as i32)).sum();
+        assert_eq!(kraft, 1.0);
+
+        assert!(matches!(
+            limited_depths(&[0.2; 5], 2),
+            Err(Error::MaxRecursiveDepthExceeded)
+        ));
+    }
+
+    #[test]
+    fn compile_tr_optimized() {
+        let config = CompilerConfig::default();
+        for s in &[
+            "or(3@pk(A),1@thresh(2,pk(B),pk(C),pk(D)))",
+            "or(9@and(pk(A),pk(B)),1@or(and(pk(C),older(144)),thresh(2,pk(D),pk(E),pk(F))))",
+        ] {
+            let policy: Concrete<String> = policy_str!("{}", s);
+            let (descriptor, leaves) = policy
+                .compile_tr_optimized(Some("UNSPENDABLE".to_owned()), &config)
+                .unwrap();
+            // The unspendable key never signs
+            let lifted = descriptor
+                .lift()
+                .unwrap()
+                .satisfy_constraint(&Semantic::Key("UNSPENDABLE".to_owned()), false);
+            assert_eq!(
+                lifted.satisfying_sets().unwrap(),
+                policy.lift().unwrap().satisfying_sets().unwrap()
+            );
+
+            let tr = match descriptor {
+                Descriptor::Tr(ref tr) => tr,
+                _ => panic!("compile_tr_optimized must produce a Tr descriptor"),
+            };
+            let mut tree_leaves: Vec<_> = tr
+                .iter_scripts()
+                .map(|(depth, script)| (depth, script.as_miniscript().unwrap().clone()))
+                .collect();
+            let mut reported: Vec<_> = leaves
+                .iter()
+                .map(|leaf| (leaf.depth, (*leaf.ms).clone()))
+                .collect();
+            tree_leaves.sort();
+            reported.sort();
+            assert_eq!(tree_leaves, reported);
+            for leaf in &leaves {
+                assert!(leaf.spend_weight > leaf.ms.script_size());
+            }
+        }
+
+        // Without a viable internal key, every branch is a leaf
+        let policy: Concrete<String> = policy_str!("or(and(pk(A),pk(B)),and(pk(C),pk(D)))");
+        let (_, leaves) = policy
+            .compile_tr_optimized(Some("UNSPENDABLE".to_owned()), &config)
+            .unwrap();
+        assert_eq!(leaves.len(), 2);
+        assert!(leaves
+            .iter()
+            .all(|leaf| leaf.depth == 1 && leaf.prob == 0.5));
+
+        // Leaves exceeding the size limit are errors
+        let config = CompilerConfig {
+            max_tap_leaf_size: Some(10),
+            ..config
+        };
+        assert!(policy
+            .compile_tr_optimized(Some("UNSPENDABLE".to_owned()), &config)
+            .is_err());
+    }
+
+    #[test]
+    fn split_key_thresholds() {
+        let keys: Vec<String> = (0..20).map(|i| format!("K{}", i)).collect();
+        let pks: Vec<String> = keys.iter().map(|k| format!("pk({})", k)).collect();
+        let config = CompilerConfig {
+            split_key_thresholds: true,
+            ..CompilerConfig::default()
+        };
+        let num_leaves = |policy: &Concrete<String>, config: &CompilerConfig| match policy
+            .compile_tr_with_config(Some("UNSPENDABLE".to_owned()), config)
+            .unwrap()
+        {
+            Descriptor::Tr(ref tr) => tr.iter_scripts().count(),
+            _ => panic!("compile_tr_with_config must produce a Tr descriptor"),
+        };
+
+        // A 2-of-20 is cheaper to spend through a leaf per pair of keys
+        let policy: Concrete<String> = policy_str!("thresh(2,{})", pks.join(","));
+        assert_eq!(num_leaves(&policy, &CompilerConfig::default()), 1);
+        assert_eq!(num_leaves(&policy, &config), 190);
+        let (_, leaves) = policy
+            .compile_tr_optimized(Some("UNSPENDABLE".to_owned()), &config)
+            .unwrap();
+        assert_eq!(leaves.len(), 190);
+
+        // A 2-of-3 is cheaper as a single multi_a leaf
+        let policy: Concrete<String> = policy_str!("thresh(2,{})", pks[..3].join(","));
+        assert_eq!(num_leaves(&policy, &config), 1);
+
+        // Too many subsets are never enumerated
+        let policy: Concrete<String> = policy_str!("thresh(10,{})", pks.join(","));
+        assert_eq!(num_leaves(&policy, &config), 1);
+
+        assert_eq!(binomial(20, 2), Some(190));
+        assert_eq!(binomial(200, 100), None);
+        assert_eq!(
+            key_subsets(&keys[..4], 2),
+            vec![
+                vec!["K0", "K1"],
+                vec!["K0", "K2"],
+                vec!["K0", "K3"],
+                vec!["K1", "K2"],
+                vec!["K1", "K3"],
+                vec!["K2", "K3"],
+            ]
+        );
+    }
+
+    #[test]
+    fn compile_tr_parallel() {
+        let unspendable_key = Some("UNSPENDABLE".to_owned());
+        let split = CompilerConfig {
+            split_key_thresholds: true,
+            ..CompilerConfig::default()
+        };
+        let small_leaves = CompilerConfig {
+            max_tap_leaf_size: Some(40),
+            ..CompilerConfig::default()
+        };
+        let invalid = CompilerConfig {
+            sat_weight: -1.0,
+            ..CompilerConfig::default()
+        };
+        for s in &[
+            "pk(A)",
+            "or(pk(A),pk(B))",
+            "or(pk(A),thresh(4,pk(B),older(100),pk(C),and(after(100),or(pk(D),or(pk(E),and(pk(F),thresh(2,pk(G),or(pk(H),and(thresh(5,pk(I),or(pk(J),pk(K)),pk(L),pk(M),pk(N),pk(O),pk(P),pk(Q),pk(R),pk(S),pk(T)),pk(U))),pk(V),or(and(pk(W),pk(X)),pk(Y)),after(100)))))),pk(Z)))",
+            "or(3@and(pk(A),pk(B)),1@thresh(1,and(pk(C),older(10)),thresh(2,pk(D),pk(E),pk(F)),and(pk(G),or(pk(H),pk(I))),thresh(3,pk(J),pk(K),pk(L)),and(after(10),pk(M))))",
+            "thresh(1,and(sha256(1111111111111111111111111111111111111111111111111111111111111111),pk(A)),thresh(2,pk(B),pk(C),pk(D)),and(older(10),thresh(3,pk(E),pk(F),pk(G),pk(H))))",
+        ] {
+            let policy = Concrete::<String>::from_str(s).unwrap();
+            for config in &[CompilerConfig::default(), split, small_leaves, invalid] {
+                let expected = policy.compile_tr_with_config(unspendable_key.clone(), config);
+                for threads in &[0, 1, 2, 3, 8, 64] {
+                    let desc = policy.compile_tr_parallel(unspendable_key.clone(), config, *threads);
+                    assert_eq!(desc, expected);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compile_tr_with_aggregation() {
+        let config = CompilerConfig::default();
+        let musig = |keys: &[String]| Some(format!("musig({})", keys.join(",")));
+        let internal_key = |desc: &Descriptor<String>| match desc {
+            Descriptor::Tr(ref tr) => tr.internal_key().clone(),
+            _ => panic!("compile_tr_with_aggregation must produce a Tr descriptor"),
+        };
+
+        let policy: Concrete<String> =
+            policy_str!("or(9@and(pk(A),pk(B)),1@or(9@pk(C),1@thresh(3,pk(D),pk(E),pk(F))))");
+        let desc = policy
+            .compile_tr_with_aggregation(None, &config, musig)
+            .unwrap();
+        assert_eq!(internal_key(&desc), "musig(A,B)");
+        // The remaining branches are leaves
+        match desc {
+            Descriptor::Tr(ref tr) => assert_eq!(tr.iter_scripts().count(), 2),
+            _ => unreachable!(),
+        }
+
+        // A more probable single key needs no aggregation
+        let policy: Concrete<String> = policy_str!("or(1@and(pk(A),pk(B)),9@pk(C))");
+        let desc = policy
+            .compile_tr_with_aggregation(None, &config, musig)
+            .unwrap();
+        assert_eq!(desc, policy.compile_tr(None).unwrap());
+
+        // The whole n-of-n policy is spent through the key path
+        let policy: Concrete<String> = policy_str!("thresh(3,pk(A),pk(B),pk(C))");
+        let desc = policy
+            .compile_tr_with_aggregation(None, &config, musig)
+            .unwrap();
+        assert_eq!(
+            desc,
+            Descriptor::new_tr("musig(A,B,C)".to_owned(), None).unwrap()
+        );
+
+        // Declined aggregations fall back to the usual internal key selection
+        let policy: Concrete<String> = policy_str!("or(9@and(pk(A),pk(B)),1@and(pk(C),pk(D)))");
+        let desc = policy
+            .compile_tr_with_aggregation(Some("UNSPENDABLE".to_owned()), &config, |keys| {
+                if keys.contains(&"A".to_owned()) {
+                    None
+                } else {
+                    musig(keys)
+                }
+            })
+            .unwrap();
+        assert_eq!(internal_key(&desc), "musig(C,D)");
+        let desc = policy
+            .compile_tr_with_aggregation(Some("UNSPENDABLE".to_owned()), &config, |_| None)
+            .unwrap();
+        assert_eq!(
+            desc,
+            policy.compile_tr(Some("UNSPENDABLE".to_owned())).unwrap()
+        );
+    }
 }
@@ -0,0 +1,350 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Policy Diffs
+//!
+//! Comparison of two semantic policies, e.g. the lifted policies of a
+//! descriptor before and after a key rotation. Both policies are normalized
+//! and sorted before being compared, so that differences in the ordering of
+//! branches are not reported.
+//!
+
+use std::collections::BTreeSet;
+use std::fmt;
+
+use super::concrete::PolicyError;
+use super::semantic::Policy;
+use crate::MiniscriptKey;
+
+/// A threshold whose `k` or number of branches changed between two policies
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ThresholdChange<Pk: MiniscriptKey> {
+    /// The threshold in the old policy
+    pub old: Policy<Pk>,
+    /// The corresponding threshold in the new policy
+    pub new: Policy<Pk>,
+}
+
+/// Differences between the spending conditions of two semantic policies
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PolicyDiff<Pk: MiniscriptKey> {
+    /// Minimal spending paths of the new policy which are not spending paths
+    /// of the old one. See [`Policy::satisfying_sets`].
+    pub added_paths: Vec<Vec<Policy<Pk>>>,
+    /// Minimal spending paths of the old policy which are not spending paths
+    /// of the new one
+    pub removed_paths: Vec<Vec<Policy<Pk>>>,
+    /// Corresponding thresholds of both policies whose `k` or number of
+    /// branches changed
+    pub changed_thresholds: Vec<ThresholdChange<Pk>>,
+    /// Relative timelocks only present in the new policy
+    pub added_relative_timelocks: Vec<u32>,
+    /// Relative timelocks only present in the old policy
+    pub removed_relative_timelocks: Vec<u32>,
+    /// Absolute timelocks only present in the new policy
+    pub added_absolute_timelocks: Vec<u32>,
+    /// Absolute timelocks only present in the old policy
+    pub removed_absolute_timelocks: Vec<u32>,
+    /// Keys which are part of a spending path of the new policy but of no
+    /// spending path of the old one
+    pub keys_gained: Vec<Pk>,
+    /// Keys which are part of a spending path of the old policy but of no
+    /// spending path of the new one
+    pub keys_lost: Vec<Pk>,
+    /// Whether every satisfaction of the old policy satisfies the new one,
+    /// i.e. the new policy is at least as permissive. `None` if the policies
+    /// are too large for entailment checks.
+    pub old_entails_new: Option<bool>,
+    /// Whether every satisfaction of the new policy satisfies the old one
+    pub new_entails_old: Option<bool>,
+}
+
+impl<Pk: MiniscriptKey> PolicyDiff<Pk> {
+    /// Whether the two policies have the same spending conditions
+    pub fn is_empty(&self) -> bool {
+        self.added_paths.is_empty()
+            && self.removed_paths.is_empty()
+            && self.changed_thresholds.is_empty()
+            && self.added_relative_timelocks.is_empty()
+            && self.removed_relative_timelocks.is_empty()
+            && self.added_absolute_timelocks.is_empty()
+            && self.removed_absolute_timelocks.is_empty()
+            && self.keys_gained.is_empty()
+            && self.keys_lost.is_empty()
+    }
+}
+
+impl<Pk: MiniscriptKey> Policy<Pk> {
+    /// Computes the differences in spending conditions between `self`, the
+    /// old policy, and `new`.
+    ///
+    /// Descriptors can be compared by diffing their lifted policies.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use elements_miniscript::policy::semantic::Policy;
+    /// use std::str::FromStr;
+    ///
+    /// let old = Policy::<String>::from_str("or(and(pk(A),pk(B)),and(pk(A),older(144)))").unwrap();
+    /// let new = Policy::<String>::from_str("or(and(pk(A),pk(C)),and(pk(A),older(144)))").unwrap();
+    /// let diff = old.diff(&new).unwrap();
+    /// assert_eq!(diff.keys_gained, vec!["C".to_owned()]);
+    /// assert_eq!(diff.keys_lost, vec!["B".to_owned()]);
+    /// println!("{}", diff);
+    /// ```
+    pub fn diff(&self, new: &Policy<Pk>) -> Result<PolicyDiff<Pk>, PolicyError> {
+        let old = self.clone().normalized().sorted();
+        let new = new.clone().normalized().sorted();
+
+        let old_paths = old.satisfying_sets()?;
+        let new_paths = new.satisfying_sets()?;
+        let added_paths: Vec<_> = new_paths
+            .iter()
+            .filter(|path| !old_paths.contains(path))
+            .cloned()
+            .collect();
+        let removed_paths: Vec<_> = old_paths
+            .iter()
+            .filter(|path| !new_paths.contains(path))
+            .cloned()
+            .collect();
+
+        let mut changed_thresholds = vec![];
+        diff_thresholds(&old, &new, &mut changed_thresholds);
+
+        let old_keys = path_keys(&old_paths);
+        let new_keys = path_keys(&new_paths);
+
+        Ok(PolicyDiff {
+            added_paths,
+            removed_paths,
+            changed_thresholds,
+            added_relative_timelocks: difference(
+                &new.relative_timelocks(),
+                &old.relative_timelocks(),
+            ),
+            removed_relative_timelocks: difference(
+                &old.relative_timelocks(),
+                &new.relative_timelocks(),
+            ),
+            added_absolute_timelocks: difference(
+                &new.absolute_timelocks(),
+                &old.absolute_timelocks(),
+            ),
+            removed_absolute_timelocks: difference(
+                &old.absolute_timelocks(),
+                &new.absolute_timelocks(),
+            ),
+            keys_gained: new_keys.difference(&old_keys).cloned().collect(),
+            keys_lost: old_keys.difference(&new_keys).cloned().collect(),
+            old_entails_new: old.clone().entails(new.clone()).ok(),
+            new_entails_old: new.entails(old).ok(),
+        })
+    }
+}
+
+// Walks both policies in parallel, recording the topmost thresholds whose
+// shape differs. Branches present in both thresholds are skipped, and the
+// remaining sub-thresholds are paired by the number of conditions they share.
+fn diff_thresholds<Pk: MiniscriptKey>(
+    old: &Policy<Pk>,
+    new: &Policy<Pk>,
+    changes: &mut Vec<ThresholdChange<Pk>>,
+) {
+    let (old_k, old_subs, new_k, new_subs) = match (old, new) {
+        (Policy::Threshold(old_k, old_subs), Policy::Threshold(new_k, new_subs)) => {
+            (old_k, old_subs, new_k, new_subs)
+        }
+        _ => return,
+    };
+    if old_k != new_k || old_subs.len() != new_subs.len() {
+        changes.push(ThresholdChange {
+            old: old.clone(),
+            new: new.clone(),
+        });
+        return;
+    }
+
+    let is_unmatched_thresh = |sub: &&Policy<Pk>, others: &[Policy<Pk>]| {
+        matches!(**sub, Policy::Threshold(..)) && !others.contains(*sub)
+    };
+    let mut old_rem: Vec<_> = old_subs
+        .iter()
+        .filter(|sub| is_unmatched_thresh(sub, new_subs))
+        .collect();
+    let mut new_rem: Vec<_> = new_subs
+        .iter()
+        .filter(|sub| is_unmatched_thresh(sub, old_subs))
+        .collect();
+    loop {
+        let mut best = None;
+        for (i, old_sub) in old_rem.iter().enumerate() {
+            let old_conds = conditions(old_sub);
+            for (j, new_sub) in new_rem.iter().enumerate() {
+                let shared = old_conds.intersection(&conditions(new_sub)).count();
+                if shared > best.map_or(0, |(_, _, n)| n) {
+                    best = Some((i, j, shared));
+                }
+            }
+        }
+        match best {
+            Some((i, j, _)) => {
+                diff_thresholds(old_rem.remove(i), new_rem.remove(j), changes);
+            }
+            None => break,
+        }
+    }
+}
+
+// The conditions appearing anywhere in a policy
+fn conditions<Pk: MiniscriptKey>(policy: &Policy<Pk>) -> BTreeSet<&Policy<Pk>> {
+    match *policy {
+        Policy::Threshold(_, ref subs) => subs.iter().flat_map(conditions).collect(),
+        ref cond => Some(cond).into_iter().collect(),
+    }
+}
+
+fn path_keys<Pk: MiniscriptKey>(paths: &[Vec<Policy<Pk>>]) -> BTreeSet<Pk> {
+    paths
+        .iter()
+        .flatten()
+        .filter_map(|cond| match *cond {
+            Policy::Key(ref pk) => Some(pk.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+fn difference(a: &[u32], b: &[u32]) -> Vec<u32> {
+    a.iter().filter(|x| !b.contains(x)).cloned().collect()
+}
+
+fn fmt_path<Pk: MiniscriptKey>(f: &mut fmt::Formatter<'_>, path: &[Policy<Pk>]) -> fmt::Result {
+    for (i, cond) in path.iter().enumerate() {
+        if i == 0 {
+            write!(f, "{}", cond)?;
+        } else {
+            write!(f, ", {}", cond)?;
+        }
+    }
+    Ok(())
+}
+
+impl<Pk: MiniscriptKey> fmt::Display for PolicyDiff<Pk> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no changes");
+        }
+        let mut lines = 0;
+        let mut newline = |f: &mut fmt::Formatter<'_>| {
+            lines += 1;
+            if lines > 1 {
+                f.write_str("\n")
+            } else {
+                Ok(())
+            }
+        };
+        for path in &self.added_paths {
+            newline(f)?;
+            f.write_str("+ path: ")?;
+            fmt_path(f, path)?;
+        }
+        for path in &self.removed_paths {
+            newline(f)?;
+            f.write_str("- path: ")?;
+            fmt_path(f, path)?;
+        }
+        for change in &self.changed_thresholds {
+            newline(f)?;
+            write!(f, "~ threshold: {} -> {}", change.old, change.new)?;
+        }
+        for n in &self.added_relative_timelocks {
+            newline(f)?;
+            write!(f, "+ relative timelock: {}", n)?;
+        }
+        for n in &self.removed_relative_timelocks {
+            newline(f)?;
+            write!(f, "- relative timelock: {}", n)?;
+        }
+        for n in &self.added_absolute_timelocks {
+            newline(f)?;
+            write!(f, "+ absolute timelock: {}", n)?;
+        }
+        for n in &self.removed_absolute_timelocks {
+            newline(f)?;
+            write!(f, "- absolute timelock: {}", n)?;
+        }
+        for pk in &self.keys_gained {
+            newline(f)?;
+            write!(f, "+ key: {}", pk)?;
+        }
+        for pk in &self.keys_lost {
+            newline(f)?;
+            write!(f, "- key: {}", pk)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    type StringPolicy = Policy<String>;
+
+    #[test]
+    fn policy_diff() {
+        let old = StringPolicy::from_str("or(thresh(2,pk(A),pk(B),pk(C)),and(pk(A),older(144)))")
+            .unwrap();
+        let diff = old.diff(&old).unwrap();
+        assert!(diff.is_empty());
+        assert_eq!(diff.old_entails_new, Some(true));
+        assert_eq!(diff.to_string(), "no changes");
+
+        // Raise the threshold and the timelock
+        let new =
+            StringPolicy::from_str("or(and(pk(A),pk(B),pk(C)),and(pk(A),older(288)))").unwrap();
+        let diff = old.diff(&new).unwrap();
+        assert_eq!(diff.removed_paths.len(), 4);
+        assert_eq!(diff.added_paths.len(), 2);
+        assert_eq!(diff.changed_thresholds.len(), 1);
+        assert_eq!(diff.added_relative_timelocks, vec![288]);
+        assert_eq!(diff.removed_relative_timelocks, vec![144]);
+        assert!(diff.keys_gained.is_empty() && diff.keys_lost.is_empty());
+        assert_eq!(
+            diff.to_string(),
+            "+ path: pk(A), pk(B), pk(C)\n\
+             + path: pk(A), older(288)\n\
+             - path: pk(A), pk(B)\n\
+             - path: pk(A), pk(C)\n\
+             - path: pk(A), older(144)\n\
+             - path: pk(B), pk(C)\n\
+             ~ threshold: thresh(2,pk(A),pk(B),pk(C)) -> and(pk(A),pk(B),pk(C))\n\
+             + relative timelock: 288\n\
+             - relative timelock: 144"
+        );
+
+        // Rotate a key
+        let new = StringPolicy::from_str("or(thresh(2,pk(A),pk(B),pk(D)),and(pk(A),older(144)))")
+            .unwrap();
+        let diff = old.diff(&new).unwrap();
+        assert_eq!(diff.keys_gained, vec!["D".to_owned()]);
+        assert_eq!(diff.keys_lost, vec!["C".to_owned()]);
+        assert!(diff.changed_thresholds.is_empty());
+        assert_eq!(diff.old_entails_new, Some(false));
+        assert_eq!(diff.new_entails_old, Some(false));
+
+        // Drop a spending path, the new policy is strictly more restrictive
+        let new = StringPolicy::from_str("thresh(2,pk(A),pk(B),pk(C))").unwrap();
+        let diff = old.diff(&new).unwrap();
+        assert_eq!(
+            diff.removed_paths,
+            vec![vec![Policy::Key("A".to_owned()), Policy::older(144)]]
+        );
+        assert!(diff.added_paths.is_empty());
+        assert_eq!(diff.new_entails_old, Some(true));
+        assert_eq!(diff.old_entails_new, Some(false));
+    }
+}
@@ -17,18 +17,23 @@ use crate::{error, fmt};
 #[cfg(feature = "compiler")]
 pub mod compiler;
 pub mod concrete;
+pub mod diff;
+pub mod schedule;
 pub mod semantic;
+pub mod source;
 
 pub use self::concrete::Policy as Concrete;
 /// Semantic policies are "abstract" policies elsewhere; but we
 /// avoid this word because it is a reserved keyword in Rust
 pub use self::semantic::Policy as Semantic;
-use crate::descriptor::{CovError, Descriptor};
+use crate::descriptor::Descriptor;
 use crate::miniscript::{Miniscript, ScriptContext};
 use crate::{AbsLockTime, BtcPolicy, Error, Extension, MiniscriptKey, Terminal};
 
 /// Policy entailment algorithm maximum number of terminals allowed
 const ENTAILMENT_MAX_TERMINALS: usize = 20;
+/// Maximum number of satisfying sets enumerated for a policy
+const SATISFYING_SETS_MAX: usize = 10_000;
 /// Trait describing script representations which can be lifted into
 /// an abstract policy, by discarding information.
 /// After Lifting all policies are converted into `KeyHash(Pk::HasH)` to
@@ -57,6 +62,8 @@ pub enum LiftError {
     BranchExceedResourceLimits,
     /// Cannot lift raw descriptors
     RawDescriptorLift,
+    /// Cannot lift taproot trees with hidden nodes
+    HiddenTapNode,
 }
 
 impl fmt::Display for LiftError {
@@ -69,6 +76,9 @@ impl fmt::Display for LiftError {
                 "Cannot lift policies containing one branch that exceeds resource limits",
             ),
             LiftError::RawDescriptorLift => f.write_str("Cannot lift raw descriptors"),
+            LiftError::HiddenTapNode => {
+                f.write_str("Cannot lift taproot trees with hidden nodes")
+            }
         }
     }
 }
@@ -78,7 +88,10 @@ impl error::Error for LiftError {
         use self::LiftError::*;
 
         match self {
-            HeightTimelockCombination | BranchExceedResourceLimits | RawDescriptorLift => None,
+            HeightTimelockCombination
+            | BranchExceedResourceLimits
+            | RawDescriptorLift
+            | HiddenTapNode => None,
         }
     }
 }
@@ -164,7 +177,7 @@ where
             Terminal::Multi(k, ref keys) | Terminal::MultiA(k, ref keys) => {
                 Semantic::Threshold(k, keys.iter().map(|k| Semantic::Key(k.clone())).collect())
             }
-            Terminal::Ext(ref _e) => Err(Error::CovError(CovError::CovenantLift))?,
+            Terminal::Ext(ref e) => Semantic::Ext(e.to_string(), e.mall_prop().safe),
         }
         .normalized();
         Ok(ret)
@@ -179,7 +192,7 @@ impl<Pk: MiniscriptKey, T: Extension> Liftable<Pk> for Descriptor<Pk, T> {
             Descriptor::Wpkh(ref wpkh) => wpkh.lift(),
             Descriptor::Wsh(ref wsh) => wsh.lift(),
             Descriptor::Sh(ref sh) => sh.lift(),
-            Descriptor::LegacyCSFSCov(ref _cov) => Err(Error::CovError(CovError::CovenantLift)),
+            Descriptor::LegacyCSFSCov(ref cov) => cov.lift(),
             Descriptor::Tr(ref tr) => tr.lift(),
             Descriptor::TrExt(ref tr) => tr.lift(),
         }
@@ -192,7 +205,7 @@ impl<Pk: MiniscriptKey> Liftable<Pk> for Semantic<Pk> {
     }
 }
 
-impl<Pk: MiniscriptKey> Liftable<Pk> for Concrete<Pk> {
+impl<Pk: MiniscriptKey, Ext: Extension> Liftable<Pk> for Concrete<Pk, Ext> {
     fn lift(&self) -> Result<Semantic<Pk>, Error> {
         // do not lift if there is a possible satisfaction
         // involving combination of timelocks and heightlocks
@@ -207,6 +220,7 @@ impl<Pk: MiniscriptKey> Liftable<Pk> for Concrete<Pk> {
             Concrete::Hash256(ref h) => Semantic::Hash256(h.clone()),
             Concrete::Ripemd160(ref h) => Semantic::Ripemd160(h.clone()),
             Concrete::Hash160(ref h) => Semantic::Hash160(h.clone()),
+            Concrete::Ext(ref e) => Semantic::Ext(e.to_string(), e.mall_prop().safe),
             Concrete::And(ref subs) => {
                 let semantic_subs: Result<_, Error> = subs.iter().map(Liftable::lift).collect();
                 Semantic::Threshold(2, semantic_subs?)
@@ -582,6 +596,7 @@ mod benches {
 
     use super::{Concrete, Error};
     use crate::descriptor::Descriptor;
+    use crate::policy::compiler::CompilerConfig;
     type TapDesc = Result<Descriptor<String>, Error>;
 
     #[bench]
@@ -595,4 +610,43 @@ mod benches {
             black_box(pt).unwrap();
         });
     }
+
+    const XLARGE: &str = "or(pk(A),thresh(4,pk(B),older(100),pk(C),and(after(100),or(pk(D),or(pk(E),and(pk(F),thresh(2,pk(G),or(pk(H),and(thresh(5,pk(I),or(pk(J),pk(K)),pk(L),pk(M),pk(N),pk(O),pk(P),pk(Q),pk(R),pk(S),pk(T)),pk(U))),pk(V),or(and(pk(W),pk(X)),pk(Y)),after(100)))))),pk(Z)))";
+
+    #[bench]
+    pub fn compile_xlarge_tap(bh: &mut Bencher) {
+        let pol = Concrete::<String>::from_str(XLARGE).expect("parsing");
+        bh.iter(|| {
+            let pt: TapDesc = pol.compile_tr_private_experimental(Some("UNSPEND".to_string()));
+            black_box(pt).unwrap();
+        });
+    }
+
+    // 32 leaves of the shape of the root threshold of `XLARGE`
+    fn xlarge_leaves() -> Concrete<String> {
+        let leaves: Vec<String> = (0..32)
+            .map(|i| XLARGE[9..XLARGE.len() - 1].replace("pk(", &format!("pk(K{}_", i)))
+            .collect();
+        let pol = format!("thresh(1,{})", leaves.join(","));
+        Concrete::<String>::from_str(&pol).expect("parsing")
+    }
+
+    #[bench]
+    pub fn compile_xlarge_leaves_tap(bh: &mut Bencher) {
+        let pol = xlarge_leaves();
+        bh.iter(|| {
+            let pt: TapDesc = pol.compile_tr(Some("UNSPEND".to_string()));
+            black_box(pt).unwrap();
+        });
+    }
+
+    #[bench]
+    pub fn compile_xlarge_leaves_tap_parallel(bh: &mut Bencher) {
+        let pol = xlarge_leaves();
+        let config = CompilerConfig::default();
+        bh.iter(|| {
+            let pt: TapDesc = pol.compile_tr_parallel(Some("UNSPEND".to_string()), &config, 4);
+            black_box(pt).unwrap();
+        });
+    }
 }
@@ -0,0 +1,251 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Timelock Schedules
+//!
+//! Timeline of the spending conditions of a semantic policy: the conditions
+//! which apply before any timelock is reached, and how they change as the
+//! absolute and relative timelocks of the policy expire. A transaction sets a
+//! single `nLockTime` and a single `nSequence` per input, so height-based and
+//! time-based locks of the same kind are never combined within a phase.
+//!
+
+use std::fmt;
+
+use elements::{LockTime, Sequence};
+
+use super::semantic::Policy;
+use crate::MiniscriptKey;
+
+/// Mask of the relative lock time value in a `nSequence` (BIP 68)
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+
+/// A phase of the spending conditions of a policy, see
+/// [`Policy::timelock_schedule`]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TimelockPhase<Pk: MiniscriptKey> {
+    /// Absolute lock time from which the phase applies, `None` if the phase
+    /// does not require any transaction lock time
+    pub after: Option<LockTime>,
+    /// Age of the spent output from which the phase applies, `None` if the
+    /// phase applies to outputs of any age
+    pub older: Option<Sequence>,
+    /// The normalized spending conditions of the phase, where the timelocks
+    /// which are reached are replaced by [`Policy::Trivial`]
+    pub policy: Policy<Pk>,
+}
+
+impl<Pk: MiniscriptKey> TimelockPhase<Pk> {
+    /// Whether the lock times of `self` are reached whenever the ones of
+    /// `other` are
+    fn precedes(&self, other: &Self) -> bool {
+        let after = match (self.after, other.after) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => {
+                a.is_block_height() == b.is_block_height()
+                    && a.to_consensus_u32() <= b.to_consensus_u32()
+            }
+        };
+        let older = match (self.older, other.older) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(a), Some(b)) => {
+                a.is_height_locked() == b.is_height_locked()
+                    && a.to_consensus_u32() <= b.to_consensus_u32()
+            }
+        };
+        after && older
+    }
+}
+
+impl<Pk: MiniscriptKey> Policy<Pk> {
+    /// Computes the timeline of the spending conditions of the policy.
+    ///
+    /// A phase is computed for every combination of the absolute and relative
+    /// timelocks of the policy by filtering it with [`Policy::at_lock_time`]
+    /// and [`Policy::at_age`]. Phases are ordered by lock time, height-based
+    /// locks before time-based ones, and a phase is only listed when its
+    /// conditions differ from the ones of every listed phase whose lock times
+    /// are reached before it. The first phase, which requires no lock time,
+    /// is always listed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use elements_miniscript::policy::semantic::Policy;
+    /// use std::str::FromStr;
+    ///
+    /// let policy = Policy::<String>::from_str(
+    ///     "or(thresh(2,pk(A),pk(B),pk(C)),and(pk(D),older(144)))",
+    /// ).unwrap();
+    /// let schedule = policy.timelock_schedule();
+    /// assert_eq!(schedule.len(), 2);
+    /// assert_eq!(schedule[0].to_string(), "initially: thresh(2,pk(A),pk(B),pk(C))");
+    /// assert_eq!(
+    ///     schedule[1].to_string(),
+    ///     "after 144 blocks: or(thresh(2,pk(A),pk(B),pk(C)),pk(D))",
+    /// );
+    /// ```
+    pub fn timelock_schedule(&self) -> Vec<TimelockPhase<Pk>> {
+        let mut afters = vec![None];
+        afters.extend(
+            self.absolute_timelocks()
+                .into_iter()
+                .map(|n| Some(LockTime::from_consensus(n))),
+        );
+        let mut olders = vec![None];
+        olders.extend(
+            self.relative_timelocks()
+                .into_iter()
+                .map(|n| Some(Sequence::from_consensus(n))),
+        );
+
+        let mut phases = Vec::with_capacity(afters.len() * olders.len());
+        for after in &afters {
+            for older in &olders {
+                let policy = self
+                    .clone()
+                    .at_lock_time(after.unwrap_or(LockTime::ZERO))
+                    .at_age(older.unwrap_or(Sequence::ZERO));
+                phases.push(TimelockPhase {
+                    after: *after,
+                    older: *older,
+                    policy: timelocks_reached(policy).normalized(),
+                });
+            }
+        }
+        phases.sort_by_key(|phase| {
+            (
+                phase
+                    .after
+                    .map(|t| (t.is_block_time(), t.to_consensus_u32())),
+                phase
+                    .older
+                    .map(|t| (t.is_time_locked(), t.to_consensus_u32())),
+            )
+        });
+
+        let mut ret: Vec<TimelockPhase<Pk>> = vec![];
+        for phase in phases {
+            let is_new = !ret
+                .iter()
+                .any(|prev| prev.precedes(&phase) && prev.policy == phase.policy);
+            if is_new {
+                ret.push(phase);
+            }
+        }
+        ret
+    }
+}
+
+// Replaces the timelocks left by `at_lock_time` and `at_age`, which are all
+// reached, by `Trivial`.
+fn timelocks_reached<Pk: MiniscriptKey>(policy: Policy<Pk>) -> Policy<Pk> {
+    match policy {
+        Policy::After(..) | Policy::Older(..) => Policy::Trivial,
+        Policy::Threshold(k, subs) => {
+            Policy::Threshold(k, subs.into_iter().map(timelocks_reached).collect())
+        }
+        x => x,
+    }
+}
+
+impl<Pk: MiniscriptKey> fmt::Display for TimelockPhase<Pk> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.after {
+            Some(LockTime::Blocks(h)) => write!(f, "from height {}", h.to_consensus_u32())?,
+            Some(LockTime::Seconds(t)) => write!(f, "from time {}", t.to_consensus_u32())?,
+            None => {}
+        }
+        if let Some(older) = self.older {
+            if self.after.is_some() {
+                f.write_str(", ")?;
+            }
+            let n = older.to_consensus_u32() & SEQUENCE_LOCKTIME_MASK;
+            if older.is_time_locked() {
+                write!(f, "after {} seconds", n * 512)?;
+            } else {
+                write!(f, "after {} blocks", n)?;
+            }
+        }
+        if self.after.is_none() && self.older.is_none() {
+            f.write_str("initially")?;
+        }
+        write!(f, ": {}", self.policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    type StringPolicy = Policy<String>;
+
+    fn policy(s: &str) -> StringPolicy {
+        StringPolicy::from_str(s).unwrap().normalized().sorted()
+    }
+
+    #[test]
+    fn timelock_schedule() {
+        // Without timelocks there is a single phase
+        let schedule = policy("thresh(2,pk(A),pk(B),pk(C))").timelock_schedule();
+        assert_eq!(schedule.len(), 1);
+        assert_eq!(schedule[0].after, None);
+        assert_eq!(schedule[0].older, None);
+
+        let pol = policy(
+            "or(thresh(2,pk(A),pk(B),pk(C)),or(and(after(1000),thresh(1,pk(A),pk(B),pk(C))),and(older(144),pk(D))))",
+        );
+        let schedule = pol.timelock_schedule();
+        let phases: Vec<_> = schedule
+            .iter()
+            .map(|p| (p.after, p.older, p.policy.clone().sorted()))
+            .collect();
+        let at_height = Some(LockTime::from_consensus(1000));
+        let aged = Some(Sequence::from_consensus(144));
+        assert_eq!(
+            phases,
+            vec![
+                (None, None, policy("thresh(2,pk(A),pk(B),pk(C))")),
+                (None, aged, policy("or(thresh(2,pk(A),pk(B),pk(C)),pk(D))")),
+                (
+                    at_height,
+                    None,
+                    policy("or(thresh(2,pk(A),pk(B),pk(C)),thresh(1,pk(A),pk(B),pk(C)))")
+                ),
+                (
+                    at_height,
+                    aged,
+                    policy("or(thresh(2,pk(A),pk(B),pk(C)),thresh(1,pk(A),pk(B),pk(C)),pk(D))")
+                ),
+            ]
+        );
+        assert_eq!(
+            schedule[2].to_string(),
+            format!("from height 1000: {}", schedule[2].policy)
+        );
+
+        // Height and time based locks are never combined
+        let pol = policy("or(and(pk(A),after(1000)),and(pk(B),after(1700000000)))");
+        let schedule = pol.timelock_schedule();
+        assert_eq!(schedule.len(), 3);
+        assert_eq!(schedule[0].policy, Policy::Unsatisfiable);
+        assert_eq!(schedule[1].policy, Policy::Key("A".to_owned()));
+        assert_eq!(schedule[2].policy, Policy::Key("B".to_owned()));
+        assert_eq!(schedule[2].to_string(), "from time 1700000000: pk(B)");
+
+        // A relative timelock which is never reached without an absolute one
+        // does not open a phase on its own
+        let pol = policy("or(pk(A),and(pk(B),after(1000),older(144)))");
+        let schedule = pol.timelock_schedule();
+        assert_eq!(schedule.len(), 2);
+        assert_eq!(schedule[1].after, at_height);
+        assert_eq!(schedule[1].older, aged);
+        assert_eq!(
+            schedule[1].to_string(),
+            "from height 1000, after 144 blocks: or(pk(A),pk(B))"
+        );
+    }
+}
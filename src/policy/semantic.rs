@@ -9,7 +9,7 @@ use std::{fmt, str};
 use elements::{LockTime, Sequence};
 
 use super::concrete::PolicyError;
-use super::ENTAILMENT_MAX_TERMINALS;
+use super::{ENTAILMENT_MAX_TERMINALS, SATISFYING_SETS_MAX};
 use crate::{errstr, expression, AbsLockTime, Error, ForEachKey, MiniscriptKey, Translator};
 
 /// Abstract policy which corresponds to the semantics of a Miniscript
@@ -38,6 +38,10 @@ pub enum Policy<Pk: MiniscriptKey> {
     Ripemd160(Pk::Ripemd160),
     /// A HASH160 whose preimage must be provided to satisfy the descriptor
     Hash160(Pk::Hash160),
+    /// An extension fragment, such as a covenant, kept as an uninterpreted
+    /// condition. Holds the string form of the extension and whether
+    /// satisfying it requires a signature.
+    Ext(String, bool),
     /// A set of descriptors, satisfactions must be provided for `k` of them
     Threshold(usize, Vec<Policy<Pk>>),
 }
@@ -73,7 +77,8 @@ impl<Pk: MiniscriptKey> ForEachKey<Pk> for Policy<Pk> {
             | Policy::Ripemd160(..)
             | Policy::Hash160(..)
             | Policy::After(..)
-            | Policy::Older(..) => true,
+            | Policy::Older(..)
+            | Policy::Ext(..) => true,
             Policy::Threshold(_, ref subs) => subs.iter().all(|sub| sub.for_each_key(&mut pred)),
         }
     }
@@ -145,6 +150,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             Policy::Hash160(ref h) => t.hash160(h).map(Policy::Hash160),
             Policy::After(n) => Ok(Policy::After(n)),
             Policy::Older(n) => Ok(Policy::Older(n)),
+            Policy::Ext(ref e, sig) => Ok(Policy::Ext(e.clone(), sig)),
             Policy::Threshold(k, ref subs) => {
                 let new_subs: Result<Vec<Policy<Q>>, _> =
                     subs.iter().map(|sub| sub._translate_pk(t)).collect();
@@ -249,6 +255,8 @@ impl<Pk: MiniscriptKey> fmt::Debug for Policy<Pk> {
             Policy::Hash256(ref h) => write!(f, "hash256({})", h),
             Policy::Ripemd160(ref h) => write!(f, "ripemd160({})", h),
             Policy::Hash160(ref h) => write!(f, "hash160({})", h),
+            Policy::Ext(ref e, false) => write!(f, "ext({})", e),
+            Policy::Ext(ref e, true) => write!(f, "ext_sig({})", e),
             Policy::Threshold(k, ref subs) => {
                 if k == subs.len() {
                     write!(f, "and(")?;
@@ -282,6 +290,8 @@ impl<Pk: MiniscriptKey> fmt::Display for Policy<Pk> {
             Policy::Hash256(ref h) => write!(f, "hash256({})", h),
             Policy::Ripemd160(ref h) => write!(f, "ripemd160({})", h),
             Policy::Hash160(ref h) => write!(f, "hash160({})", h),
+            Policy::Ext(ref e, false) => write!(f, "ext({})", e),
+            Policy::Ext(ref e, true) => write!(f, "ext_sig({})", e),
             Policy::Threshold(k, ref subs) => {
                 if k == subs.len() {
                     write!(f, "and(")?;
@@ -345,6 +355,8 @@ impl_from_tree!(
             ("hash160", 1) => expression::terminal(&top.args[0], |x| {
                 Pk::Hash160::from_str(x).map(Policy::Hash160)
             }),
+            ("ext", 1) => Ok(Policy::Ext(ext_string(&top.args[0]), false)),
+            ("ext_sig", 1) => Ok(Policy::Ext(ext_string(&top.args[0]), true)),
             ("and", nsubs) => {
                 if nsubs < 2 {
                     return Err(Error::PolicyError(PolicyError::InsufficientArgsforAnd));
@@ -397,6 +409,16 @@ impl_from_tree!(
     }
 );
 
+// Reconstructs the string form of an extension from its parse tree
+fn ext_string(tree: &expression::Tree<'_>) -> String {
+    if tree.args.is_empty() {
+        tree.name.to_owned()
+    } else {
+        let args: Vec<_> = tree.args.iter().map(ext_string).collect();
+        format!("{}({})", tree.name, args.join(","))
+    }
+}
+
 impl<Pk: MiniscriptKey> Policy<Pk> {
     /// Flatten out trees of `And`s and `Or`s; eliminate `Trivial` and
     /// `Unsatisfiable`s. Does not reorder any branches; use `.sort`.
@@ -478,7 +500,8 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             | Policy::Sha256(..)
             | Policy::Hash256(..)
             | Policy::Ripemd160(..)
-            | Policy::Hash160(..) => vec![],
+            | Policy::Hash160(..)
+            | Policy::Ext(..) => vec![],
             Policy::After(..) => vec![],
             Policy::Older(t) => vec![t.to_consensus_u32()],
             Policy::Threshold(_, ref subs) => subs.iter().fold(vec![], |mut acc, x| {
@@ -506,7 +529,8 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             | Policy::Sha256(..)
             | Policy::Hash256(..)
             | Policy::Ripemd160(..)
-            | Policy::Hash160(..) => vec![],
+            | Policy::Hash160(..)
+            | Policy::Ext(..) => vec![],
             Policy::Older(..) => vec![],
             Policy::After(t) => vec![t.to_u32()],
             Policy::Threshold(_, ref subs) => subs.iter().fold(vec![], |mut acc, x| {
@@ -575,11 +599,13 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
     }
 
     /// Count the number of public keys and keyhashes referenced in a policy.
-    /// Duplicate keys will be double-counted.
+    /// Duplicate keys will be double-counted. Extensions requiring a
+    /// signature are counted as a single key.
     pub fn n_keys(&self) -> usize {
         match *self {
             Policy::Unsatisfiable | Policy::Trivial => 0,
             Policy::Key(..) => 1,
+            Policy::Ext(_, sig) => sig as usize,
             Policy::After(..)
             | Policy::Older(..)
             | Policy::Sha256(..)
@@ -598,6 +624,7 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             Policy::Unsatisfiable => None,
             Policy::Trivial => Some(0),
             Policy::Key(..) => Some(1),
+            Policy::Ext(_, sig) => Some(sig as usize),
             Policy::After(..)
             | Policy::Older(..)
             | Policy::Sha256(..)
@@ -617,6 +644,161 @@ impl<Pk: MiniscriptKey> Policy<Pk> {
             }
         }
     }
+
+    /// Filter a policy by eliminating the keys which are not in `keys`,
+    /// e.g. the keys a signer has access to.
+    ///
+    /// Extensions requiring a signature are kept, since the keys they use
+    /// are not known to the semantic policy.
+    pub fn with_available_keys(self, keys: &[Pk]) -> Policy<Pk> {
+        let ret = match self {
+            Policy::Key(pk) => {
+                if keys.contains(&pk) {
+                    Policy::Key(pk)
+                } else {
+                    Policy::Unsatisfiable
+                }
+            }
+            Policy::Threshold(k, subs) => Policy::Threshold(
+                k,
+                subs.into_iter()
+                    .map(|sub| sub.with_available_keys(keys))
+                    .collect(),
+            ),
+            x => x,
+        };
+        ret.normalized()
+    }
+
+    /// Enumerate the minimal sets of conditions (keys, hash preimages,
+    /// timelocks and extensions) which satisfy the policy.
+    ///
+    /// Each set is sorted, and no set is a superset of another one. An empty
+    /// set means the policy is trivially satisfiable, while no sets means it
+    /// is unsatisfiable. To restrict the sets to what can be spent with some
+    /// keys at some time, filter the policy first.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use elements_miniscript::policy::semantic::Policy;
+    /// use elements_miniscript::elements::Sequence;
+    /// use std::str::FromStr;
+    ///
+    /// let policy = Policy::<String>::from_str("or(and(pk(A),pk(B)),and(pk(A),older(144)))").unwrap();
+    /// assert_eq!(policy.satisfying_sets().unwrap().len(), 2);
+    ///
+    /// // What can A spend alone after 100 blocks?
+    /// let restricted = policy
+    ///     .with_available_keys(&["A".to_owned()])
+    ///     .at_age(Sequence::from_height(100));
+    /// assert!(restricted.satisfying_sets().unwrap().is_empty());
+    /// ```
+    pub fn satisfying_sets(&self) -> Result<Vec<Vec<Policy<Pk>>>, PolicyError> {
+        let mut sets = self.clone().normalized().real_satisfying_sets()?;
+        sets.sort();
+        Ok(sets)
+    }
+
+    // Helper function to do the recursion in `satisfying_sets`.
+    fn real_satisfying_sets(&self) -> Result<Vec<Vec<Policy<Pk>>>, PolicyError> {
+        match *self {
+            Policy::Unsatisfiable => Ok(vec![]),
+            Policy::Trivial => Ok(vec![vec![]]),
+            Policy::Threshold(k, ref subs) => {
+                let sub_sets = subs
+                    .iter()
+                    .map(Policy::real_satisfying_sets)
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Unsatisfiable subs take no part in the combinations, so that
+                // every combination gives at least one set
+                let sub_sets: Vec<_> = sub_sets.into_iter().filter(|s| !s.is_empty()).collect();
+                if binomial(sub_sets.len(), k).map_or(true, |n| n > SATISFYING_SETS_MAX) {
+                    return Err(PolicyError::SatisfyingSetsMax);
+                }
+                let mut ret: Vec<Vec<Policy<Pk>>> = vec![];
+                for combination in combinations(sub_sets.len(), k) {
+                    // Union of one satisfying set of each sub in the combination
+                    let mut unions = vec![vec![]];
+                    for i in combination {
+                        let mut next = vec![];
+                        for union in &unions {
+                            for set in &sub_sets[i] {
+                                let mut new: Vec<_> = union.iter().chain(set).cloned().collect();
+                                new.sort();
+                                new.dedup();
+                                next.push(new);
+                            }
+                        }
+                        if next.len() + ret.len() > SATISFYING_SETS_MAX {
+                            return Err(PolicyError::SatisfyingSetsMax);
+                        }
+                        unions = next;
+                    }
+                    ret.extend(unions);
+                }
+                Ok(minimal_sets(ret))
+            }
+            ref leaf => Ok(vec![vec![leaf.clone()]]),
+        }
+    }
+}
+
+// The number of `k` sized subsets of `0..n`, `None` on overflow
+fn binomial(n: usize, k: usize) -> Option<usize> {
+    if k > n {
+        return Some(0);
+    }
+    let mut ret: usize = 1;
+    for i in 0..k.min(n - k) {
+        // `ret * (n - i)` is `(i + 1) * binomial(n, i + 1)`
+        ret = ret.checked_mul(n - i)? / (i + 1);
+    }
+    Some(ret)
+}
+
+// All the `k` sized subsets of `0..n`, as sorted index lists
+fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    fn recurse(
+        start: usize,
+        n: usize,
+        k: usize,
+        current: &mut Vec<usize>,
+        ret: &mut Vec<Vec<usize>>,
+    ) {
+        if current.len() == k {
+            ret.push(current.clone());
+            return;
+        }
+        // Leave enough indices to complete the combination
+        for i in start..=(n - (k - current.len())) {
+            current.push(i);
+            recurse(i + 1, n, k, current, ret);
+            current.pop();
+        }
+    }
+    let mut ret = vec![];
+    if k <= n {
+        recurse(0, n, k, &mut Vec::with_capacity(k), &mut ret);
+    }
+    ret
+}
+
+// Removes duplicate sets and the sets which are supersets of another one.
+// The sets must be sorted.
+fn minimal_sets<Pk: MiniscriptKey>(mut sets: Vec<Vec<Policy<Pk>>>) -> Vec<Vec<Policy<Pk>>> {
+    sets.sort_by(|a, b| a.len().cmp(&b.len()).then_with(|| a.cmp(b)));
+    sets.dedup();
+    let mut ret: Vec<Vec<Policy<Pk>>> = vec![];
+    for set in sets {
+        let is_superset = ret
+            .iter()
+            .any(|min| min.iter().all(|x| set.binary_search(x).is_ok()));
+        if !is_superset {
+            ret.push(set);
+        }
+    }
+    ret
 }
 
 impl<Pk: MiniscriptKey> Policy<Pk> {
@@ -963,4 +1145,82 @@ mod tests {
         assert!(auth_alice.entails(htlc_pol.clone()).unwrap());
         assert!(htlc_pol.entails(control_alice).unwrap());
     }
+
+    #[test]
+    fn ext_policy() {
+        let policy = StringPolicy::from_str(
+            "or(and(pk(A),ext(num_eq(curr_inp_v,inp_v(0)))),and(ext_sig(csfs(B,msg)),older(10)))",
+        )
+        .unwrap();
+        assert_eq!(
+            policy.to_string(),
+            "or(and(pk(A),ext(num_eq(curr_inp_v,inp_v(0)))),and(ext_sig(csfs(B,msg)),older(10)))"
+        );
+        assert_eq!(policy.n_keys(), 2);
+        assert_eq!(policy.minimum_n_keys(), Some(1));
+        assert_eq!(policy.relative_timelocks(), vec![10]);
+
+        // Extensions are uninterpreted conditions, only equal ones entail each other
+        let ext = StringPolicy::from_str("ext(num_eq(curr_inp_v,inp_v(0)))").unwrap();
+        let auth = StringPolicy::from_str("and(pk(A),ext(num_eq(curr_inp_v,inp_v(0))))").unwrap();
+        assert!(auth.clone().entails(policy.clone()).unwrap());
+        assert!(!StringPolicy::from_str("pk(A)")
+            .unwrap()
+            .entails(policy.clone())
+            .unwrap());
+        assert!(auth.entails(ext).unwrap());
+    }
+
+    #[test]
+    fn satisfying_sets() {
+        let key = |s: &str| Policy::Key(s.to_owned());
+        let policy = StringPolicy::from_str(
+            "or(and(pk(A),pk(B)),or(and(pk(A),older(144)),thresh(2,pk(A),pk(B),pk(C))))",
+        )
+        .unwrap();
+        assert_eq!(
+            policy.satisfying_sets().unwrap(),
+            vec![
+                vec![key("A"), key("B")],
+                vec![key("A"), key("C")],
+                vec![key("A"), Policy::older(144)],
+                vec![key("B"), key("C")],
+            ]
+        );
+
+        // Restricted to keys and time
+        let a_only = policy.clone().with_available_keys(&["A".to_owned()]);
+        assert_eq!(
+            a_only.satisfying_sets().unwrap(),
+            vec![vec![key("A"), Policy::older(144)]]
+        );
+        let a_only_now = a_only.at_age(Sequence::from_height(143));
+        assert!(a_only_now.satisfying_sets().unwrap().is_empty());
+
+        assert_eq!(
+            StringPolicy::Trivial.satisfying_sets().unwrap(),
+            vec![Vec::<StringPolicy>::new()]
+        );
+        assert!(StringPolicy::Unsatisfiable
+            .satisfying_sets()
+            .unwrap()
+            .is_empty());
+
+        // Non-minimal sets are removed
+        let policy = StringPolicy::from_str("or(pk(A),and(pk(A),sha256(H)))").unwrap();
+        assert_eq!(policy.satisfying_sets().unwrap(), vec![vec![key("A")]]);
+
+        // Large thresholds are rejected before enumerating their combinations
+        let keys: Vec<String> = (0..40).map(|i| format!("pk(K{})", i)).collect();
+        let policy = StringPolicy::from_str(&format!("thresh(20,{})", keys.join(","))).unwrap();
+        assert_eq!(
+            policy.satisfying_sets(),
+            Err(PolicyError::SatisfyingSetsMax)
+        );
+        let policy = StringPolicy::from_str(&format!("thresh(2,{})", keys.join(","))).unwrap();
+        assert_eq!(policy.satisfying_sets().unwrap().len(), 780);
+        assert_eq!(binomial(30, 15), Some(155_117_520));
+        assert_eq!(binomial(200, 100), None);
+        assert_eq!(binomial(3, 4), Some(0));
+    }
 }
@@ -0,0 +1,717 @@
+// SPDX-License-Identifier: CC0-1.0
+
+//! Policy Sources
+//!
+//! A human-friendly source format for concrete policies over
+//! [`DescriptorPublicKey`]s, lowered into [`Policy`]. On top of the fragments
+//! of the compact policy language, a source may contain:
+//!
+//! * comments, starting with `#` and running to the end of the line;
+//! * key aliases `key NAME = KEY`, where `KEY` is a descriptor public key,
+//!   usable in `pk(NAME)`;
+//! * bindings `let NAME = POLICY`, usable in place of any subpolicy;
+//! * relative timelocks in friendly units, `older(144 blocks)` or
+//!   `older(30 days)`, time-based ones being rounded up to a multiple of 512
+//!   seconds.
+//!
+//! Statements end with the line, but whitespace, including newlines, is free
+//! within parentheses. The source ends with the policy itself.
+//!
+//! ```text
+//! # The keys of the participants
+//! key alice = 02a489e0ea42b56148d212d325b7c67c6460483ff931c303ea311edfef667c8f35
+//! key bob = [d34db33f/44'/0'/0']xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/1/*
+//!
+//! let recovery = and(pk(bob), older(30 days))
+//! or(
+//!     99@pk(alice),
+//!     1@recovery,
+//! )
+//! ```
+//!
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::{error, fmt};
+
+use super::concrete::{Policy, PolicyError};
+use crate::descriptor::{DescriptorKeyParseError, DescriptorPublicKey};
+
+/// Type flag of time-based relative lock times in a `nSequence` (BIP 68)
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+/// Maximum value of a relative lock time, in blocks or 512 seconds units
+const SEQUENCE_LOCKTIME_MAX: u32 = 0xffff;
+/// Granularity of time-based relative lock times, in seconds
+const SEQUENCE_LOCKTIME_GRANULARITY: u64 = 512;
+
+/// An error in a policy source, at a given position
+#[derive(Clone, PartialEq, Debug)]
+pub struct SourceError {
+    /// The line of the error, starting at 1
+    pub line: usize,
+    /// The column of the error, in characters and starting at 1
+    pub column: usize,
+    /// The error
+    pub kind: SourceErrorKind,
+}
+
+/// Kinds of errors in policy sources
+#[derive(Clone, PartialEq, Debug)]
+pub enum SourceErrorKind {
+    /// A character which can not start a token
+    UnexpectedChar(char),
+    /// A token, or the end of the source, where something else was expected
+    UnexpectedToken {
+        /// What was expected
+        expected: &'static str,
+        /// What was found
+        found: String,
+    },
+    /// The source has no policy after its statements
+    MissingPolicy,
+    /// A name which is neither a fragment, a key alias nor a binding
+    UnknownName(String),
+    /// A name defined twice, or a reserved name
+    DuplicateName(String),
+    /// A fragment called with the wrong number of arguments
+    ArgumentCount(String),
+    /// Odds `n@` outside of an `or`
+    OddsOutsideOr,
+    /// A number which could not be parsed
+    InvalidNumber(String),
+    /// A timelock unit which is not a number of blocks, seconds, minutes,
+    /// hours, days or weeks
+    UnknownUnit(String),
+    /// A hash which could not be parsed
+    InvalidHash(String),
+    /// A key which could not be parsed
+    InvalidKey(DescriptorKeyParseError),
+    /// The policy is invalid
+    Policy(PolicyError),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: ", self.line, self.column)?;
+        match self.kind {
+            SourceErrorKind::UnexpectedChar(c) => write!(f, "unexpected character '{}'", c),
+            SourceErrorKind::UnexpectedToken {
+                expected,
+                ref found,
+            } => write!(f, "expected {}, found {}", expected, found),
+            SourceErrorKind::MissingPolicy => f.write_str("missing policy"),
+            SourceErrorKind::UnknownName(ref s) => write!(f, "unknown name '{}'", s),
+            SourceErrorKind::DuplicateName(ref s) => write!(f, "name '{}' is already defined", s),
+            SourceErrorKind::ArgumentCount(ref s) => {
+                write!(f, "wrong number of arguments for '{}'", s)
+            }
+            SourceErrorKind::OddsOutsideOr => f.write_str("odds are only allowed in or()"),
+            SourceErrorKind::InvalidNumber(ref s) => write!(f, "invalid number '{}'", s),
+            SourceErrorKind::UnknownUnit(ref s) => write!(f, "unknown timelock unit '{}'", s),
+            SourceErrorKind::InvalidHash(ref s) => write!(f, "invalid hash '{}'", s),
+            SourceErrorKind::InvalidKey(ref e) => write!(f, "invalid key: {}", e),
+            SourceErrorKind::Policy(ref e) => fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+impl error::Error for SourceError {
+    fn cause(&self) -> Option<&dyn error::Error> {
+        match self.kind {
+            SourceErrorKind::InvalidKey(ref e) => Some(e),
+            SourceErrorKind::Policy(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Tokens of policy sources
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Open,
+    Close,
+    Comma,
+    At,
+    Equals,
+    /// The end of a statement, only emitted outside of parentheses
+    Newline,
+    Word(String),
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Token::Open => f.write_str("'('"),
+            Token::Close => f.write_str("')'"),
+            Token::Comma => f.write_str("','"),
+            Token::At => f.write_str("'@'"),
+            Token::Equals => f.write_str("'='"),
+            Token::Newline => f.write_str("end of line"),
+            Token::Word(ref s) => write!(f, "'{}'", s),
+        }
+    }
+}
+
+/// A token with its line and column
+struct Spanned {
+    token: Token,
+    line: usize,
+    column: usize,
+}
+
+/// Splits a policy source into tokens, dropping comments and the newlines
+/// within parentheses. Also returns the position of the end of the source.
+fn tokenize(src: &str) -> Result<(Vec<Spanned>, (usize, usize)), SourceError> {
+    let mut ret: Vec<Spanned> = vec![];
+    let mut depth = 0usize;
+    let mut end = (1, 1);
+    for (line_idx, line) in src.lines().enumerate() {
+        end = (line_idx + 1, line.chars().count() + 1);
+        let mut chars = line.chars().enumerate().peekable();
+        while let Some((col_idx, c)) = chars.next() {
+            let token = match c {
+                '#' => break,
+                '(' => {
+                    depth += 1;
+                    Token::Open
+                }
+                ')' => {
+                    depth = depth.saturating_sub(1);
+                    Token::Close
+                }
+                ',' => Token::Comma,
+                '@' => Token::At,
+                '=' => Token::Equals,
+                c if c.is_whitespace() => continue,
+                c if c.is_control() => {
+                    return Err(SourceError {
+                        line: line_idx + 1,
+                        column: col_idx + 1,
+                        kind: SourceErrorKind::UnexpectedChar(c),
+                    })
+                }
+                c => {
+                    let mut word = c.to_string();
+                    while let Some(&(_, c)) = chars.peek() {
+                        if c.is_whitespace() || "#(),@=".contains(c) {
+                            break;
+                        }
+                        word.push(c);
+                        chars.next();
+                    }
+                    Token::Word(word)
+                }
+            };
+            ret.push(Spanned {
+                token,
+                line: line_idx + 1,
+                column: col_idx + 1,
+            });
+        }
+        let ends_statement = ret.last().map_or(false, |t| t.token != Token::Newline);
+        if depth == 0 && ends_statement {
+            ret.push(Spanned {
+                token: Token::Newline,
+                line: end.0,
+                column: end.1,
+            });
+        }
+    }
+    Ok((ret, end))
+}
+
+/// Parser of policy sources, lowering them into policies as it goes
+struct Parser {
+    tokens: Vec<Spanned>,
+    end: (usize, usize),
+    pos: usize,
+    keys: HashMap<String, DescriptorPublicKey>,
+    bindings: HashMap<String, Policy<DescriptorPublicKey>>,
+}
+
+impl Parser {
+    /// An error at the position of the token at `pos`, or at the end of the
+    /// source
+    fn error_at(&self, pos: usize, kind: SourceErrorKind) -> SourceError {
+        let (line, column) = match self.tokens.get(pos) {
+            Some(t) => (t.line, t.column),
+            None => self.end,
+        };
+        SourceError { line, column, kind }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|t| &t.token)
+    }
+
+    /// An error for the current token, which is not what was expected
+    fn unexpected(&self, expected: &'static str) -> SourceError {
+        let found = match self.peek() {
+            Some(token) => token.to_string(),
+            None => "end of input".to_owned(),
+        };
+        self.error_at(
+            self.pos,
+            SourceErrorKind::UnexpectedToken { expected, found },
+        )
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> Result<(), SourceError> {
+        if self.peek() == Some(&token) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.unexpected(expected))
+        }
+    }
+
+    /// Consumes a word, returning it along with its position
+    fn word(&mut self, expected: &'static str) -> Result<(String, usize), SourceError> {
+        match self.peek() {
+            Some(Token::Word(w)) => {
+                let w = w.clone();
+                self.pos += 1;
+                Ok((w, self.pos - 1))
+            }
+            _ => Err(self.unexpected(expected)),
+        }
+    }
+
+    fn number(&mut self) -> Result<(u32, usize), SourceError> {
+        let (word, pos) = self.word("a number")?;
+        match word.parse::<u32>() {
+            Ok(n) => Ok((n, pos)),
+            Err(_) => Err(self.error_at(pos, SourceErrorKind::InvalidNumber(word))),
+        }
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek() == Some(&Token::Newline) {
+            self.pos += 1;
+        }
+    }
+
+    /// Parses the whole source
+    fn source(&mut self) -> Result<Policy<DescriptorPublicKey>, SourceError> {
+        loop {
+            self.skip_newlines();
+            match self.peek() {
+                None => return Err(self.error_at(self.pos, SourceErrorKind::MissingPolicy)),
+                Some(Token::Word(w)) if w == "key" => {
+                    self.pos += 1;
+                    let (name, name_pos) = self.definition()?;
+                    let (key, key_pos) = self.word("a key")?;
+                    let key = DescriptorPublicKey::from_str(&key)
+                        .map_err(|e| self.error_at(key_pos, SourceErrorKind::InvalidKey(e)))?;
+                    self.end_of_statement()?;
+                    self.check_name(&name, name_pos)?;
+                    self.keys.insert(name, key);
+                }
+                Some(Token::Word(w)) if w == "let" => {
+                    self.pos += 1;
+                    let (name, name_pos) = self.definition()?;
+                    let (_, policy) = self.policy(false)?;
+                    self.end_of_statement()?;
+                    self.check_name(&name, name_pos)?;
+                    self.bindings.insert(name, policy);
+                }
+                Some(_) => {
+                    let start = self.pos;
+                    let (_, policy) = self.policy(false)?;
+                    self.skip_newlines();
+                    if self.peek().is_some() {
+                        return Err(self.unexpected("end of input"));
+                    }
+                    policy
+                        .check_timelocks()
+                        .map_err(|e| self.error_at(start, SourceErrorKind::Policy(e)))?;
+                    return Ok(policy);
+                }
+            }
+        }
+    }
+
+    /// Parses `NAME =` in a definition
+    fn definition(&mut self) -> Result<(String, usize), SourceError> {
+        let name = self.word("a name")?;
+        self.expect(Token::Equals, "'='")?;
+        Ok(name)
+    }
+
+    fn end_of_statement(&mut self) -> Result<(), SourceError> {
+        match self.peek() {
+            None | Some(Token::Newline) => Ok(()),
+            _ => Err(self.unexpected("end of line")),
+        }
+    }
+
+    /// Checks that a name is neither defined nor reserved
+    fn check_name(&self, name: &str, pos: usize) -> Result<(), SourceError> {
+        let reserved = ["key", "let", "UNSATISFIABLE", "TRIVIAL"];
+        if reserved.contains(&name)
+            || self.keys.contains_key(name)
+            || self.bindings.contains_key(name)
+        {
+            Err(self.error_at(pos, SourceErrorKind::DuplicateName(name.to_owned())))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Parses a policy, preceded by its odds if `allow_odds`
+    fn policy(
+        &mut self,
+        allow_odds: bool,
+    ) -> Result<(usize, Policy<DescriptorPublicKey>), SourceError> {
+        let mut odds = 1;
+        if self.tokens.get(self.pos + 1).map(|t| &t.token) == Some(&Token::At) {
+            let odds_pos = self.pos;
+            if !allow_odds {
+                return Err(self.error_at(odds_pos, SourceErrorKind::OddsOutsideOr));
+            }
+            odds = self.number()?.0 as usize;
+            self.pos += 1;
+        }
+
+        let (name, name_pos) = self.word("a policy")?;
+        if self.peek() != Some(&Token::Open) {
+            let policy = match name.as_str() {
+                "UNSATISFIABLE" => Policy::Unsatisfiable,
+                "TRIVIAL" => Policy::Trivial,
+                _ => match self.bindings.get(&name) {
+                    Some(policy) => policy.clone(),
+                    None => return Err(self.error_at(name_pos, SourceErrorKind::UnknownName(name))),
+                },
+            };
+            return Ok((odds, policy));
+        }
+        self.pos += 1;
+
+        let policy = match name.as_str() {
+            "pk" => {
+                let (key, key_pos) = self.word("a key")?;
+                match self.keys.get(&key) {
+                    Some(pk) => Policy::Key(pk.clone()),
+                    None => DescriptorPublicKey::from_str(&key)
+                        .map(Policy::Key)
+                        .map_err(|e| self.error_at(key_pos, SourceErrorKind::InvalidKey(e)))?,
+                }
+            }
+            "after" => {
+                let (n, pos) = self.number()?;
+                Policy::after(check_time(n).map_err(|e| self.error_at(pos, e))?)
+            }
+            "older" => self.older()?,
+            "sha256" => Policy::Sha256(self.hash()?),
+            "hash256" => Policy::Hash256(self.hash()?),
+            "ripemd160" => Policy::Ripemd160(self.hash()?),
+            "hash160" => Policy::Hash160(self.hash()?),
+            "and" => {
+                let subs = self.policies(false)?;
+                if subs.len() != 2 {
+                    let e = SourceErrorKind::Policy(PolicyError::NonBinaryArgAnd);
+                    return Err(self.error_at(name_pos, e));
+                }
+                Policy::And(subs.into_iter().map(|(_, sub)| sub).collect())
+            }
+            "or" => {
+                let subs = self.policies(true)?;
+                if subs.len() != 2 {
+                    let e = SourceErrorKind::Policy(PolicyError::NonBinaryArgOr);
+                    return Err(self.error_at(name_pos, e));
+                }
+                Policy::Or(subs)
+            }
+            "thresh" => {
+                let (k, _) = self.number()?;
+                let mut subs = vec![];
+                if self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                    subs = self.policies(false)?;
+                }
+                if k == 0 || k as usize > subs.len() {
+                    let e = SourceErrorKind::Policy(PolicyError::IncorrectThresh);
+                    return Err(self.error_at(name_pos, e));
+                }
+                Policy::Threshold(k as usize, subs.into_iter().map(|(_, sub)| sub).collect())
+            }
+            _ => return Err(self.error_at(name_pos, SourceErrorKind::UnknownName(name))),
+        };
+        if self.peek() != Some(&Token::Close) {
+            return match self.peek() {
+                Some(Token::Comma) | Some(Token::Word(_)) => {
+                    Err(self.error_at(name_pos, SourceErrorKind::ArgumentCount(name)))
+                }
+                _ => Err(self.unexpected("')'")),
+            };
+        }
+        self.pos += 1;
+        Ok((odds, policy))
+    }
+
+    /// Parses comma separated policies, up to the closing parenthesis, which
+    /// is not consumed. A trailing comma is allowed.
+    fn policies(
+        &mut self,
+        allow_odds: bool,
+    ) -> Result<Vec<(usize, Policy<DescriptorPublicKey>)>, SourceError> {
+        let mut ret = vec![];
+        while self.peek() != Some(&Token::Close) {
+            ret.push(self.policy(allow_odds)?);
+            match self.peek() {
+                Some(Token::Comma) => self.pos += 1,
+                Some(Token::Close) => {}
+                _ => return Err(self.unexpected("',' or ')'")),
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Parses the argument of `older`, a number of blocks, optionally
+    /// followed by `blocks`, or a duration
+    fn older(&mut self) -> Result<Policy<DescriptorPublicKey>, SourceError> {
+        let (n, pos) = self.number()?;
+        let unit = match self.peek() {
+            Some(Token::Word(_)) => Some(self.word("a unit")?),
+            _ => None,
+        };
+        let seconds = match unit {
+            None => {
+                return Ok(Policy::older(
+                    check_time(n).map_err(|e| self.error_at(pos, e))?,
+                ))
+            }
+            Some((ref unit, _)) if unit == "block" || unit == "blocks" => {
+                if n > SEQUENCE_LOCKTIME_MAX {
+                    let e = SourceErrorKind::Policy(PolicyError::TimeTooFar);
+                    return Err(self.error_at(pos, e));
+                }
+                return Ok(Policy::older(
+                    check_time(n).map_err(|e| self.error_at(pos, e))?,
+                ));
+            }
+            Some((unit, unit_pos)) => {
+                let multiplier = match unit.as_str() {
+                    "second" | "seconds" => 1,
+                    "minute" | "minutes" => 60,
+                    "hour" | "hours" => 60 * 60,
+                    "day" | "days" => 24 * 60 * 60,
+                    "week" | "weeks" => 7 * 24 * 60 * 60,
+                    _ => return Err(self.error_at(unit_pos, SourceErrorKind::UnknownUnit(unit))),
+                };
+                u64::from(n) * multiplier
+            }
+        };
+        // Round up, so that the timelock lasts at least the given duration
+        let units = (seconds + SEQUENCE_LOCKTIME_GRANULARITY - 1) / SEQUENCE_LOCKTIME_GRANULARITY;
+        if units == 0 {
+            return Err(self.error_at(pos, SourceErrorKind::Policy(PolicyError::ZeroTime)));
+        } else if units > u64::from(SEQUENCE_LOCKTIME_MAX) {
+            return Err(self.error_at(pos, SourceErrorKind::Policy(PolicyError::TimeTooFar)));
+        }
+        Ok(Policy::older(SEQUENCE_LOCKTIME_TYPE_FLAG | units as u32))
+    }
+
+    fn hash<H: FromStr>(&mut self) -> Result<H, SourceError> {
+        let (word, pos) = self.word("a hash")?;
+        H::from_str(&word).map_err(|_| self.error_at(pos, SourceErrorKind::InvalidHash(word)))
+    }
+}
+
+/// Checks a timelock value like the compact policy language does, accepting
+/// values from 1 up to 2^31 included
+fn check_time(n: u32) -> Result<u32, SourceErrorKind> {
+    if n > 2u32.pow(31) {
+        Err(SourceErrorKind::Policy(PolicyError::TimeTooFar))
+    } else if n == 0 {
+        Err(SourceErrorKind::Policy(PolicyError::ZeroTime))
+    } else {
+        Ok(n)
+    }
+}
+
+impl Policy<DescriptorPublicKey> {
+    /// Parses a policy from the source format described in the
+    /// [`source`](crate::policy::source) module
+    pub fn from_source(src: &str) -> Result<Self, SourceError> {
+        let (tokens, end) = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            end,
+            pos: 0,
+            keys: HashMap::new(),
+            bindings: HashMap::new(),
+        };
+        parser.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: &str = "02a489e0ea42b56148d212d325b7c67c6460483ff931c303ea311edfef667c8f35";
+    const BOB: &str = "[d34db33f/44'/0'/0']xpub6ERApfZwUNrhLCkDtcHTcxd75RbzS1ed54G1LkBUHQVHQKqhMkhgbmJbZRkrgZw4koxb5JaHWkY4ALHY2grBGRjaDMzQLcgJvLJuZZvRcEL/1/*";
+
+    fn error(src: &str) -> (usize, usize, SourceErrorKind) {
+        let e = Policy::from_source(src).unwrap_err();
+        (e.line, e.column, e.kind)
+    }
+
+    #[test]
+    fn from_source() {
+        let src = format!(
+            "# The keys of the participants
+key alice = {}
+key bob   = {}   # a wildcard key
+
+let recovery = and(pk(bob), older(30 days))
+or(
+    99@pk(alice),   # the usual path
+    1@recovery,
+)
+",
+            ALICE, BOB
+        );
+        // 30 days are 5062.5 units of 512 seconds, rounded up
+        let expected = Policy::from_str(&format!(
+            "or(99@pk({}),1@and(pk({}),older({})))",
+            ALICE,
+            BOB,
+            (1 << 22) | 5063
+        ))
+        .unwrap();
+        assert_eq!(Policy::from_source(&src).unwrap(), expected);
+
+        // The compact language is a valid source
+        let compact = format!("thresh(2,pk({}),pk({}),after(100))", ALICE, BOB);
+        assert_eq!(
+            Policy::from_source(&compact).unwrap(),
+            Policy::from_str(&compact).unwrap()
+        );
+
+        let src = format!("key a = {}\nand(pk(a), older(144 blocks))", ALICE);
+        let expected = Policy::from_str(&format!("and(pk({}),older(144))", ALICE)).unwrap();
+        assert_eq!(Policy::from_source(&src).unwrap(), expected);
+    }
+
+    #[test]
+    fn source_errors() {
+        let src = format!("key a = {}\nlet x = pk(a)\nor(x, pk(b))", ALICE);
+        assert_eq!(
+            error(&src),
+            (
+                3,
+                10,
+                SourceErrorKind::InvalidKey(DescriptorPublicKey::from_str("b").unwrap_err())
+            )
+        );
+        assert_eq!(
+            error("and(\n  TRIVIAL,\n  y\n)"),
+            (3, 3, SourceErrorKind::UnknownName("y".to_owned()))
+        );
+        assert_eq!(
+            error("# nothing but comments\n"),
+            (1, 23, SourceErrorKind::MissingPolicy)
+        );
+        assert_eq!(
+            error("and(TRIVIAL, older(2 fortnights))"),
+            (1, 22, SourceErrorKind::UnknownUnit("fortnights".to_owned()))
+        );
+        assert_eq!(
+            error("and(TRIVIAL, older(400 days))"),
+            (1, 20, SourceErrorKind::Policy(PolicyError::TimeTooFar))
+        );
+        assert_eq!(
+            error("and(1@TRIVIAL, TRIVIAL)"),
+            (1, 5, SourceErrorKind::OddsOutsideOr)
+        );
+        assert_eq!(
+            error("let x = TRIVIAL\nlet x = UNSATISFIABLE\nx"),
+            (2, 5, SourceErrorKind::DuplicateName("x".to_owned()))
+        );
+        assert_eq!(
+            error("thresh(3, TRIVIAL, TRIVIAL)"),
+            (1, 1, SourceErrorKind::Policy(PolicyError::IncorrectThresh))
+        );
+        assert_eq!(
+            error("after(10 blocks)"),
+            (1, 1, SourceErrorKind::ArgumentCount("after".to_owned()))
+        );
+        assert_eq!(
+            error("TRIVIAL\nTRIVIAL").2,
+            SourceErrorKind::UnexpectedToken {
+                expected: "end of input",
+                found: "'TRIVIAL'".to_owned(),
+            }
+        );
+        assert_eq!(
+            Policy::from_source("or(TRIVIAL, TRIVIAL")
+                .unwrap_err()
+                .to_string(),
+            "1:20: expected ',' or ')', found end of input"
+        );
+    }
+
+    #[test]
+    fn timelock_bounds() {
+        // The bounds of the compact language
+        for n in &[1, 2u32.pow(31)] {
+            for frag in &["after", "older"] {
+                let src = format!("{}({})", frag, n);
+                assert_eq!(
+                    Policy::from_source(&src).unwrap(),
+                    Policy::from_str(&src).unwrap()
+                );
+            }
+        }
+        for frag in &["after", "older"] {
+            // The column of the number
+            let col = frag.len() + 2;
+            let src = format!("{}({})", frag, 2u32.pow(31) + 1);
+            assert_eq!(
+                error(&src),
+                (1, col, SourceErrorKind::Policy(PolicyError::TimeTooFar))
+            );
+            assert!(<Policy<DescriptorPublicKey>>::from_str(&src).is_err());
+            let src = format!("{}(0)", frag);
+            assert_eq!(
+                error(&src),
+                (1, col, SourceErrorKind::Policy(PolicyError::ZeroTime))
+            );
+            assert!(<Policy<DescriptorPublicKey>>::from_str(&src).is_err());
+        }
+
+        // Durations up to the largest relative timelock, in both unit forms
+        let max = u64::from(SEQUENCE_LOCKTIME_MAX) * SEQUENCE_LOCKTIME_GRANULARITY;
+        let expected = Policy::older(SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MAX);
+        assert_eq!(
+            Policy::from_source(&format!("older({} seconds)", max)).unwrap(),
+            expected
+        );
+        assert_eq!(
+            error(&format!("older({} seconds)", max + 1)),
+            (1, 7, SourceErrorKind::Policy(PolicyError::TimeTooFar))
+        );
+        assert_eq!(
+            Policy::from_source("older(1 day)").unwrap(),
+            Policy::older(SEQUENCE_LOCKTIME_TYPE_FLAG | 169)
+        );
+        assert_eq!(
+            Policy::from_source("older(2 days)").unwrap(),
+            Policy::older(SEQUENCE_LOCKTIME_TYPE_FLAG | 338)
+        );
+        assert_eq!(
+            error("older(2 dayss)"),
+            (1, 9, SourceErrorKind::UnknownUnit("dayss".to_owned()))
+        );
+        assert_eq!(
+            Policy::from_source(&format!("older({} blocks)", SEQUENCE_LOCKTIME_MAX)).unwrap(),
+            Policy::older(SEQUENCE_LOCKTIME_MAX)
+        );
+        assert_eq!(
+            error(&format!("older({} blocks)", SEQUENCE_LOCKTIME_MAX + 1)),
+            (1, 7, SourceErrorKind::Policy(PolicyError::TimeTooFar))
+        );
+    }
+}
@@ -12,31 +12,20 @@ use bitcoin::key::XOnlyPublicKey;
 use bitcoin::{self, PublicKey};
 use elements::secp256k1_zkp::{self, Secp256k1};
 use elements::taproot::LeafVersion;
-use elements::{self, confidential, Script, Sequence, Transaction, TxOut};
+use elements::{
+    self, confidential, BlindAssetProofs, BlindValueProofs, Script, Sequence, Transaction, TxOut,
+};
 
 use super::{sanity_check, Error, InputError, Psbt, PsbtInputSatisfier};
 use crate::descriptor::{LegacyCSFSCov, LegacyCovSatisfier};
-use crate::extensions::{CovExtArgs, TxEnv};
+use crate::extensions::{CovExtArgs, LegacyOutputsPref, LegacyVerEq, TxEnv};
+use crate::interpreter::{HashLockType, KeySigPair, SatisfiedConstraint};
+use crate::policy::{Liftable, Semantic};
 use crate::{
-    interpreter, util, BareCtx, CovenantExt, Descriptor, ExtParams, Legacy, Miniscript, Satisfier,
-    Segwitv0, SigType, Tap, ToPublicKey,
+    interpreter, util, BareCtx, CovenantExt, Descriptor, ExtParams, Extension, Legacy, Miniscript,
+    Satisfier, Segwitv0, SigType, Tap, ToPublicKey, Translator,
 };
 
-// Get the amount being spent for the psbt input
-fn get_amt(psbt: &Psbt, index: usize) -> Result<confidential::Value, InputError> {
-    let amt;
-    let inp = &psbt.inputs()[index];
-    if let Some(ref witness_utxo) = inp.witness_utxo {
-        amt = witness_utxo.value;
-    } else if let Some(ref non_witness_utxo) = inp.non_witness_utxo {
-        let vout = inp.previous_output_index;
-        amt = non_witness_utxo.output[vout as usize].value;
-    } else {
-        return Err(InputError::MissingUtxo);
-    }
-    Ok(amt)
-}
-
 // Satisfy the taproot descriptor. It is not possible to infer the complete
 // descriptor from psbt because the information about all the scripts might not
 // be present. Also, currently the spec does not support hidden branches, so
@@ -272,36 +261,160 @@ pub(super) fn get_descriptor(
     }
 }
 
-// Helper function to de-duplicate code
-pub fn _interpreter_inp_check<C: secp256k1_zkp::Verification>(
+// Check that the explicit amount and asset, and the proofs in the psbt input
+// are consistent with the value and asset commitments in the spent utxo.
+// Proofs which are not present in the psbt are not checked, except when an
+// explicit amount or asset is claimed for a confidential utxo.
+pub(super) fn input_blinding_check<C: secp256k1_zkp::Verification>(
     psbt: &Psbt,
-    tx: &Transaction,
     secp: &Secp256k1<C>,
     index: usize,
-    genesis_hash: elements::BlockHash,
+) -> Result<(), InputError> {
+    let inp = &psbt.inputs()[index];
+    let utxo = get_utxo(psbt, index)?;
+
+    match (utxo.asset, inp.asset) {
+        (confidential::Asset::Explicit(utxo_asset), Some(pset_asset)) => {
+            if utxo_asset != pset_asset {
+                return Err(InputError::ExplicitAssetMismatch {
+                    pset_asset,
+                    utxo_asset,
+                });
+            }
+        }
+        (confidential::Asset::Confidential(asset_comm), Some(pset_asset)) => {
+            match inp.blind_asset_proof {
+                Some(ref proof) => {
+                    if !proof.blind_asset_proof_verify(secp, pset_asset, asset_comm) {
+                        return Err(InputError::InvalidBlindAssetProof);
+                    }
+                }
+                None => return Err(InputError::MissingBlindAssetProof),
+            }
+        }
+        _ => {}
+    }
+
+    let asset_gen = match utxo.asset.into_asset_gen(secp) {
+        Some(gen) => gen,
+        // Nothing can be committed to a null asset
+        None => return Ok(()),
+    };
+    match utxo.value {
+        confidential::Value::Explicit(utxo_amount) => {
+            if let Some(pset_amount) = inp.amount {
+                if pset_amount != utxo_amount {
+                    return Err(InputError::ExplicitAmountMismatch {
+                        pset_amount,
+                        utxo_amount,
+                    });
+                }
+            }
+        }
+        confidential::Value::Confidential(value_comm) => {
+            if let Some(ref rangeproof) = inp.in_utxo_rangeproof {
+                rangeproof
+                    .verify(secp, value_comm, utxo.script_pubkey.as_bytes(), asset_gen)
+                    .map_err(|_e| InputError::InvalidUtxoRangeproof)?;
+            }
+            if let Some(pset_amount) = inp.amount {
+                match inp.blind_value_proof {
+                    Some(ref proof) => {
+                        if !proof.blind_value_proof_verify(secp, pset_amount, asset_gen, value_comm)
+                        {
+                            return Err(InputError::InvalidBlindValueProof);
+                        }
+                    }
+                    None => return Err(InputError::MissingBlindValueProof),
+                }
+            }
+        }
+        confidential::Value::Null => {}
+    }
+    Ok(())
+}
+
+// Check that the surjection proofs of all confidential outputs of the
+// extracted transaction prove that the output asset is one of the input assets.
+// As in Elements Core, the surjection domain lists for every input the asset
+// of the spent utxo, followed by the issued asset and, for new issuances, the
+// reissuance token if the input issues any.
+pub(super) fn output_surjection_check<C: secp256k1_zkp::Verification>(
+    psbt: &Psbt,
+    tx: &Transaction,
+    secp: &Secp256k1<C>,
 ) -> Result<(), Error> {
+    let mut domain = Vec::with_capacity(psbt.inputs().len());
+    for (txin, utxo) in tx.input.iter().zip(prevouts(psbt)?) {
+        domain.extend(utxo.asset.into_asset_gen(secp));
+        if !txin.has_issuance() {
+            continue;
+        }
+        let issuance = &txin.asset_issuance;
+        let (asset, token) = txin.issuance_ids();
+        if !issuance.amount.is_null() {
+            domain.extend(confidential::Asset::Explicit(asset).into_asset_gen(secp));
+        }
+        // Only new issuances can issue reissuance tokens
+        if !issuance.inflation_keys.is_null()
+            && issuance.asset_blinding_nonce == secp256k1_zkp::ZERO_TWEAK
+        {
+            domain.extend(confidential::Asset::Explicit(token).into_asset_gen(secp));
+        }
+    }
+    for (index, txout) in tx.output.iter().enumerate() {
+        if let confidential::Asset::Confidential(asset_comm) = txout.asset {
+            match txout.witness.surjection_proof {
+                Some(ref proof) => {
+                    if !proof.verify(secp, asset_comm, &domain) {
+                        return Err(Error::InvalidSurjectionProof(index));
+                    }
+                }
+                None => return Err(Error::MissingSurjectionProof(index)),
+            }
+        }
+    }
+    Ok(())
+}
+
+// Construct the interpreter for the final script sig and witness of a psbt input.
+// `empty_script_sig` is used when the input has no final script sig.
+fn input_interpreter<'txin>(
+    psbt: &'txin Psbt,
+    index: usize,
+    empty_script_sig: &'txin Script,
+) -> Result<interpreter::Interpreter<'txin, CovenantExt<CovExtArgs>>, Error> {
     let cltv = psbt
         .locktime()
         .map_err(|_e| Error::LockTimeCombinationError)?;
     let input = &psbt.inputs()[index];
 
     let spk = get_scriptpubkey(psbt, index).map_err(|e| Error::InputError(e, index))?;
-    let empty_script_sig = Script::new();
-    let empty_witness = Vec::new();
-    let script_sig = input.final_script_sig.as_ref().unwrap_or(&empty_script_sig);
-    let witness = input
-        .final_script_witness
-        .as_ref()
-        .unwrap_or(&empty_witness);
+    let script_sig = input.final_script_sig.as_ref().unwrap_or(empty_script_sig);
+    let witness = match input.final_script_witness {
+        Some(ref witness) => &witness[..],
+        None => &[],
+    };
+    let csv = input.sequence.unwrap_or(Sequence::MAX);
 
+    interpreter::Interpreter::from_txdata(spk, script_sig, witness, csv, cltv)
+        .map_err(|e| Error::InputError(InputError::Interpreter(e), index))
+}
+
+// Helper function to de-duplicate code
+pub fn _interpreter_inp_check<C: secp256k1_zkp::Verification>(
+    psbt: &Psbt,
+    tx: &Transaction,
+    secp: &Secp256k1<C>,
+    index: usize,
+    genesis_hash: elements::BlockHash,
+) -> Result<(), Error> {
     // Now look at all the satisfied constraints. If everything is filled in
     // corrected, there should be no errors
+    input_blinding_check(psbt, secp, index).map_err(|e| Error::InputError(e, index))?;
 
-    let csv = psbt.inputs()[index].sequence.unwrap_or(Sequence::MAX);
-    let _amt = get_amt(psbt, index).map_err(|e| Error::InputError(e, index))?;
-
-    let interpreter = interpreter::Interpreter::from_txdata(spk, script_sig, witness, csv, cltv)
-        .map_err(|e| Error::InputError(InputError::Interpreter(e), index))?;
+    let empty_script_sig = Script::new();
+    let interpreter = input_interpreter(psbt, index, &empty_script_sig)?;
 
     let prevouts = prevouts(psbt)?;
     let env = TxEnv::new(tx, &prevouts, index)
@@ -315,7 +428,10 @@ pub fn _interpreter_inp_check<C: secp256k1_zkp::Verification>(
     }
     Ok(())
 }
-/// Interpreter check per psbt input
+/// Interpreter check per psbt input. Also checks that the explicit amount
+/// and asset of the input are consistent with the commitments of the spent
+/// utxo, verifying any rangeproof, blind value proof and blind asset proof
+/// present in the psbt input.
 pub fn interpreter_inp_check<C: secp256k1_zkp::Verification>(
     psbt: &Psbt,
     secp: &Secp256k1<C>,
@@ -330,7 +446,9 @@ pub fn interpreter_inp_check<C: secp256k1_zkp::Verification>(
 /// script is correctly interpreted according to the context
 /// The psbt must have included final script sig and final witness.
 /// In other words, this checks whether the finalized psbt interprets
-/// correctly
+/// correctly. The blinding data of every input is checked as in
+/// [`interpreter_inp_check`], and the surjection proofs of all confidential
+/// outputs are verified against the assets being spent.
 pub fn interpreter_check<C: secp256k1_zkp::Verification>(
     psbt: &Psbt,
     secp: &Secp256k1<C>,
@@ -340,6 +458,151 @@ pub fn interpreter_check<C: secp256k1_zkp::Verification>(
     for index in 0..psbt.inputs().len() {
         _interpreter_inp_check(psbt, &tx, secp, index, genesis_hash)?;
     }
+    output_surjection_check(psbt, &tx, secp)?;
+    Ok(())
+}
+
+// Normalizes keys to their x-only form, so that a lifted taproot policy can
+// be compared against the x-only keys revealed by Schnorr signatures.
+struct XOnlyNormalizer;
+
+impl Translator<PublicKey, PublicKey, ()> for XOnlyNormalizer {
+    fn pk(&mut self, pk: &PublicKey) -> Result<PublicKey, ()> {
+        Ok(pk.to_x_only_pubkey().to_public_key())
+    }
+
+    translate_hash_clone!(PublicKey, PublicKey, ());
+}
+
+/// Checks that the finalized psbt input at `index` spends a coin controlled by
+/// `expected` along a path permitted by it.
+///
+/// The input is rejected if the descriptor inferred by the interpreter does
+/// not produce the script pubkey of `expected`, if the constraints satisfied by
+/// the final witness do not entail the semantic policy of `expected`, or if a
+/// satisfied timelock is contradicted by the transaction locktime or the input
+/// sequence.
+pub fn descriptor_inp_check<C: secp256k1_zkp::Verification>(
+    psbt: &Psbt,
+    tx: &Transaction,
+    secp: &Secp256k1<C>,
+    index: usize,
+    genesis_hash: elements::BlockHash,
+    expected: &Descriptor<PublicKey, CovenantExt<CovExtArgs>>,
+) -> Result<(), Error> {
+    if index >= psbt.inputs().len() {
+        return Err(Error::InputIdxOutofBounds {
+            psbt_inp: psbt.inputs().len(),
+            index,
+        });
+    }
+    let empty_script_sig = Script::new();
+    let interpreter = input_interpreter(psbt, index, &empty_script_sig)?;
+    let input_err = |e: InputError| Error::InputError(e, index);
+
+    // Taproot key spends reveal neither the internal key nor the tree, in which
+    // case only the spent script pubkey can be compared. Taproot script spends
+    // are inferred with x-only keys, the unrevealed branches of the tree being
+    // hidden nodes of the merkle path.
+    let spk = get_scriptpubkey(psbt, index).map_err(input_err)?;
+    let inferred_spk = if interpreter.is_taproot_v1_key_spend() {
+        spk.clone()
+    } else if interpreter.is_taproot_v1_script_spend() {
+        interpreter
+            .inferred_descriptor_string()
+            .parse::<Descriptor<XOnlyPublicKey, CovenantExt<CovExtArgs>>>()
+            .map_err(|e| input_err(InputError::MiniscriptError(e)))?
+            .script_pubkey()
+    } else {
+        interpreter
+            .inferred_descriptor()
+            .map_err(|e| input_err(InputError::MiniscriptError(e)))?
+            .script_pubkey()
+    };
+    if inferred_spk != expected.script_pubkey() {
+        return Err(input_err(InputError::DescriptorMismatch {
+            expected: expected.to_string(),
+            inferred: interpreter.inferred_descriptor_string(),
+        }));
+    }
+
+    let prevouts = prevouts(psbt)?;
+    let env = TxEnv::new(tx, &prevouts, index).ok_or_else(|| input_err(InputError::MissingUtxo))?;
+    let seq = psbt.inputs()[index].sequence.unwrap_or(Sequence::MAX);
+    let mut satisfied = vec![];
+    for constraint in interpreter.iter(secp, &env, genesis_hash) {
+        let constraint = constraint.map_err(|e| input_err(InputError::Interpreter(e)))?;
+        match constraint {
+            SatisfiedConstraint::PublicKey { key_sig }
+            | SatisfiedConstraint::PublicKeyHash { key_sig, .. } => {
+                let pk = match key_sig {
+                    KeySigPair::Ecdsa(pk, _sig) => pk,
+                    KeySigPair::Schnorr(xpk, _sig) => xpk.to_public_key(),
+                };
+                satisfied.push(Semantic::Key(pk));
+            }
+            SatisfiedConstraint::HashLock { hash, .. } => satisfied.push(match hash {
+                HashLockType::Sha256(h) => Semantic::Sha256(h),
+                HashLockType::Hash256(h) => Semantic::Hash256(h),
+                HashLockType::Hash160(h) => Semantic::Hash160(h),
+                HashLockType::Ripemd160(h) => Semantic::Ripemd160(h),
+            }),
+            SatisfiedConstraint::AbsoluteTimelock { n } => {
+                if !seq.enables_absolute_lock_time() {
+                    return Err(input_err(InputError::TimelockContradiction(
+                        n.to_consensus_u32(),
+                    )));
+                }
+                satisfied.push(Semantic::After(n.into()));
+            }
+            SatisfiedConstraint::RelativeTimelock { n } => {
+                if n.is_relative_lock_time()
+                    && (tx.version < 2 || !<dyn Satisfier<PublicKey>>::check_older(&seq, n))
+                {
+                    return Err(input_err(InputError::TimelockContradiction(
+                        n.to_consensus_u32(),
+                    )));
+                }
+                satisfied.push(Semantic::Older(n));
+            }
+            // Extensions are matched against the policy by their string form
+            SatisfiedConstraint::VerEq { n } => satisfied.push(
+                Liftable::<PublicKey>::lift(&LegacyVerEq { n }).expect("Lifting is infallible"),
+            ),
+            SatisfiedConstraint::OutputsPref { pref } => satisfied.push(
+                Liftable::<PublicKey>::lift(&LegacyOutputsPref { pref })
+                    .expect("Lifting is infallible"),
+            ),
+            SatisfiedConstraint::Ext { ext } => {
+                satisfied.push(Semantic::Ext(ext.to_string(), ext.mall_prop().safe))
+            }
+        }
+    }
+
+    let mut policy = expected
+        .lift()
+        .map_err(|e| input_err(InputError::MiniscriptError(e)))?;
+    let mut satisfied = match (interpreter.is_taproot_v1_key_spend(), expected) {
+        // The key spend signature is made with the tweaked output key, which
+        // was already matched against the descriptor above.
+        (true, Descriptor::Tr(tr)) => Semantic::Key(*tr.internal_key()),
+        (true, Descriptor::TrExt(tr)) => Semantic::Key(*tr.internal_key()),
+        _ => Semantic::Threshold(satisfied.len(), satisfied).normalized(),
+    };
+    if interpreter.sig_type() == SigType::Schnorr {
+        policy = policy
+            .translate_pk(&mut XOnlyNormalizer)
+            .expect("Translation is infallible");
+        satisfied = satisfied
+            .translate_pk(&mut XOnlyNormalizer)
+            .expect("Translation is infallible");
+    }
+    let entailed = satisfied
+        .entails(policy)
+        .map_err(|e| input_err(InputError::MiniscriptError(e.into())))?;
+    if !entailed {
+        return Err(input_err(InputError::PolicyNotEntailed));
+    }
     Ok(())
 }
 